@@ -195,6 +195,8 @@ int s[MAXSOCK];
 void can_send(uint32_t id, size_t dlen, uint8_t *data) {
     int i;
     struct canfd_frame frame;
+    /* payloads longer than 8 bytes are sent as CAN FD frames */
+    int mtu = (dlen > CAN_MAX_DLEN) ? CANFD_MTU : CAN_MTU;
         
     frame.can_id = id;
     frame.len = dlen;
@@ -205,7 +207,7 @@ void can_send(uint32_t id, size_t dlen, uint8_t *data) {
     }
 
     // TODO loop over sockets?
-    if (write(s[0], &frame, CAN_MTU) != CAN_MTU) {
+    if (write(s[0], &frame, mtu) != mtu) {
         perror("write");
     }
 
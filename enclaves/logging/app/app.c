@@ -88,6 +88,7 @@ const int canfd_on = 1;
 extern int optind, opterr, optopt;
 
 static volatile int running = 1;
+static volatile int dump_stats = 0;
 
 sgx_enclave_id_t global_eid = 0;
 
@@ -114,6 +115,9 @@ void print_usage(char *prg)
 	fprintf(stderr, "         -e          (dump CAN error frames in human-readable format)\n");
 	fprintf(stderr, "         -x          (print extra message infos, rx/tx brs esi)\n");
 	fprintf(stderr, "         -T <msecs>  (terminate after <msecs> without any reception)\n");
+	fprintf(stderr, "         -w <file>   (append tamper-evident secure log to <file>, state in <file>.state)\n");
+	fprintf(stderr, "         -R          (end the secure log of '-w' and start a new one)\n");
+	fprintf(stderr, "         -V <file>   (verify secure log <file> and exit)\n");
 	fprintf(stderr, "\n");
 	fprintf(stderr, "Up to %d CAN interfaces with optional filter sets can be specified\n", MAXSOCK);
 	fprintf(stderr, "on the commandline in the form: <ifname>[,filter]*\n");
@@ -141,6 +145,11 @@ void sigterm(int signo)
 	running = 0;
 }
 
+void sigusr1(int signo)
+{
+	dump_stats = 1;
+}
+
 int idx2dindex(int ifidx, int socket) {
 
 	int i;
@@ -192,9 +201,152 @@ int idx2dindex(int ifidx, int socket) {
 
 int s[MAXSOCK];
 
+static FILE *seclog = NULL;
+static char *seclog_state = NULL;
+static int seclog_reset = 0;
+
+static const char *seclog_errors[] = {
+    "ok", "modified", "truncated", "malformed", "stale state", "monotonic counter unavailable",
+    "missing state"
+};
+
+static const char *seclog_error(int error) {
+    return (error > 0 && error < 7) ? seclog_errors[error] : "error";
+}
+
+void log_write(size_t len, uint8_t *record) {
+    if (seclog && (fwrite(record, 1, len, seclog) != len || fflush(seclog) != 0)) {
+        perror("secure log write");
+    }
+}
+
+/* Replaces the state file the next session of the secure log continues from. */
+void log_state_write(size_t len, uint8_t *state) {
+    char tmp[strlen(seclog_state) + 5];
+    FILE *f;
+
+    sprintf(tmp, "%s.tmp", seclog_state);
+    if (!(f = fopen(tmp, "wb"))) {
+        perror("secure log state");
+        return;
+    }
+    if (fwrite(state, 1, len, f) != len || fclose(f) != 0 || rename(tmp, seclog_state) != 0)
+        perror("secure log state");
+}
+
+/* Continues the secure log from its state file, or starts a new log when resetting. */
+int open_log_file(void) {
+    uint8_t state[128];
+    size_t len = 0;
+    FILE *f;
+    int eret = 0;
+    sgx_status_t ret;
+
+    if ((f = fopen(seclog_state, "rb"))) {
+        len = fread(state, 1, sizeof(state), f);
+        fclose(f);
+    }
+
+    /* A new log can't follow the records of the old one in the same file. */
+    if (seclog_reset && (fseek(seclog, 0, SEEK_END) != 0 || ftell(seclog) != 0)) {
+        fprintf(stderr, "secure log: move the old log away before resetting it\n");
+        return 1;
+    }
+
+    if (seclog_reset)
+        ret = reset_log(global_eid, &eret, state, len);
+    else
+        ret = open_log(global_eid, &eret, state, len);
+
+    if (ret != SGX_SUCCESS) {
+        printf("Enclave call failed.\n");
+        return 1;
+    }
+    if (eret != 0) {
+        fprintf(stderr, "secure log: cannot %s log (%s)%s\n", seclog_reset ? "reset" : "continue",
+                seclog_error(eret), seclog_reset ? "" : ", use -R to start a new log");
+        return 1;
+    }
+
+    return 0;
+}
+
+/* Checks the chain of a secure log in the enclave that wrote it. */
+int verify_log_file(const char *path) {
+    FILE *f;
+    uint8_t *buf;
+    long len;
+    uint64_t records = 0;
+    int eret = 0;
+
+    if (!(f = fopen(path, "rb"))) {
+        perror("fopen");
+        return 1;
+    }
+    fseek(f, 0, SEEK_END);
+    len = ftell(f);
+    rewind(f);
+
+    buf = malloc(len > 0 ? len : 1);
+    if (!buf || fread(buf, 1, len, f) != (size_t)len) {
+        perror("read");
+        fclose(f);
+        return 1;
+    }
+    fclose(f);
+
+    if (initialize_enclave(&global_eid) < 0) {
+        printf("Enclave initialization failed.\n");
+        return 1;
+    }
+
+    if (verify_log(global_eid, &eret, buf, len, &records) != SGX_SUCCESS) {
+        printf("Enclave call failed.\n");
+        return 1;
+    }
+    free(buf);
+
+    if (eret == 0)
+        printf("%s: %lu records verified.\n", path, (unsigned long)records);
+    else
+        printf("%s: %s at record %lu.\n", path, seclog_error(eret), (unsigned long)records);
+
+    return eret;
+}
+
+static const char *stats_names[] = {
+    "sent", "authenticated", "incorrect_macs", "missing_macs", "unexpected_macs",
+    "desyncs", "resyncs", "auth_fail_sent", "auth_fail_received", "epoch_changes",
+    "auth_fail_suppressed",
+};
+
+void stats_export(uint16_t id, size_t count, uint64_t *stats) {
+    size_t i;
+
+    printf("[STATS]\tid=0x%X", id);
+    for (i = 0; i < count && i < sizeof(stats_names) / sizeof(stats_names[0]); i++) {
+        printf(" %s=%lu", stats_names[i], (unsigned long)stats[i]);
+    }
+    printf("\n");
+}
+
+static const char *ids_severities[] = { "INFO", "WARNING", "CRITICAL" };
+static const char *ids_kinds[] = {
+    "MAC failures", "desyncs", "unknown id flood", "early frame", "late frame", "MAC suppression",
+};
+
+void ids_alert(uint8_t severity, uint32_t kind, uint16_t id, uint32_t count) {
+    printf("[ALERT]\t%s: %s on id 0x%X (%u)\n",
+           severity < 3 ? ids_severities[severity] : "?",
+           kind < sizeof(ids_kinds) / sizeof(ids_kinds[0]) ? ids_kinds[kind] : "?",
+           id, count);
+}
+
 void can_send(uint32_t id, size_t dlen, uint8_t *data) {
     int i;
     struct canfd_frame frame;
+    /* payloads longer than 8 bytes are sent as CAN FD frames */
+    int mtu = (dlen > CAN_MAX_DLEN) ? CANFD_MTU : CAN_MTU;
         
     frame.can_id = id;
     frame.len = dlen;
@@ -207,7 +359,7 @@ void can_send(uint32_t id, size_t dlen, uint8_t *data) {
     }
 
     // TODO loop over sockets?
-    if (write(s[0], &frame, CAN_MTU) != CAN_MTU) {
+    if (write(s[0], &frame, mtu) != mtu) {
         perror("write");
         // TODO return 1;
     }
@@ -245,18 +397,19 @@ int main(int argc, char **argv)
 	struct canfd_frame frame;
 	int nbytes, i, maxdlen;
 	struct ifreq ifr;
-	struct timeval tv, last_tv;
+	struct timeval tv, last_tv, now;
 	struct timeval timeout, timeout_config = { 0, 0 }, *timeout_current = NULL;
 	FILE *logfile = NULL;
 
 	signal(SIGTERM, sigterm);
 	signal(SIGHUP, sigterm);
 	signal(SIGINT, sigterm);
+	signal(SIGUSR1, sigusr1);
 
 	last_tv.tv_sec  = 0;
 	last_tv.tv_usec = 0;
 
-	while ((opt = getopt(argc, argv, "t:HciaSs:b:B:u:lDdxLn:r:heT:?")) != -1) {
+	while ((opt = getopt(argc, argv, "t:HciaSs:b:B:u:lDdxLn:r:heT:w:RV:?")) != -1) {
 		switch (opt) {
 		case 't':
 			timestamp = optarg[0];
@@ -390,6 +543,26 @@ int main(int argc, char **argv)
 			timeout_config.tv_usec = (timeout_config.tv_usec % 1000) * 1000;
 			timeout_current = &timeout;
 			break;
+
+		case 'w':
+			if (!(seclog = fopen(optarg, "ab"))) {
+				perror("secure log");
+				exit(1);
+			}
+			if (!(seclog_state = malloc(strlen(optarg) + 7))) {
+				perror("secure log");
+				exit(1);
+			}
+			sprintf(seclog_state, "%s.state", optarg);
+			break;
+
+		case 'R':
+			seclog_reset = 1;
+			break;
+
+		case 'V':
+			return verify_log_file(optarg);
+
 		default:
 			print_usage(basename(argv[0]));
 			exit(1);
@@ -623,6 +796,9 @@ int main(int argc, char **argv)
         return 1;
     }
 
+    if (seclog && open_log_file())
+        return 1;
+
     int eret = 0;
 
 	while (running) {
@@ -634,7 +810,15 @@ int main(int argc, char **argv)
 		if (timeout_current)
 			*timeout_current = timeout_config;
 
+		if (dump_stats) {
+			/* SIGUSR1: export and reset the connection statistics */
+			dump_stats = 0;
+			export_stats(global_eid, 1);
+		}
+
 		if ((ret = select(s[currmax-1]+1, &rdfs, NULL, NULL, timeout_current)) <= 0) {
+			if (ret < 0 && errno == EINTR)
+				continue;
 			//perror("select");
 			running = 0;
 			continue;
@@ -680,7 +864,9 @@ int main(int argc, char **argv)
 
                 printf("[C] can_id = %X, len = %d, flags = %X\n", frame.can_id, frame.len, frame.flags);
 
-                sgx_ret = recv_message(global_eid, &eret, frame.can_id, frame.len, frame.data);
+                gettimeofday(&now, NULL);
+                sgx_ret = recv_message(global_eid, &eret, frame.can_id, frame.len, frame.data,
+                                       (uint64_t)now.tv_sec * 1000000 + now.tv_usec);
 
                 // printf("Got message: len = %d, eret = %d\n", frame.len, eret);
 
@@ -744,6 +930,13 @@ int main(int argc, char **argv)
         }
 	}
 
+	export_stats(global_eid, 0);
+
+	gettimeofday(&now, NULL);
+	close_log(global_eid, (uint64_t)now.tv_sec * 1000000 + now.tv_usec);
+	if (seclog)
+		fclose(seclog);
+
 	for (i=0; i<currmax; i++)
 		close(s[i]);
 
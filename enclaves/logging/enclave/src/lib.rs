@@ -7,13 +7,17 @@ extern crate vulcan;
 
 #[macro_use]
 extern crate sgx_tstd as std;
+extern crate sgx_tse;
+extern crate sgx_types;
 
 use std::slice;
-use std::collections::HashMap;
 use std::sync::SgxMutex;
 
 use vulcan::*;
 
+mod seclog;
+use seclog::*;
+
 const CAN_ID_PING: u16 = 0xf0;
 const CAN_ID_PONG: u16 = 0xf8;
 const CAN_ID_AEC: u16 = 0xbb;
@@ -27,67 +31,85 @@ const KEY_AEC: SancusKey = [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe, 0xba, 0xbe, 0x88
 
 extern {
     fn can_send(id: u32, dlen: usize, data: *const u8);
+    fn stats_export(id: u16, count: usize, stats: *const u64);
+    fn ids_alert(severity: u8, kind: u32, id: u16, count: u32);
 }
 
-fn vulcan_send(id: u32, data: &[u8]) {
+fn raise_alert(log: &mut SecureLog, alert: Alert, now: u64) {
+    log.alert(&alert, now);
+
     unsafe {
-        can_send(id, data.len(), data.as_ptr());
+        ids_alert(alert.severity as u8, alert.kind as u32, alert.id, alert.count);
     }
 }
 
-struct ExpectedStore(HashMap<u16, [u8; CAN_PAYLOAD_SIZE]>);
-
-impl ExpectedStore {
-    fn new() -> Self {
-        ExpectedStore(HashMap::new())
+// Prints an event of the VulCAN context, for debugging.
+#[cfg(feature = "debug")]
+fn print_event(event: &Event) {
+    match *event {
+        Event::Received(ref msg) => {
+            println!("[MSG]\tReceived '0x{:X}' ({}): {:?}.", msg.id, msg.counter, msg.data);
+        }
+        Event::Authenticated(ref msg) => {
+            println!("[AUTH]\tMessage from '0x{:X}' ({}) has been authenticated: {:?}.",
+                     msg.id, msg.counter, msg.data);
+        }
+        Event::MissingMAC(ref f) => {
+            println!("[FAIL]\tPrevious message for id '0x{:X}' was not authenticated ({:?}).", f.id, f.reason)
+        }
+        Event::UnexpectedMAC(ref f) => {
+            println!("[FAIL]\tReceived unexpected MAC message for id '0x{:X}' ({:?}).", f.id, f.reason)
+        }
+        Event::IncorrectMAC(ref f) => {
+            println!("[FAIL]\tReceived incorrect MAC message for id '0x{:X}' ({:?}).", f.id, f.reason)
+        }
+        Event::Desync(ref f) => {
+            println!("[DESYNC]\tWith '0x{:X}' at counter {} ({:?}).", f.id, f.counter, f.reason);
+        }
+        Event::Resynced(id) => {
+            println!("[RESYNC]\tWith '0x{:X}'.", id);
+        }
+        Event::UnknownId(ref f) => {
+            println!("[FAIL]\tUnknown connection id '0x{:X}'.", f.id);
+        }
+        _ => {
+            println!("Something happened.")
+        }
     }
 }
 
-impl VulCANStore for ExpectedStore {
-    type K = u16;
-    type V = [u8; CAN_PAYLOAD_SIZE];
+#[cfg(not(feature = "debug"))]
+fn print_event(_event: &Event) {}
 
-    #[inline]
-    fn get(&self, k: &Self::K) -> Option<&Self::V> {
-        self.0.get(k)
-    }
-    #[inline]
-    fn insert(&mut self, k: &Self::K, v: Self::V) -> Option<Self::V> {
-        self.0.insert(*k, v)
-    }
-    #[inline]
-    fn remove(&mut self, k: &Self::K) -> Option<Self::V> {
-        self.0.remove(k)
-    }
-    #[inline]
-    fn contains_key(&self, k: &Self::K) -> bool {
-        self.0.contains_key(k)
-    }
-    #[inline]
-    fn len(&self) -> usize {
-        self.0.len()
+fn vulcan_send(id: u32, data: &[u8]) {
+    unsafe {
+        can_send(id, data.len(), data.as_ptr());
     }
 }
 
 lazy_static! {
-    static ref VULCAN: SgxMutex<LeiAContext<ExpectedStore>> = {
+    static ref VULCAN: SgxMutex<LeiAContext<ArrayStore<u16, LeiAPending>>> = {
         let connections = [
             LeiAConnection::new(CAN_ID_PING).with_k_i(&KEY_PING),
             LeiAConnection::new(CAN_ID_PONG).with_k_i(&KEY_PONG),
         ];
         let aec = LeiAConnection::new(CAN_ID_AEC).with_k_i(&KEY_AEC);
         
-        let store = ExpectedStore::new();
+        let store = ArrayStore::new();
             
         let mut vulcan = vulcan::leia(&connections, aec, store, vulcan_send);
         vulcan.init();
 
         SgxMutex::new(vulcan)
     };
+
+    static ref IDS: SgxMutex<Ids> = SgxMutex::new(Ids::new(ArrayStore::new(), ArrayStore::new()));
+
+    static ref LOG: SgxMutex<SecureLog> = SgxMutex::new(SecureLog::new());
 }
 
 #[no_mangle]
-pub extern "C" fn recv_message(eid: u32, dlen: u32, data: *const u8) -> u16 {
+pub extern "C" fn recv_message(eid: u32, dlen: u32, data: *const u8, timestamp: u64) -> u16 {
 
     // Construct slice from raw pointer
     let data = unsafe {
@@ -95,39 +117,95 @@ pub extern "C" fn recv_message(eid: u32, dlen: u32, data: *const u8) -> u16 {
     };
 
     let mut context = VULCAN.lock().unwrap();
+    let mut ids = IDS.lock().unwrap();
+    let mut log = LOG.lock().unwrap();
+
+    log.frame(eid, &data, timestamp);
+    ids.frame(context.layout(), eid, timestamp, |alert| raise_alert(&mut log, alert, timestamp));
     
     // Pass the message to the leia context
     if let Ok(resp) = context.auth_recv(eid, &data) {
-        match resp {
-            Event::Received(x, ref mac) => {
-                println!("[MSG]\tReceived '0x{:X}, expecting mac: {:?}'.", x, mac);
-            }
-            Event::Authenticated(id) => {
-                println!("[AUTH]\tMessage from '0x{:X}' has been authenticated.", id);
-            }
-            Event::MissingMAC(id) => {
-                println!("[FAIL]\tPrevious message for id '0x{:X}' was not authenticated.", id)
-            }
-            Event::UnexpectedMAC(id) => {
-                println!("[FAIL]\tReceived unexpected MAC message for id '0x{:X}'.", id)
-            }
-            Event::IncorrectMAC(id) => {
-                println!("[FAIL]\tReceived incorrect MAC message for id '0x{:X}'.", id)
-            }
-            Event::Desync(id) => {
-                println!("[DESYNC]\tWith '0x{:X}'.", id);
-            }
-            Event::Resynced(id) => {
-                println!("[RESYNC]\tWith '0x{:X}'.", id);
-            }
-            Event::UnknownId(id) => {
-                println!("[FAIL]\tUnknown connection id '0x{:X}'.", id);
-            }
-            _ => {
-                println!("Something happened.")
-            }
-        }
+        log.event(&resp, timestamp);
+        ids.event(&resp, timestamp, |alert| raise_alert(&mut log, alert, timestamp));
+
+        print_event(&resp);
     }
 
     0
 }
+
+#[no_mangle]
+pub extern "C" fn export_stats(reset: i32) {
+    let mut context = VULCAN.lock().unwrap();
+
+    for connection in context.connections() {
+        let s = connection.stats();
+        let stats = [s.sent, s.authenticated, s.incorrect_macs, s.missing_macs,
+                     s.unexpected_macs, s.desyncs, s.resyncs, s.auth_fail_sent,
+                     s.auth_fail_received, s.epoch_changes, s.auth_fail_suppressed];
+
+        unsafe {
+            stats_export(connection.id(), stats.len(), stats.as_ptr());
+        }
+    }
+
+    if reset != 0 {
+        context.reset_stats();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn open_log(state: *const u8, len: usize) -> i32 {
+    let state = if len == 0 {
+        &[]
+    } else {
+        unsafe {
+            slice::from_raw_parts(state, len)
+        }
+    };
+
+    match LOG.lock().unwrap().open(state) {
+        Ok(()) => 0,
+        Err(error) => error as i32,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn reset_log(state: *const u8, len: usize) -> i32 {
+    let state = if len == 0 {
+        &[]
+    } else {
+        unsafe {
+            slice::from_raw_parts(state, len)
+        }
+    };
+
+    match LOG.lock().unwrap().reset(state) {
+        Ok(()) => 0,
+        Err(error) => error as i32,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn close_log(timestamp: u64) {
+    LOG.lock().unwrap().close(timestamp);
+}
+
+#[no_mangle]
+pub extern "C" fn verify_log(log: *const u8, len: usize, records: *mut u64) -> i32 {
+    let log = unsafe {
+        slice::from_raw_parts(log, len)
+    };
+
+    // @TODO: Verify in chunks, so logs larger than the enclave heap can be checked.
+    let (ret, count) = match seclog::verify(log) {
+        Ok(count) => (0, count),
+        Err((error, index)) => (error as i32, index),
+    };
+
+    unsafe {
+        *records = count;
+    }
+
+    ret
+}
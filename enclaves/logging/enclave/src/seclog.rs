@@ -0,0 +1,456 @@
+// Tamper-evident log of the frames, events and alerts seen by the logging enclave.
+//
+// Every record is MAC'd together with the MAC of the previous record, using a
+// key derived from the enclave's seal key. The chain continues across sessions,
+// so modifying, removing or reordering records or whole sessions breaks it. Each
+// session starts with the next value of a platform monotonic counter, which
+// detects sessions removed from the end of the log, and the chain state between
+// sessions is MAC'd and bound to the same counter, so it can't be rolled back.
+// A new log, with a new counter, is only started by an explicit reset, so the
+// host can't restart the chain by withholding the state.
+// Only an enclave with the same identity on the same platform can derive the
+// key, so logs are verified by the enclave itself.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sgx_tse::{rsgx_get_key, rsgx_self_report};
+use sgx_types::*;
+
+use vulcan::spongent::spongent_mac;
+use vulcan::*;
+
+const LOG_KEY_ID: &[u8] = b"vulcan-secure-log";
+const LOG_STATE_ID: &[u8] = b"vulcan-log-state";
+
+const LOG_HEADER_SIZE: usize = 22;
+const LOG_MAC_SIZE: usize = 16;
+const LOG_MAX_RECORD_SIZE: usize = LOG_HEADER_SIZE + CAN_FD_PAYLOAD_SIZE + LOG_MAC_SIZE;
+
+// Monotonic counter uuid, session number, sequence number and MAC of the last record.
+const LOG_UUID_SIZE: usize = SGX_MC_UUID_COUNTER_ID_SIZE + SGX_MC_UUID_NONCE_SIZE;
+const LOG_START_SIZE: usize = 4 + LOG_UUID_SIZE;
+const LOG_STATE_SIZE: usize = LOG_UUID_SIZE + 4 + 8 + LOG_MAC_SIZE + LOG_MAC_SIZE;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    Start = 0,
+    Frame = 1,
+    Event = 2,
+    Alert = 3,
+    Stop = 4,
+}
+
+/// Outcome of the verification of a log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogError {
+    /// A record has been modified, removed or reordered.
+    Modified = 1,
+    /// A session does not end with a stop record, or sessions are missing at the end.
+    Truncated = 2,
+    /// A record or the log state could not be parsed.
+    Malformed = 3,
+    /// The log state is not the one written at the end of the last session.
+    ///
+    /// This is also the case after a crash in the middle of a session, which
+    /// moved the counter on without writing a new state. Such a log can't be
+    /// continued and has to be reset.
+    Stale = 4,
+    /// The monotonic counter could not be accessed.
+    Counter = 5,
+    /// No log state was given to continue from.
+    Missing = 6,
+}
+
+extern {
+    fn log_write(len: usize, record: *const u8);
+    fn log_state_write(len: usize, state: *const u8);
+}
+
+// Derives the log key from the seal key of the enclave.
+fn log_key() -> SancusKey {
+    let report = rsgx_self_report();
+
+    let mut key_id = sgx_key_id_t::default();
+    key_id.id[..LOG_KEY_ID.len()].copy_from_slice(LOG_KEY_ID);
+
+    let request = sgx_key_request_t {
+        key_name: SGX_KEYSELECT_SEAL,
+        key_policy: SGX_KEYPOLICY_MRENCLAVE,
+        isv_svn: report.body.isv_svn,
+        reserved1: 0,
+        cpu_svn: report.body.cpu_svn,
+        attribute_mask: sgx_attributes_t {
+            flags: TSEAL_DEFAULT_FLAGSMASK,
+            xfrm: 0,
+        },
+        key_id: key_id,
+        misc_mask: TSEAL_DEFAULT_MISCMASK,
+        reserved2: [0; SGX_KEY_REQUEST_RESERVED2_BYTES],
+    };
+
+    rsgx_get_key(&request).expect("Could not derive log key.")
+}
+
+// Runs `op` on a monotonic counter within a session with the platform services.
+fn counter_op<F>(op: F) -> Result<(), LogError>
+where
+    F: FnOnce() -> sgx_status_t,
+{
+    if unsafe { sgx_create_pse_session() } != sgx_status_t::SGX_SUCCESS {
+        return Err(LogError::Counter);
+    }
+
+    let status = op();
+
+    unsafe {
+        sgx_close_pse_session();
+    }
+
+    if status == sgx_status_t::SGX_SUCCESS {
+        Ok(())
+    } else {
+        Err(LogError::Counter)
+    }
+}
+
+fn mc_uuid(uuid: &[u8; LOG_UUID_SIZE]) -> sgx_mc_uuid_t {
+    let mut mc = sgx_mc_uuid_t::default();
+    mc.counter_id.copy_from_slice(&uuid[..SGX_MC_UUID_COUNTER_ID_SIZE]);
+    mc.nonce.copy_from_slice(&uuid[SGX_MC_UUID_COUNTER_ID_SIZE..]);
+    mc
+}
+
+// Creates a new monotonic counter, starting at zero, and returns its uuid.
+fn create_counter() -> Result<[u8; LOG_UUID_SIZE], LogError> {
+    let mut mc = sgx_mc_uuid_t::default();
+    let mut value = 0;
+    counter_op(|| unsafe { sgx_create_monotonic_counter(&mut mc, &mut value) })?;
+
+    let mut uuid = [0; LOG_UUID_SIZE];
+    uuid[..SGX_MC_UUID_COUNTER_ID_SIZE].copy_from_slice(&mc.counter_id);
+    uuid[SGX_MC_UUID_COUNTER_ID_SIZE..].copy_from_slice(&mc.nonce);
+    Ok(uuid)
+}
+
+fn increment_counter(uuid: &[u8; LOG_UUID_SIZE]) -> Result<u32, LogError> {
+    let mc = mc_uuid(uuid);
+    let mut value = 0;
+    counter_op(|| unsafe { sgx_increment_monotonic_counter(&mc, &mut value) })?;
+    Ok(value)
+}
+
+fn read_counter(uuid: &[u8; LOG_UUID_SIZE]) -> Result<u32, LogError> {
+    let mc = mc_uuid(uuid);
+    let mut value = 0;
+    counter_op(|| unsafe { sgx_read_monotonic_counter(&mc, &mut value) })?;
+    Ok(value)
+}
+
+// Frees the monotonic counter, as the platform only holds a limited number of them.
+fn destroy_counter(uuid: &[u8; LOG_UUID_SIZE]) -> Result<(), LogError> {
+    let mc = mc_uuid(uuid);
+    counter_op(|| unsafe { sgx_destroy_monotonic_counter(&mc) })
+}
+
+// Computes the MAC of the log state kept between sessions.
+fn state_mac(key: &SancusKey, state: &[u8]) -> [u8; LOG_MAC_SIZE] {
+    let mut ad = [0; LOG_MAC_SIZE + LOG_STATE_SIZE];
+    ad[..LOG_STATE_ID.len()].copy_from_slice(LOG_STATE_ID);
+    ad[LOG_MAC_SIZE..LOG_MAC_SIZE + state.len()].copy_from_slice(state);
+
+    spongent_mac(key, &ad[..LOG_MAC_SIZE + state.len()]).unwrap()
+}
+
+// Computes the MAC of a record, chained to the MAC of the previous one.
+fn record_mac(key: &SancusKey, prev: &[u8; LOG_MAC_SIZE], record: &[u8]) -> [u8; LOG_MAC_SIZE] {
+    let mut ad = [0; LOG_MAC_SIZE + LOG_HEADER_SIZE + CAN_FD_PAYLOAD_SIZE];
+    ad[..LOG_MAC_SIZE].copy_from_slice(prev);
+    ad[LOG_MAC_SIZE..LOG_MAC_SIZE + record.len()].copy_from_slice(record);
+
+    spongent_mac(key, &ad[..LOG_MAC_SIZE + record.len()]).unwrap()
+}
+
+pub struct SecureLog {
+    key: SancusKey,
+    counter: Option<[u8; LOG_UUID_SIZE]>,
+    session: u32,
+    active: bool,
+    seq: u64,
+    prev: [u8; LOG_MAC_SIZE],
+}
+
+impl SecureLog {
+    /// Creates a log that ignores all records until it is opened.
+    pub fn new() -> Self {
+        SecureLog {
+            key: log_key(),
+            counter: None,
+            session: 0,
+            active: false,
+            seq: 0,
+            prev: [0; LOG_MAC_SIZE],
+        }
+    }
+
+    /// Opens the log, continuing the chain from the `state` written when the
+    /// previous session was closed.
+    ///
+    /// A log whose last session was never closed, e.g. because the host crashed,
+    /// can't be continued and fails with `Stale` until it is reset.
+    pub fn open(&mut self, state: &[u8]) -> Result<(), LogError> {
+        let uuid = self.state_counter(state)?;
+        let session = LittleEndian::read_u32(&state[LOG_UUID_SIZE..LOG_UUID_SIZE + 4]);
+
+        // The counter moves on with every session, so an older state, or one
+        // of a session that was never closed, no longer matches it.
+        if read_counter(&uuid)? != session {
+            return Err(LogError::Stale);
+        }
+
+        self.counter = Some(uuid);
+        self.session = session;
+        self.seq = LittleEndian::read_u64(&state[LOG_UUID_SIZE + 4..LOG_UUID_SIZE + 12]);
+        self.prev.copy_from_slice(&state[LOG_UUID_SIZE + 12..LOG_STATE_SIZE - LOG_MAC_SIZE]);
+        Ok(())
+    }
+
+    /// Starts a new log with a new monotonic counter, ending the log of `state`
+    /// if given. The counter of that log is destroyed, after which sessions
+    /// removed from its end are no longer detected.
+    ///
+    /// The state of the new log is written right away.
+    pub fn reset(&mut self, state: &[u8]) -> Result<(), LogError> {
+        if !state.is_empty() {
+            let uuid = self.state_counter(state)?;
+            destroy_counter(&uuid)?;
+        }
+
+        self.counter = Some(create_counter()?);
+        self.session = 0;
+        self.active = false;
+        self.seq = 0;
+        self.prev = [0; LOG_MAC_SIZE];
+        self.write_state();
+        Ok(())
+    }
+
+    // Checks the MAC of a log state, returning the uuid of its counter.
+    fn state_counter(&self, state: &[u8]) -> Result<[u8; LOG_UUID_SIZE], LogError> {
+        if state.is_empty() {
+            return Err(LogError::Missing);
+        }
+
+        if state.len() != LOG_STATE_SIZE {
+            return Err(LogError::Malformed);
+        }
+
+        let mac_pos = LOG_STATE_SIZE - LOG_MAC_SIZE;
+        if state_mac(&self.key, &state[..mac_pos])[..] != state[mac_pos..] {
+            return Err(LogError::Modified);
+        }
+
+        let mut uuid = [0; LOG_UUID_SIZE];
+        uuid.copy_from_slice(&state[..LOG_UUID_SIZE]);
+        Ok(uuid)
+    }
+
+    /// Logs a raw frame.
+    pub fn frame(&mut self, eid: u32, data: &[u8], now: u64) {
+        self.append(RecordKind::Frame, eid, data, now);
+    }
+
+    /// Logs an event of the VulCAN context.
+    pub fn event(&mut self, event: &Event, now: u64) {
+        let (tag, id, counter, reason) = match *event {
+            Event::Received(ref m) => (0, m.id, m.counter, 0),
+            Event::Buffered(id) => (1, id, 0, 0),
+            Event::Authenticated(ref m) => (2, m.id, m.counter, 0),
+            Event::MissingMAC(ref f) => (3, f.id, f.counter, f.reason as u8),
+            Event::UnexpectedMAC(ref f) => (4, f.id, f.counter, f.reason as u8),
+            Event::IncorrectMAC(ref f) => (5, f.id, f.counter, f.reason as u8),
+            Event::Desync(ref f) => (6, f.id, f.counter, f.reason as u8),
+            Event::Resynced(id) => (7, id, 0, 0),
+            Event::UnknownId(ref f) => (8, f.id, f.counter, f.reason as u8),
+            Event::AuthFailSuppressed(id, count) => (9, id, count, 0),
+            Event::Debug(_) => return,
+        };
+
+        let mut data = [0; 10];
+        data[0] = tag;
+        data[1] = reason;
+        LittleEndian::write_u64(&mut data[2..], counter);
+
+        self.append(RecordKind::Event, id as u32, &data, now);
+    }
+
+    /// Logs an alert of the intrusion detection.
+    pub fn alert(&mut self, alert: &Alert, now: u64) {
+        let mut data = [0; 6];
+        data[0] = alert.severity as u8;
+        data[1] = alert.kind as u8;
+        LittleEndian::write_u32(&mut data[2..], alert.count);
+
+        self.append(RecordKind::Alert, alert.id as u32, &data, now);
+    }
+
+    /// Ends the current session, after which a new one is started, and writes
+    /// the state the next session continues from.
+    pub fn close(&mut self, now: u64) {
+        if self.counter.is_none() {
+            return;
+        }
+
+        if self.active {
+            self.append(RecordKind::Stop, 0, &[], now);
+            self.active = false;
+        }
+
+        self.write_state();
+    }
+
+    // Writes the state the next session continues from.
+    fn write_state(&self) {
+        let uuid = match self.counter {
+            Some(uuid) => uuid,
+            None => return,
+        };
+
+        let mut state = [0; LOG_STATE_SIZE];
+        state[..LOG_UUID_SIZE].copy_from_slice(&uuid);
+        LittleEndian::write_u32(&mut state[LOG_UUID_SIZE..LOG_UUID_SIZE + 4], self.session);
+        LittleEndian::write_u64(&mut state[LOG_UUID_SIZE + 4..LOG_UUID_SIZE + 12], self.seq);
+        state[LOG_UUID_SIZE + 12..LOG_STATE_SIZE - LOG_MAC_SIZE].copy_from_slice(&self.prev);
+
+        let mac = state_mac(&self.key, &state[..LOG_STATE_SIZE - LOG_MAC_SIZE]);
+        state[LOG_STATE_SIZE - LOG_MAC_SIZE..].copy_from_slice(&mac);
+
+        unsafe {
+            log_state_write(LOG_STATE_SIZE, state.as_ptr());
+        }
+    }
+
+    // Appends a record, starting a new session first if needed. Records are
+    // dropped while the log is not open or the monotonic counter is unavailable.
+    fn append(&mut self, kind: RecordKind, id: u32, data: &[u8], now: u64) {
+        let uuid = match self.counter {
+            Some(uuid) => uuid,
+            None => return,
+        };
+
+        if !self.active && kind != RecordKind::Start {
+            self.session = match increment_counter(&uuid) {
+                Ok(session) => session,
+                Err(_) => return,
+            };
+            self.active = true;
+
+            let mut start = [0; LOG_START_SIZE];
+            LittleEndian::write_u32(&mut start[..4], self.session);
+            start[4..].copy_from_slice(&uuid);
+            self.append(RecordKind::Start, 0, &start, now);
+        }
+
+        let mut record = [0; LOG_MAX_RECORD_SIZE];
+        LittleEndian::write_u64(&mut record[0..8], self.seq);
+        LittleEndian::write_u64(&mut record[8..16], now);
+        record[16] = kind as u8;
+        LittleEndian::write_u32(&mut record[17..21], id);
+        record[21] = data.len() as u8;
+        record[LOG_HEADER_SIZE..LOG_HEADER_SIZE + data.len()].copy_from_slice(data);
+
+        let mac_pos = LOG_HEADER_SIZE + data.len();
+        let mac = record_mac(&self.key, &self.prev, &record[..mac_pos]);
+        record[mac_pos..mac_pos + LOG_MAC_SIZE].copy_from_slice(&mac);
+
+        unsafe {
+            log_write(mac_pos + LOG_MAC_SIZE, record.as_ptr());
+        }
+
+        self.seq += 1;
+        self.prev = mac;
+    }
+}
+
+/// Verifies a log consisting of one or more sessions, returning the number of
+/// records on success or the index of the first bad record on failure.
+pub fn verify(log: &[u8]) -> Result<u64, (LogError, u64)> {
+    let key = log_key();
+
+    let mut index = 0;
+    let mut seq = 0;
+    let mut prev = [0; LOG_MAC_SIZE];
+    let mut pos = 0;
+    let mut active = false;
+    let mut session = 0;
+    let mut counter: Option<[u8; LOG_UUID_SIZE]> = None;
+
+    while pos < log.len() {
+        if log.len() - pos < LOG_HEADER_SIZE {
+            return Err((LogError::Malformed, index));
+        }
+
+        let header = &log[pos..pos + LOG_HEADER_SIZE];
+        let mac_pos = pos + LOG_HEADER_SIZE + header[21] as usize;
+        if header[21] as usize > CAN_FD_PAYLOAD_SIZE || log.len() < mac_pos + LOG_MAC_SIZE {
+            return Err((LogError::Malformed, index));
+        }
+
+        let kind = header[16];
+        if active == (kind == RecordKind::Start as u8) {
+            // A start record in the middle of a session means its end is missing.
+            let error = if active {
+                LogError::Truncated
+            } else {
+                LogError::Modified
+            };
+            return Err((error, index));
+        }
+
+        let mac = record_mac(&key, &prev, &log[pos..mac_pos]);
+        if LittleEndian::read_u64(&header[0..8]) != seq
+            || mac[..] != log[mac_pos..mac_pos + LOG_MAC_SIZE]
+        {
+            return Err((LogError::Modified, index));
+        }
+
+        if kind == RecordKind::Start as u8 {
+            let start = &log[pos + LOG_HEADER_SIZE..mac_pos];
+            if start.len() != LOG_START_SIZE {
+                return Err((LogError::Malformed, index));
+            }
+
+            let mut uuid = [0; LOG_UUID_SIZE];
+            uuid.copy_from_slice(&start[4..]);
+
+            // Sessions are numbered by consecutive values of the same counter.
+            if LittleEndian::read_u32(&start[..4]) != session + 1
+                || counter.map_or(false, |counter| counter != uuid)
+            {
+                return Err((LogError::Modified, index));
+            }
+
+            session += 1;
+            counter = Some(uuid);
+        }
+
+        active = kind != RecordKind::Stop as u8;
+        seq += 1;
+        prev = mac;
+
+        pos = mac_pos + LOG_MAC_SIZE;
+        index += 1;
+    }
+
+    if active {
+        return Err((LogError::Truncated, index));
+    }
+
+    // Sessions removed from the end leave a valid chain, but fall behind the counter.
+    if let Some(uuid) = counter {
+        if read_counter(&uuid).map_err(|error| (error, index))? != session {
+            return Err((LogError::Truncated, index));
+        }
+    }
+
+    Ok(index)
+}
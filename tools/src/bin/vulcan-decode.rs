@@ -0,0 +1,134 @@
+//! Annotates candump output with decoded LeiA frames and optionally exports it to PCAP.
+
+extern crate vulcan;
+extern crate vulcan_tools;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+
+use vulcan::*;
+use vulcan_tools::candump;
+use vulcan_tools::config::{parse_layout, LAYOUT_USAGE};
+use vulcan_tools::pcap::PcapWriter;
+
+fn usage() -> ! {
+    eprintln!("Usage: vulcan-decode [options] [<candump log>]\n");
+    eprintln!("Decodes the LeiA frames in a candump log, or live from stdin, e.g.");
+    eprintln!("  candump -L can0 | vulcan-decode -a bb\n");
+    eprintln!("Options:");
+    eprintln!("  -a <id>      (id of the authentication error channel, in hexadecimal)");
+    eprintln!("  -l <layout>  (EID layout, see below)");
+    eprintln!(
+        "  -w <file>    (also write all frames to a PCAP file, PCAPNG if it ends in .pcapng)\n"
+    );
+    eprintln!("{}", LAYOUT_USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let mut aec_id = 0;
+    let mut layout = EidLayout::default();
+    let mut pcap = None;
+    let mut input = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-a" => {
+                aec_id = match args.next().map(|id| u16::from_str_radix(&id, 16)) {
+                    Some(Ok(id)) => id,
+                    _ => usage(),
+                }
+            }
+            "-l" => {
+                let spec = args.next().unwrap_or_else(|| usage());
+                layout = parse_layout(&spec).unwrap_or_else(|e| {
+                    eprintln!("vulcan-decode: {}", e);
+                    usage();
+                });
+            }
+            "-w" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                let writer = File::create(&path)
+                    .and_then(|file| {
+                        let file = BufWriter::new(file);
+                        if path.ends_with(".pcapng") {
+                            PcapWriter::new_pcapng(file)
+                        } else {
+                            PcapWriter::new(file)
+                        }
+                    })
+                    .unwrap_or_else(|e| {
+                        eprintln!("vulcan-decode: {}: {}", path, e);
+                        process::exit(1);
+                    });
+                pcap = Some(writer);
+            }
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let result = match input {
+        Some(path) => match File::open(&path) {
+            Ok(file) => decode(BufReader::new(file), aec_id, &layout, pcap),
+            Err(e) => {
+                eprintln!("vulcan-decode: {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => {
+            let stdin = io::stdin();
+            decode(stdin.lock(), aec_id, &layout, pcap)
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("vulcan-decode: {}", e);
+        process::exit(1);
+    }
+}
+
+fn decode<R, W>(
+    input: R,
+    aec_id: u16,
+    layout: &EidLayout,
+    mut pcap: Option<PcapWriter<W>>,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in input.lines() {
+        let line = line?;
+
+        match candump::parse_line(&line) {
+            Some(Ok(frame)) => {
+                match leia_decode(frame.id, &frame.data, aec_id, layout) {
+                    Some(leia) => writeln!(out, "{}  ; {}", line.trim(), leia)?,
+                    None => writeln!(out, "{}", line.trim())?,
+                }
+
+                if let Some(ref mut pcap) = pcap {
+                    pcap.write(&frame)?;
+                }
+            }
+            Some(Err(e)) => writeln!(out, "{}  ; {}", line.trim(), e)?,
+            None => writeln!(out, "{}", line)?,
+        }
+
+        // Keep up with live input.
+        out.flush()?;
+    }
+
+    if let Some(ref mut pcap) = pcap {
+        pcap.flush()?;
+    }
+
+    Ok(())
+}
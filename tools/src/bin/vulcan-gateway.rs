@@ -0,0 +1,143 @@
+//! Runs a candump log through a re-authenticating gateway between two LeiA domains.
+
+extern crate vulcan;
+extern crate vulcan_tools;
+
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::process;
+
+use vulcan::*;
+use vulcan_tools::candump::{self, Frame};
+use vulcan_tools::topology::Topology;
+
+thread_local! {
+    // Timestamp of the frame being processed, in microseconds.
+    static NOW: Cell<u64> = Cell::new(0);
+    // Frames sent by the gateway while processing the current frame.
+    static SENT: RefCell<Vec<(Domain, Frame)>> = RefCell::new(Vec::new());
+}
+
+fn now() -> u64 {
+    NOW.with(|now| now.get())
+}
+
+fn send(domain: Domain, id: u32, data: &[u8]) {
+    SENT.with(|sent| {
+        sent.borrow_mut().push((
+            domain,
+            Frame {
+                id: id,
+                data: data.to_vec(),
+                fd_flags: if data.len() > CAN_PAYLOAD_SIZE {
+                    Some(0)
+                } else {
+                    None
+                },
+                ..Default::default()
+            },
+        ))
+    });
+}
+
+fn send_a(id: u32, data: &[u8]) {
+    send(Domain::A, id, data);
+}
+
+fn send_b(id: u32, data: &[u8]) {
+    send(Domain::B, id, data);
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: vulcan-gateway <topology> [<candump log>]\n");
+    eprintln!("Runs a candump log (or stdin) through the gateway described by <topology>.");
+    eprintln!("The interface of every frame must be the name of one of the domains, and");
+    eprintln!("frames sent by the gateway are printed in candump format on the egress domain.");
+    process::exit(1);
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args.len() > 2 {
+        usage();
+    }
+
+    let mut topology = String::new();
+    if let Err(e) = File::open(&args[0]).and_then(|mut f| f.read_to_string(&mut topology)) {
+        eprintln!("vulcan-gateway: {}: {}", args[0], e);
+        process::exit(1);
+    }
+
+    let topology = Topology::parse(&topology).unwrap_or_else(|e| {
+        eprintln!("vulcan-gateway: {}: {}", args[0], e);
+        process::exit(1);
+    });
+
+    match args.get(1) {
+        Some(path) => match File::open(path) {
+            Ok(file) => run(&topology, BufReader::new(file)),
+            Err(e) => {
+                eprintln!("vulcan-gateway: {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => {
+            let stdin = io::stdin();
+            run(&topology, stdin.lock());
+        }
+    }
+}
+
+fn run<R: BufRead>(topology: &Topology, input: R) {
+    let mut gateway = topology.gateway(send_a, send_b, now);
+
+    for line in input.lines() {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("vulcan-gateway: {}", e);
+            process::exit(1);
+        });
+
+        let frame = match candump::parse_line(&line) {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                println!("# {}  ; {}", line.trim(), e);
+                continue;
+            }
+            None => continue,
+        };
+
+        let domain = match frame
+            .iface
+            .as_ref()
+            .and_then(|iface| topology.domain(iface))
+        {
+            Some(domain) => domain,
+            None => {
+                println!("# {}  ; unknown domain", line.trim());
+                continue;
+            }
+        };
+
+        NOW.with(|now| now.set((frame.timestamp.unwrap_or(0.0) * 1e6).round() as u64));
+
+        match gateway.recv(domain, frame.id, &frame.data) {
+            Ok((event, forward)) => println!("# {}  ; {:?} {:?}", frame, event, forward),
+            Err(()) => println!("# {}  ; not a LeiA frame", frame),
+        }
+
+        for (domain, mut sent) in
+            SENT.with(|sent| mem::replace(&mut *sent.borrow_mut(), Vec::new()))
+        {
+            let name = match domain {
+                Domain::A => &topology.names[0],
+                Domain::B => &topology.names[1],
+            };
+            sent.timestamp = frame.timestamp;
+            sent.iface = Some(name.clone());
+            println!("{}", sent);
+        }
+    }
+}
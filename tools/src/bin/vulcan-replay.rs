@@ -0,0 +1,146 @@
+//! Replays a candump log through a LeiA context and reports the resulting events.
+
+extern crate vulcan;
+extern crate vulcan_tools;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::process;
+
+use vulcan::*;
+use vulcan_tools::candump::{self, Frame};
+use vulcan_tools::config::{Config, CONFIG_USAGE, LAYOUT_USAGE};
+
+thread_local! {
+    // Timestamp of the frame being replayed, in microseconds.
+    static NOW: Cell<u64> = Cell::new(0);
+    // Frames the context would have sent while replaying the current frame.
+    static SENT: RefCell<Vec<Frame>> = RefCell::new(Vec::new());
+}
+
+fn now() -> u64 {
+    NOW.with(|now| now.get())
+}
+
+fn send(id: u32, data: &[u8]) {
+    SENT.with(|sent| {
+        sent.borrow_mut().push(Frame {
+            id: id,
+            data: data.to_vec(),
+            fd_flags: if data.len() > CAN_PAYLOAD_SIZE {
+                Some(0)
+            } else {
+                None
+            },
+            ..Default::default()
+        })
+    });
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: vulcan-replay [options] [<candump log>]\n");
+    eprintln!("Replays a candump log (or stdin) through a LeiA context.\n");
+    eprintln!("Options:");
+    eprintln!("{}\n", CONFIG_USAGE);
+    eprintln!("{}", LAYOUT_USAGE);
+    process::exit(1);
+}
+
+// Short name of an event, used in the summary.
+fn event_name(event: &Event) -> &'static str {
+    match *event {
+        Event::Received(_) => "received",
+        Event::Buffered(_) => "buffered",
+        Event::Authenticated(_) => "authenticated",
+        Event::MissingMAC(_) => "missing MAC",
+        Event::UnexpectedMAC(_) => "unexpected MAC",
+        Event::IncorrectMAC(_) => "incorrect MAC",
+        Event::Desync(_) => "desync",
+        Event::Resynced(_) => "resynced",
+        Event::UnknownId(_) => "unknown id",
+        Event::AuthFailSuppressed(..) => "AUTH_FAIL suppressed",
+        Event::Debug(_) => "debug",
+    }
+}
+
+fn main() {
+    let (config, rest) = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("vulcan-replay: {}", e);
+            usage();
+        }
+    };
+
+    match rest.len() {
+        0 => {
+            let stdin = io::stdin();
+            replay(&config, stdin.lock());
+        }
+        1 => match File::open(&rest[0]) {
+            Ok(file) => replay(&config, BufReader::new(file)),
+            Err(e) => {
+                eprintln!("vulcan-replay: {}: {}", rest[0], e);
+                process::exit(1);
+            }
+        },
+        _ => usage(),
+    }
+}
+
+fn replay<R: BufRead>(config: &Config, input: R) {
+    let mut context = config.context(send).with_time(now);
+    let mut summary = BTreeMap::new();
+    let mut errors = 0;
+
+    for (n, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("vulcan-replay: {}", e);
+                process::exit(1);
+            }
+        };
+
+        let frame = match candump::parse_line(&line) {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                println!("{:6}  {}  -> parse error: {}", n + 1, line.trim(), e);
+                errors += 1;
+                continue;
+            }
+            None => continue,
+        };
+
+        NOW.with(|now| now.set((frame.timestamp.unwrap_or(0.0) * 1e6).round() as u64));
+
+        match context.auth_recv(frame.id, &frame.data) {
+            Ok(event) => {
+                println!("{:6}  {}  -> {:?}", n + 1, frame, event);
+                *summary.entry(event_name(&event)).or_insert(0) += 1;
+            }
+            Err(()) => println!("{:6}  {}  -> not a LeiA frame", n + 1, frame),
+        }
+
+        for sent in SENT.with(|sent| mem::replace(&mut *sent.borrow_mut(), Vec::new())) {
+            println!("        would send {}", sent);
+        }
+    }
+
+    println!("\nEvents:");
+    for (name, count) in &summary {
+        println!("  {:16} {}", name, count);
+    }
+    if errors > 0 {
+        println!("  {:16} {}", "parse errors", errors);
+    }
+
+    println!("\nConnections:");
+    for connection in context.connections() {
+        println!("  0x{:03X}  {:?}", connection.id(), connection.stats());
+    }
+}
@@ -0,0 +1,282 @@
+//! Parsing of candump log files.
+//!
+//! Both the log file format of `candump -l` (`(timestamp) iface frame`) and bare
+//! frames as accepted by `cansend` are supported. Frames are written as
+//! `<can_id>#<data>` for classic CAN and `<can_id>##<flags><data>` for CAN FD,
+//! where a 3 digit id is a standard id and an 8 digit id an extended one.
+
+use std::fmt;
+
+use vulcan::*;
+
+pub const CAN_RTR_FLAG: u32 = 0x40000000;
+pub const CAN_ERR_FLAG: u32 = 0x20000000;
+
+/// A frame read from a candump log.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    /// Timestamp in seconds, if present.
+    pub timestamp: Option<f64>,
+    /// Interface the frame was captured on, if present.
+    pub iface: Option<String>,
+    /// Identifier including the EFF, RTR and ERR flags, as used by SocketCAN.
+    pub id: u32,
+    /// Payload of the frame.
+    pub data: Vec<u8>,
+    /// CAN FD flags, if this is a CAN FD frame.
+    pub fd_flags: Option<u8>,
+}
+
+impl Frame {
+    /// Returns whether the frame uses an extended identifier.
+    pub fn is_extended(&self) -> bool {
+        self.id & CAN_EFF_FLAG != 0
+    }
+}
+
+/// Reason a line could not be parsed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The timestamp is not of the form `(seconds.fraction)`.
+    Timestamp,
+    /// The identifier is not 3 or 8 hex digits followed by `#`.
+    Id,
+    /// The data is not a sequence of hex bytes, or too long.
+    Data,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Timestamp => write!(f, "invalid timestamp"),
+            ParseError::Id => write!(f, "invalid CAN id"),
+            ParseError::Data => write!(f, "invalid data"),
+        }
+    }
+}
+
+/// Parses a line of a candump log, returning `None` for empty lines and comments.
+pub fn parse_line(line: &str) -> Option<Result<Frame, ParseError>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let mut timestamp = None;
+    let mut iface = None;
+    let mut fields = line.split_whitespace().collect::<Vec<_>>();
+
+    if fields[0].starts_with('(') {
+        let ts = fields.remove(0);
+        if !ts.ends_with(')') {
+            return Some(Err(ParseError::Timestamp));
+        }
+        match ts[1..ts.len() - 1].parse() {
+            Ok(ts) => timestamp = Some(ts),
+            Err(_) => return Some(Err(ParseError::Timestamp)),
+        }
+    }
+
+    if fields.len() == 2 {
+        iface = Some(fields.remove(0).to_string());
+    }
+    if fields.len() != 1 {
+        return Some(Err(ParseError::Id));
+    }
+
+    Some(parse_frame(fields[0]).map(|mut frame| {
+        frame.timestamp = timestamp;
+        frame.iface = iface;
+        frame
+    }))
+}
+
+/// Parses a single frame, in the format accepted by `parse_canframe`.
+pub fn parse_frame(cs: &str) -> Result<Frame, ParseError> {
+    let (id, rest) = match cs.find('#') {
+        Some(3) => (&cs[..3], &cs[4..]),
+        Some(8) => (&cs[..8], &cs[9..]),
+        _ => return Err(ParseError::Id),
+    };
+
+    let mut id = u32::from_str_radix(id, 16).map_err(|_| ParseError::Id)?;
+    if cs.find('#') == Some(8) && id & CAN_ERR_FLAG == 0 {
+        id |= CAN_EFF_FLAG;
+    }
+
+    let mut frame = Frame {
+        id: id,
+        ..Default::default()
+    };
+
+    if rest.starts_with('R') || rest.starts_with('r') {
+        frame.id |= CAN_RTR_FLAG;
+        return Ok(frame);
+    }
+
+    let (max_len, data) = if rest.starts_with('#') {
+        let flags = rest.chars().nth(1).and_then(|c| c.to_digit(16));
+        frame.fd_flags = Some(flags.ok_or(ParseError::Data)? as u8);
+        (CAN_FD_PAYLOAD_SIZE, &rest[2..])
+    } else {
+        (CAN_PAYLOAD_SIZE, rest)
+    };
+
+    let digits = data.chars().filter(|&c| c != '.').collect::<Vec<_>>();
+    if digits.len() % 2 != 0 || digits.len() / 2 > max_len {
+        return Err(ParseError::Data);
+    }
+
+    for pair in digits.chunks(2) {
+        let hi = pair[0].to_digit(16).ok_or(ParseError::Data)?;
+        let lo = pair[1].to_digit(16).ok_or(ParseError::Data)?;
+        frame.data.push((hi << 4 | lo) as u8);
+    }
+
+    Ok(frame)
+}
+
+impl fmt::Display for Frame {
+    /// Formats the frame as a line of a candump log.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ts) = self.timestamp {
+            write!(f, "({:.6}) ", ts)?;
+        }
+        if let Some(ref iface) = self.iface {
+            write!(f, "{} ", iface)?;
+        }
+
+        if self.is_extended() {
+            write!(f, "{:08X}#", self.id & CAN_EFF_MASK)?;
+        } else {
+            write!(f, "{:03X}#", self.id & 0x7FF)?;
+        }
+
+        if self.id & CAN_RTR_FLAG != 0 {
+            return write!(f, "R");
+        }
+        if let Some(flags) = self.fd_flags {
+            write!(f, "#{:X}", flags)?;
+        }
+        for b in &self.data {
+            write!(f, "{:02X}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classic_frames_are_parsed() {
+        let frame = parse_frame("123#DEADbeef").unwrap();
+        assert_eq!(frame.id, 0x123);
+        assert!(!frame.is_extended());
+        assert_eq!(frame.data, vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(frame.fd_flags, None);
+
+        let frame = parse_frame("123#").unwrap();
+        assert!(frame.data.is_empty());
+
+        // Bytes may be separated by dots.
+        let frame = parse_frame("123#01.02.03").unwrap();
+        assert_eq!(frame.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn extended_ids_have_eight_digits() {
+        let frame = parse_frame("00000123#01").unwrap();
+        assert_eq!(frame.id, 0x123 | CAN_EFF_FLAG);
+        assert!(frame.is_extended());
+
+        let frame = parse_frame("1FFFFFFF#").unwrap();
+        assert_eq!(frame.id, CAN_EFF_MASK | CAN_EFF_FLAG);
+
+        // Error frames don't get the EFF flag.
+        let frame = parse_frame("20000004#0000000000000000").unwrap();
+        assert_eq!(frame.id, CAN_ERR_FLAG | 4);
+        assert!(!frame.is_extended());
+    }
+
+    #[test]
+    fn fd_frames_carry_flags() {
+        let frame = parse_frame("123##1").unwrap();
+        assert_eq!(frame.fd_flags, Some(1));
+        assert!(frame.data.is_empty());
+
+        let data = "00".repeat(CAN_FD_PAYLOAD_SIZE);
+        let frame = parse_frame(&format!("00000123##F{}", data)).unwrap();
+        assert_eq!(frame.fd_flags, Some(0xF));
+        assert_eq!(frame.data.len(), CAN_FD_PAYLOAD_SIZE);
+        assert!(frame.is_extended());
+    }
+
+    #[test]
+    fn remote_frames_have_no_data() {
+        let frame = parse_frame("123#R").unwrap();
+        assert_eq!(frame.id, 0x123 | CAN_RTR_FLAG);
+        assert!(frame.data.is_empty());
+
+        let frame = parse_frame("00000123#r").unwrap();
+        assert_eq!(frame.id, 0x123 | CAN_EFF_FLAG | CAN_RTR_FLAG);
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        assert_eq!(parse_frame("12#01"), Err(ParseError::Id));
+        assert_eq!(parse_frame("1234#01"), Err(ParseError::Id));
+        assert_eq!(parse_frame("12G#01"), Err(ParseError::Id));
+        assert_eq!(parse_frame("123"), Err(ParseError::Id));
+        assert_eq!(parse_frame("123#0"), Err(ParseError::Data));
+        assert_eq!(parse_frame("123#0G"), Err(ParseError::Data));
+        assert_eq!(parse_frame("123#010203040506070809"), Err(ParseError::Data));
+        assert_eq!(parse_frame("123##"), Err(ParseError::Data));
+        assert_eq!(parse_frame("123##G01"), Err(ParseError::Data));
+
+        let data = "00".repeat(CAN_FD_PAYLOAD_SIZE + 1);
+        assert_eq!(
+            parse_frame(&format!("123##0{}", data)),
+            Err(ParseError::Data)
+        );
+    }
+
+    #[test]
+    fn log_lines_are_parsed() {
+        let frame = parse_line("(1436509052.249713) vcan0 123#01")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.timestamp, Some(1436509052.249713));
+        assert_eq!(frame.iface, Some("vcan0".to_string()));
+        assert_eq!(frame.data, vec![1]);
+        assert_eq!(frame.to_string(), "(1436509052.249713) vcan0 123#01");
+
+        let frame = parse_line("  00000123##1AB  ").unwrap().unwrap();
+        assert_eq!(frame.timestamp, None);
+        assert_eq!(frame.iface, None);
+        assert_eq!(frame.to_string(), "00000123##1AB");
+
+        assert!(parse_line("").is_none());
+        assert!(parse_line("# comment").is_none());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert_eq!(
+            parse_line("(1.0 vcan0 123#01"),
+            Some(Err(ParseError::Timestamp))
+        );
+        assert_eq!(
+            parse_line("(abc) vcan0 123#01"),
+            Some(Err(ParseError::Timestamp))
+        );
+        assert_eq!(
+            parse_line("(1.0) vcan0 123#01 extra"),
+            Some(Err(ParseError::Id))
+        );
+        assert_eq!(parse_line("(1.0)"), Some(Err(ParseError::Id)));
+        assert_eq!(parse_line("vcan0 123#0"), Some(Err(ParseError::Data)));
+    }
+}
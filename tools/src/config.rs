@@ -0,0 +1,194 @@
+//! Command line configuration of the LeiA connections used by the tools.
+
+use vulcan::*;
+
+/// LeiA connections given on the command line.
+pub struct Config {
+    pub connections: Vec<LeiAConnection>,
+    pub aec: LeiAConnection,
+    pub layout: EidLayout,
+}
+
+pub const CONFIG_USAGE: &str =
+    "  -c <id>:<key>[:fd<n>|:sf<n>]  (LeiA connection, optionally in CAN FD or
+                                 single-frame mode with an <n> byte MAC)
+  -a <id>:<key>                 (authentication error channel)
+  -l <layout>                   (EID layout, see below)
+
+Ids are given in hexadecimal, keys as 32 hexadecimal digits.";
+
+pub const LAYOUT_USAGE: &str =
+    "An EID layout is given as <counter bits>,<cmd shift>,<id shift>,<id bits>[,<base>]
+with the base in hexadecimal, e.g. 4,4,6,11,18000000. The default is 16,16,18,11.";
+
+impl Config {
+    /// Parses the connection options from `args`, returning the remaining arguments.
+    pub fn from_args<I>(args: I) -> Result<(Config, Vec<String>), String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut connections = Vec::new();
+        let mut aec = None;
+        let mut layout = EidLayout::default();
+        let mut rest = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "-a" => {
+                    let spec = args.next().ok_or(format!("{} needs a value", arg))?;
+                    let connection = parse_connection(&spec)?;
+                    if arg == "-c" {
+                        connections.push(connection);
+                    } else {
+                        aec = Some(connection);
+                    }
+                }
+                "-l" => {
+                    let spec = args.next().ok_or("-l needs a value")?;
+                    layout = parse_layout(&spec)?;
+                }
+                _ => rest.push(arg),
+            }
+        }
+
+        if connections.len() > 16 {
+            return Err("at most 16 connections are supported".to_string());
+        }
+
+        Ok((
+            Config {
+                connections: connections,
+                aec: aec.ok_or("no authentication error channel given (-a)")?,
+                layout: layout,
+            },
+            rest,
+        ))
+    }
+
+    /// Creates a LeiA context for the configured connections.
+    pub fn context(&self, send: fn(u32, &[u8])) -> LeiAContext<HashStore<u16, LeiAPending>> {
+        let mut context =
+            leia(&self.connections, self.aec, HashStore::new(), send).with_layout(self.layout);
+        context.init();
+        context
+    }
+}
+
+/// Parses a connection given as `<id>:<key>[:fd<n>|:sf<n>]`.
+pub fn parse_connection(spec: &str) -> Result<LeiAConnection, String> {
+    let parts = spec.split(':').collect::<Vec<_>>();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(format!("invalid connection '{}'", spec));
+    }
+
+    let id = match u16::from_str_radix(parts[0], 16) {
+        Ok(id) if id != 0 && id <= 0x7FF => id,
+        _ => return Err(format!("invalid id '{}'", parts[0])),
+    };
+
+    let key = parse_key(parts[1]).ok_or(format!("invalid key '{}'", parts[1]))?;
+    let mut connection = LeiAConnection::new(id).with_k_i(&key);
+
+    if let Some(mode) = parts.get(2) {
+        let mac_len = mode
+            .get(2..)
+            .and_then(|n| n.parse().ok())
+            .ok_or(format!("invalid mode '{}'", mode))?;
+
+        connection = match &mode[..2] {
+            "fd" if mac_len > 0 && mac_len <= 16 => connection.with_fd(mac_len),
+            "sf" if mac_len > 0 && mac_len < CAN_PAYLOAD_SIZE => {
+                connection.with_single_frame(mac_len)
+            }
+            _ => return Err(format!("invalid mode '{}'", mode)),
+        };
+    }
+
+    Ok(connection)
+}
+
+/// Parses an EID layout given as `<counter bits>,<cmd shift>,<id shift>,<id bits>[,<base>]`.
+pub fn parse_layout(spec: &str) -> Result<EidLayout, String> {
+    let parts = spec.split(',').collect::<Vec<_>>();
+    if parts.len() < 4 || parts.len() > 5 {
+        return Err(format!("invalid layout '{}'", spec));
+    }
+
+    let mut fields = [0; 4];
+    for (field, part) in fields.iter_mut().zip(&parts) {
+        *field = part
+            .parse()
+            .map_err(|_| format!("invalid layout field '{}'", part))?;
+    }
+
+    let base = match parts.get(4) {
+        Some(base) => {
+            u32::from_str_radix(base, 16).map_err(|_| format!("invalid base '{}'", base))?
+        }
+        None => 0,
+    };
+
+    let (counter_bits, cmd_shift, id_shift, id_bits) = (fields[0], fields[1], fields[2], fields[3]);
+    if counter_bits == 0 || counter_bits > 16 || id_bits == 0 || id_bits > 16 {
+        return Err(format!("invalid layout '{}'", spec));
+    }
+
+    // Check for overlapping fields here, as EidLayout::new asserts on them.
+    let counter = (1u64 << counter_bits) - 1;
+    let cmd = 0b11u64 << cmd_shift;
+    let id = ((1u64 << id_bits) - 1) << id_shift;
+    if cmd_shift > 29
+        || id_shift > 29
+        || counter & cmd != 0
+        || counter & id != 0
+        || cmd & id != 0
+        || (counter | cmd | id) > CAN_EFF_MASK as u64
+    {
+        return Err(format!(
+            "fields of layout '{}' overlap or don't fit in 29 bits",
+            spec
+        ));
+    }
+
+    Ok(EidLayout::new(counter_bits, cmd_shift, id_shift, id_bits).with_base(base))
+}
+
+fn parse_key(hex: &str) -> Option<SancusKey> {
+    if hex.len() != 2 * SANCUS_KEY_SIZE {
+        return None;
+    }
+
+    let mut key = SancusKey::default();
+    for (i, k) in key.iter_mut().enumerate() {
+        *k = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_are_parsed() {
+        assert_eq!(parse_layout("16,16,18,11"), Ok(EidLayout::default()));
+        assert_eq!(
+            parse_layout("4,4,6,11,18000000"),
+            Ok(EidLayout::new(4, 4, 6, 11).with_base(0x18000000))
+        );
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        assert!(parse_layout("16,16,18").is_err());
+        assert!(parse_layout("16,16,18,11,0,0").is_err());
+        assert!(parse_layout("0,16,18,11").is_err());
+        assert!(parse_layout("16,16,18,17").is_err());
+        assert!(parse_layout("16,15,18,11").is_err());
+        assert!(parse_layout("16,16,19,11").is_err());
+        assert!(parse_layout("16,16,18,11,G").is_err());
+        assert!(parse_layout("16,40,18,11").is_err());
+    }
+}
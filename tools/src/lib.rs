@@ -0,0 +1,9 @@
+//! Offline tools for analysing VulCAN traffic.
+
+extern crate byteorder;
+extern crate vulcan;
+
+pub mod candump;
+pub mod config;
+pub mod pcap;
+pub mod topology;
@@ -0,0 +1,221 @@
+//! Export of frames to PCAP and PCAPNG files with the SocketCAN link type, as
+//! read by Wireshark.
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+
+use vulcan::*;
+
+use candump::Frame;
+
+const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_CAN_SOCKETCAN: u32 = 227;
+
+const PCAPNG_SHB: u32 = 0x0A0D0D0A;
+const PCAPNG_IDB: u32 = 0x00000001;
+const PCAPNG_EPB: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_SHB_SIZE: u32 = 28;
+const PCAPNG_IDB_SIZE: u32 = 20;
+const PCAPNG_EPB_SIZE: u32 = 32;
+
+const SOCKETCAN_HEADER_SIZE: usize = 8;
+const CANFD_FDF: u8 = 0x04;
+
+/// Writes frames to a PCAP or PCAPNG file.
+pub struct PcapWriter<W: Write> {
+    out: W,
+    pcapng: bool,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a new PCAP writer, writing the file header to `out`.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+        out.write_u16::<LittleEndian>(PCAP_VERSION_MAJOR)?;
+        out.write_u16::<LittleEndian>(PCAP_VERSION_MINOR)?;
+        out.write_i32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>((SOCKETCAN_HEADER_SIZE + CAN_FD_PAYLOAD_SIZE) as u32)?;
+        out.write_u32::<LittleEndian>(LINKTYPE_CAN_SOCKETCAN)?;
+
+        Ok(PcapWriter {
+            out: out,
+            pcapng: false,
+        })
+    }
+
+    /// Creates a new PCAPNG writer, writing the section header and a single
+    /// interface with microsecond timestamps to `out`.
+    pub fn new_pcapng(mut out: W) -> io::Result<Self> {
+        out.write_u32::<LittleEndian>(PCAPNG_SHB)?;
+        out.write_u32::<LittleEndian>(PCAPNG_SHB_SIZE)?;
+        out.write_u32::<LittleEndian>(PCAPNG_BYTE_ORDER_MAGIC)?;
+        out.write_u16::<LittleEndian>(1)?;
+        out.write_u16::<LittleEndian>(0)?;
+        // Unknown section length.
+        out.write_i64::<LittleEndian>(-1)?;
+        out.write_u32::<LittleEndian>(PCAPNG_SHB_SIZE)?;
+
+        out.write_u32::<LittleEndian>(PCAPNG_IDB)?;
+        out.write_u32::<LittleEndian>(PCAPNG_IDB_SIZE)?;
+        out.write_u16::<LittleEndian>(LINKTYPE_CAN_SOCKETCAN as u16)?;
+        out.write_u16::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>((SOCKETCAN_HEADER_SIZE + CAN_FD_PAYLOAD_SIZE) as u32)?;
+        out.write_u32::<LittleEndian>(PCAPNG_IDB_SIZE)?;
+
+        Ok(PcapWriter {
+            out: out,
+            pcapng: true,
+        })
+    }
+
+    /// Writes a frame, using its timestamp if present.
+    ///
+    /// Frames are padded to the size of the SocketCAN `can_frame` or
+    /// `canfd_frame` structures.
+    pub fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let ts = frame.timestamp.unwrap_or(0.0);
+        let secs = ts.trunc();
+        let usecs = ((ts - secs) * 1e6).round().min(999_999.0);
+
+        let (flags, data_len) = match frame.fd_flags {
+            Some(flags) => (flags | CANFD_FDF, CAN_FD_PAYLOAD_SIZE),
+            None => (0, CAN_PAYLOAD_SIZE),
+        };
+        // Both sizes are a multiple of 4, so PCAPNG needs no padding.
+        let len = (SOCKETCAN_HEADER_SIZE + data_len) as u32;
+
+        if self.pcapng {
+            let ts = secs as u64 * 1_000_000 + usecs as u64;
+            self.out.write_u32::<LittleEndian>(PCAPNG_EPB)?;
+            self.out.write_u32::<LittleEndian>(PCAPNG_EPB_SIZE + len)?;
+            self.out.write_u32::<LittleEndian>(0)?;
+            self.out.write_u32::<LittleEndian>((ts >> 32) as u32)?;
+            self.out.write_u32::<LittleEndian>(ts as u32)?;
+        } else {
+            self.out.write_u32::<LittleEndian>(secs as u32)?;
+            self.out.write_u32::<LittleEndian>(usecs as u32)?;
+        }
+        self.out.write_u32::<LittleEndian>(len)?;
+        self.out.write_u32::<LittleEndian>(len)?;
+
+        self.out.write_u32::<BigEndian>(frame.id)?;
+        self.out.write_u8(frame.data.len() as u8)?;
+        self.out.write_u8(flags)?;
+        self.out.write_u16::<LittleEndian>(0)?;
+
+        let mut data = [0; CAN_FD_PAYLOAD_SIZE];
+        data[..frame.data.len()].copy_from_slice(&frame.data);
+        self.out.write_all(&data[..data_len])?;
+
+        if self.pcapng {
+            self.out.write_u32::<LittleEndian>(PCAPNG_EPB_SIZE + len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+    fn frame(id: u32, data: &[u8], fd_flags: Option<u8>) -> Frame {
+        Frame {
+            timestamp: Some(1.5),
+            id: id,
+            data: data.to_vec(),
+            fd_flags: fd_flags,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn global_header_uses_socketcan() {
+        let writer = PcapWriter::new(Vec::new()).unwrap();
+        let out = writer.out;
+
+        assert_eq!(out.len(), 24);
+        assert_eq!(&out[..4], &[0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(LittleEndian::read_u16(&out[4..6]), 2);
+        assert_eq!(LittleEndian::read_u16(&out[6..8]), 4);
+        assert_eq!(LittleEndian::read_u32(&out[16..20]), 72);
+        assert_eq!(LittleEndian::read_u32(&out[20..24]), 227);
+    }
+
+    #[test]
+    fn classic_frames_are_padded() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write(&frame(0x123, &[1, 2, 3], None)).unwrap();
+        let record = &writer.out[24..];
+
+        assert_eq!(record.len(), 16 + 16);
+        assert_eq!(LittleEndian::read_u32(&record[0..4]), 1);
+        assert_eq!(LittleEndian::read_u32(&record[4..8]), 500_000);
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), 16);
+        assert_eq!(LittleEndian::read_u32(&record[12..16]), 16);
+
+        let can = &record[16..];
+        assert_eq!(&can[..4], &[0x00, 0x00, 0x01, 0x23]);
+        assert_eq!(can[4], 3);
+        assert_eq!(can[5], 0);
+        assert_eq!(&can[8..], &[1, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn extended_fd_frames_keep_flags() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let id = 0x1234567 | CAN_EFF_FLAG;
+        writer.write(&frame(id, &[0xAA; 12], Some(1))).unwrap();
+        let record = &writer.out[24..];
+
+        assert_eq!(record.len(), 16 + 8 + CAN_FD_PAYLOAD_SIZE);
+        assert_eq!(LittleEndian::read_u32(&record[8..12]), 72);
+
+        let can = &record[16..];
+        assert_eq!(BigEndian::read_u32(&can[..4]), id);
+        assert_eq!(can[0] & 0x80, 0x80);
+        assert_eq!(can[4], 12);
+        assert_eq!(can[5], 1 | CANFD_FDF);
+        assert_eq!(&can[8..20], &[0xAA; 12]);
+        assert!(can[20..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn pcapng_wraps_frames_in_blocks() {
+        let mut writer = PcapWriter::new_pcapng(Vec::new()).unwrap();
+        writer.write(&frame(0x123, &[1], None)).unwrap();
+        let out = writer.out;
+
+        let shb = &out[..28];
+        assert_eq!(LittleEndian::read_u32(&shb[0..4]), 0x0A0D0D0A);
+        assert_eq!(LittleEndian::read_u32(&shb[4..8]), 28);
+        assert_eq!(LittleEndian::read_u32(&shb[8..12]), 0x1A2B3C4D);
+        assert_eq!(LittleEndian::read_u32(&shb[24..28]), 28);
+
+        let idb = &out[28..48];
+        assert_eq!(LittleEndian::read_u32(&idb[0..4]), 1);
+        assert_eq!(LittleEndian::read_u16(&idb[8..10]), 227);
+        assert_eq!(LittleEndian::read_u32(&idb[16..20]), 20);
+
+        let epb = &out[48..];
+        assert_eq!(epb.len(), 32 + 16);
+        assert_eq!(LittleEndian::read_u32(&epb[0..4]), 6);
+        assert_eq!(LittleEndian::read_u32(&epb[4..8]), 48);
+        assert_eq!(LittleEndian::read_u32(&epb[12..16]), 0);
+        assert_eq!(LittleEndian::read_u32(&epb[16..20]), 1_500_000);
+        assert_eq!(LittleEndian::read_u32(&epb[20..24]), 16);
+        assert_eq!(&epb[28..32], &[0x00, 0x00, 0x01, 0x23]);
+        assert_eq!(LittleEndian::read_u32(&epb[44..48]), 48);
+    }
+}
@@ -0,0 +1,153 @@
+//! Topology files describing the two domains of a re-authenticating gateway.
+//!
+//! A topology file contains one statement per line:
+//!
+//! ```text
+//! # Two zones with their own keys
+//! domain powertrain aec bb:<key>
+//! domain body aec bb:<key> layout 4,4,6,11,18000000
+//! conn powertrain f0:<key>
+//! conn body 1f0:<key>:fd8
+//! route powertrain f0 -> body 1f0 interval 10000
+//! ```
+//!
+//! Connections and EID layouts are given as for the `-c` and `-l` options of
+//! the tools, domains without a layout use the default one. The optional
+//! interval of a route is the minimum time between forwarded messages, in
+//! microseconds.
+
+use vulcan::*;
+
+use config::{parse_connection, parse_layout, Config};
+
+/// Gateway built from a topology, using `HashStore`s.
+pub type TopologyGateway = DomainGateway<HashStore<u16, LeiAPending>, HashStore<u16, LeiAPending>>;
+
+/// Domains and routes of a re-authenticating gateway.
+pub struct Topology {
+    /// Names of domain A and B, in the order they are declared.
+    pub names: [String; 2],
+    pub domains: [Config; 2],
+    pub routes: Vec<DomainRoute>,
+}
+
+impl Topology {
+    /// Parses a topology file.
+    pub fn parse(topology: &str) -> Result<Topology, String> {
+        let mut names = Vec::new();
+        let mut aecs = Vec::new();
+        let mut layouts = Vec::new();
+        let mut connections = vec![Vec::new(), Vec::new()];
+        let mut routes = Vec::new();
+
+        for (n, line) in topology.lines().enumerate() {
+            let words = line
+                .split('#')
+                .next()
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let error = |e: String| format!("line {}: {}", n + 1, e);
+
+            let domain = |name: &str| {
+                names
+                    .iter()
+                    .position(|d| d == name)
+                    .ok_or(error(format!("unknown domain '{}'", name)))
+            };
+
+            match (words.first().cloned(), words.len()) {
+                (None, _) => {}
+                (Some("domain"), 4) | (Some("domain"), 6)
+                    if words[2] == "aec" && words.get(4).map_or(true, |&w| w == "layout") =>
+                {
+                    if names.len() == 2 {
+                        return Err(error("only two domains are supported".to_string()));
+                    }
+                    aecs.push(parse_connection(words[3]).map_err(&error)?);
+                    layouts.push(match words.get(5) {
+                        Some(spec) => parse_layout(spec).map_err(&error)?,
+                        None => EidLayout::default(),
+                    });
+                    names.push(words[1].to_string());
+                }
+                (Some("conn"), 3) => {
+                    let d = domain(words[1])?;
+                    connections[d].push(parse_connection(words[2]).map_err(&error)?);
+                }
+                (Some("route"), 6) | (Some("route"), 8) if words[3] == "->" => {
+                    let (from, to) = (domain(words[1])?, domain(words[4])?);
+                    if from == to {
+                        return Err(error("routes must cross domains".to_string()));
+                    }
+
+                    let id = |id: &str| match u16::from_str_radix(id, 16) {
+                        Ok(id) if id != 0 && id <= 0x7FF => Ok(id),
+                        _ => Err(error(format!("invalid id '{}'", id))),
+                    };
+                    let from = if from == 0 { Domain::A } else { Domain::B };
+                    let mut route = DomainRoute::new(from, id(words[2])?, id(words[5])?);
+
+                    if words.len() == 8 {
+                        let interval = match (words[6], words[7].parse()) {
+                            ("interval", Ok(interval)) => interval,
+                            _ => return Err(error("invalid route interval".to_string())),
+                        };
+                        route = route.with_min_interval(interval);
+                    }
+
+                    routes.push(route);
+                }
+                _ => return Err(error(format!("invalid statement '{}'", line.trim()))),
+            }
+        }
+
+        if names.len() != 2 {
+            return Err("exactly two domains must be declared".to_string());
+        }
+        if routes.len() > 16 || connections.iter().any(|c| c.len() > 16) {
+            return Err("at most 16 connections and routes are supported".to_string());
+        }
+
+        let b = Config {
+            connections: connections.pop().unwrap(),
+            aec: aecs[1],
+            layout: layouts[1],
+        };
+        let a = Config {
+            connections: connections.pop().unwrap(),
+            aec: aecs[0],
+            layout: layouts[0],
+        };
+
+        Ok(Topology {
+            names: [names[0].clone(), names[1].clone()],
+            domains: [a, b],
+            routes: routes,
+        })
+    }
+
+    /// Gets the domain with the specified name.
+    pub fn domain(&self, name: &str) -> Option<Domain> {
+        match self.names.iter().position(|d| d == name) {
+            Some(0) => Some(Domain::A),
+            Some(_) => Some(Domain::B),
+            None => None,
+        }
+    }
+
+    /// Creates the gateway, sending on domain A with `send_a` and on B with `send_b`,
+    /// and timing both domains with `time`.
+    pub fn gateway(
+        &self,
+        send_a: fn(u32, &[u8]),
+        send_b: fn(u32, &[u8]),
+        time: fn() -> u64,
+    ) -> TopologyGateway {
+        DomainGateway::new(
+            self.domains[0].context(send_a).with_time(time),
+            self.domains[1].context(send_b).with_time(time),
+            &self.routes,
+        )
+    }
+}
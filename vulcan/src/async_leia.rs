@@ -0,0 +1,368 @@
+use freshness::*;
+use leia::*;
+use vulcan::*;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures_core::Stream;
+
+/// Trait representing a non-blocking CAN (FD) transceiver, to be implemented on
+/// top of the driver of the executor in use.
+pub trait AsyncCan {
+    type Error;
+
+    /// Attempts to transmit a frame with extended id `eid`.
+    fn poll_transmit(
+        &mut self,
+        cx: &mut Context,
+        eid: u32,
+        data: &[u8],
+    ) -> Poll<Result<(), Self::Error>>;
+
+    /// Attempts to receive a frame into `buf`, returning its extended id and length.
+    fn poll_receive(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut CANFDPayload,
+    ) -> Poll<Result<(u32, usize), Self::Error>>;
+}
+
+/// Asynchronous wrapper around a [LeiAContext](struct.LeiAContext.html).
+///
+/// Received events are obtained by polling the wrapper as a `Stream`. The
+/// timeouts of the context are handled by [poll](struct.LeiAContext.html#method.poll)
+/// at the time of its clock, and the resulting events are part of the stream.
+///
+/// Timeouts are only checked when the wrapper is polled, so a timer of the
+/// executor should wake the task at [next_timeout](#method.next_timeout). At most 32
+/// events are kept until they are taken from the stream, further events are
+/// dropped and counted by [dropped_events](#method.dropped_events).
+pub struct AsyncLeiA<C, S, F = NoFreshness, T = fn() -> u64>
+where
+    C: AsyncCan,
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    can: C,
+    context: LeiAContext<S, F, T>,
+    outgoing: Option<(u32, Payload)>,
+    events: Queue<Event>,
+    dropped_events: u64,
+}
+
+impl<C, S, F, T> AsyncLeiA<C, S, F, T>
+where
+    C: AsyncCan,
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    /// Creates a new wrapper sending and receiving the frames of `context` on `can`.
+    pub fn new(can: C, context: LeiAContext<S, F, T>) -> Self {
+        Self {
+            can: can,
+            context: context.with_queue(),
+            outgoing: None,
+            events: Queue::new(),
+            dropped_events: 0,
+        }
+    }
+
+    /// Sets the time after which AUTH_FAIL is sent again if no new epoch was
+    /// received, doubling it for every retry. Shorthand for
+    /// [with_auth_fail_retry](struct.LeiAContext.html#method.with_auth_fail_retry)
+    /// on the wrapped context, retrying as long as possible.
+    pub fn with_auth_fail_timeout(mut self, timeout: u64) -> Self {
+        self.context = self.context.with_auth_fail_retry(timeout, u8::max_value());
+        self
+    }
+
+    /// Gets the transceiver.
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    /// Gets the wrapped context.
+    pub fn context(&mut self) -> &mut LeiAContext<S, F, T> {
+        &mut self.context
+    }
+
+    /// Gets the time at which the stream needs to be polled again to handle the
+    /// timeouts of the context, see
+    /// [next_timeout](struct.LeiAContext.html#method.next_timeout).
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.context.next_timeout()
+    }
+
+    /// Gets the number of events dropped because they weren't taken from the
+    /// stream in time.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
+    }
+
+    /// Sends authenticated message `msg` on connection `id`.
+    pub fn send_authenticated<'a>(
+        &'a mut self,
+        id: u16,
+        msg: &'a [u8],
+    ) -> SendAuthenticated<'a, C, S, F, T> {
+        SendAuthenticated {
+            leia: self,
+            id: id,
+            msg: Some(msg),
+        }
+    }
+
+    // Transmits all frames queued by the context.
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<(), C::Error>> {
+        loop {
+            if self.outgoing.is_none() {
+                self.outgoing = self.context.pop_frame();
+            }
+
+            let (eid, data) = match self.outgoing {
+                Some(frame) => frame,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            match self.can.poll_transmit(cx, eid, &data) {
+                Poll::Ready(result) => {
+                    self.outgoing = None;
+                    if let Err(e) = result {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    // Handles the timeouts of the context.
+    fn poll_timeouts(&mut self) {
+        let events = &mut self.events;
+        let dropped = &mut self.dropped_events;
+        self.context.poll(|event| {
+            if events.push(event).is_err() {
+                *dropped += 1;
+            }
+        });
+    }
+}
+
+impl<C, S, F, T> Stream for AsyncLeiA<C, S, F, T>
+where
+    C: AsyncCan + Unpin,
+    S: LeiAStore + Unpin,
+    F: FreshnessSource + Unpin,
+    T: Clock + Unpin,
+{
+    type Item = Result<Event, C::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut buf = [0; CAN_FD_PAYLOAD_SIZE];
+        let mut timeouts_handled = false;
+
+        loop {
+            // Frames sent in response to received frames go out before anything
+            // else is received.
+            match this.poll_flush(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            // Timeouts are only handled with empty queues, as they can produce an
+            // event and two frames for every connection.
+            if !timeouts_handled && this.events.is_empty() {
+                this.poll_timeouts();
+                timeouts_handled = true;
+                continue;
+            }
+
+            if let Some(event) = this.events.pop() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+
+            match this.can.poll_receive(cx, &mut buf) {
+                Poll::Ready(Ok((eid, len))) => {
+                    // Frames not meant for the context are ignored.
+                    if let Ok(event) = this.context.auth_recv(eid, &buf[..len]) {
+                        if this.events.push(event).is_err() {
+                            this.dropped_events += 1;
+                        }
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Future returned by [send_authenticated](struct.AsyncLeiA.html#method.send_authenticated).
+pub struct SendAuthenticated<'a, C, S, F, T>
+where
+    C: AsyncCan + 'a,
+    S: LeiAStore + 'a,
+    F: FreshnessSource + 'a,
+    T: Clock + 'a,
+{
+    leia: &'a mut AsyncLeiA<C, S, F, T>,
+    id: u16,
+    msg: Option<&'a [u8]>,
+}
+
+impl<'a, C, S, F, T> Future for SendAuthenticated<'a, C, S, F, T>
+where
+    C: AsyncCan,
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    type Output = Result<(), C::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(msg) = this.msg.take() {
+            this.leia.context.leia_auth_send(this.id, msg, false);
+        }
+
+        this.leia.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    use core::ptr;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    static NOW: AtomicUsize = AtomicUsize::new(0);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::SeqCst) as u64
+    }
+
+    static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+    fn noop_clone(_: *const ()) -> RawWaker {
+        RawWaker::new(ptr::null(), &NOOP_VTABLE)
+    }
+
+    fn noop(_: *const ()) {}
+
+    // Waker that does nothing, as the tests poll by hand.
+    fn noop_waker() -> Waker {
+        unsafe { Waker::from_raw(noop_clone(ptr::null())) }
+    }
+
+    // Transceiver transmitting everything and never receiving a frame.
+    struct IdleCan;
+
+    impl AsyncCan for IdleCan {
+        type Error = ();
+
+        fn poll_transmit(&mut self, _: &mut Context, _: u32, _: &[u8]) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_receive(
+            &mut self,
+            _: &mut Context,
+            _: &mut CANFDPayload,
+        ) -> Poll<Result<(u32, usize), ()>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn timeouts_beyond_queue_are_dropped() {
+        let mut connections = [LeiAConnection::new(0); 16];
+        for (i, connection) in connections.iter_mut().enumerate() {
+            *connection = LeiAConnection::new(0x100 + 2 * i as u16).with_k_i(&[1; SANCUS_KEY_SIZE]);
+        }
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new())
+            .with_time(now)
+            .with_mac_timeout(10)
+            .with_auth_fail_retry(10, 1);
+        context.init();
+
+        // Every connection sends AUTH_FAIL, suppresses another one and waits for
+        // a MAC, each of which is reported at the next timeout.
+        let mut leia = AsyncLeiA::new(IdleCan, context);
+        for connection in connections.iter() {
+            let id = connection.id();
+            let data = EidLayout::default().build(id, LeiACmd::Data, 1);
+            let mac = EidLayout::default().build(id + 1, LeiACmd::Mac, 1);
+
+            for _ in 0..2 {
+                leia.context().auth_recv(data, &[1]).unwrap();
+                leia.context()
+                    .auth_recv(mac, &[0; CAN_PAYLOAD_SIZE])
+                    .unwrap();
+            }
+            leia.context().auth_recv(data, &[1]).unwrap();
+        }
+
+        NOW.store(100, Ordering::SeqCst);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut events = 0;
+        while let Poll::Ready(Some(Ok(_))) = Pin::new(&mut leia).poll_next(&mut cx) {
+            events += 1;
+        }
+
+        assert_eq!(events, 32);
+        assert_eq!(leia.dropped_events(), 16);
+    }
+
+    #[test]
+    fn next_timeout_follows_pending_frames() {
+        static CLOCK: AtomicUsize = AtomicUsize::new(0);
+        fn clock() -> u64 {
+            CLOCK.load(Ordering::SeqCst) as u64
+        }
+
+        let connections = [LeiAConnection::new(0x100).with_k_i(&[1; SANCUS_KEY_SIZE])];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new())
+            .with_time(clock)
+            .with_mac_timeout(10)
+            .with_auth_fail_retry(20, 1);
+        context.init();
+        let mut leia = AsyncLeiA::new(IdleCan, context);
+        assert_eq!(leia.next_timeout(), None);
+
+        let data = EidLayout::default().build(0x100, LeiACmd::Data, 1);
+        CLOCK.store(5, Ordering::SeqCst);
+        leia.context().auth_recv(data, &[1]).unwrap();
+        assert_eq!(leia.next_timeout(), Some(16));
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        CLOCK.store(16, Ordering::SeqCst);
+        match Pin::new(&mut leia).poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(Event::MissingMAC(_)))) => {}
+            _ => panic!("MAC timeout not reported"),
+        }
+        assert_eq!(leia.next_timeout(), None);
+
+        // An incorrect MAC sends AUTH_FAIL, which is retried after its timeout.
+        let mac = EidLayout::default().build(0x101, LeiACmd::Mac, 2);
+        leia.context().auth_recv(data, &[1]).unwrap();
+        leia.context()
+            .auth_recv(mac, &[0; CAN_PAYLOAD_SIZE])
+            .unwrap();
+        assert_eq!(leia.next_timeout(), Some(37));
+    }
+}
@@ -0,0 +1,256 @@
+use freshness::*;
+use leia::*;
+use vulcan::*;
+
+use embedded_can::nb::Can;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+
+use nb;
+
+/// Builds the embedded-can identifier of a LeiA frame from the id, a LeiA command
+/// code and counter value, placed as given by `layout`.
+pub fn leia_build_id(id: u16, cmd: LeiACmd, counter: u16, layout: &EidLayout) -> ExtendedId {
+    ExtendedId::new(layout.build(id, cmd, counter) & CAN_EFF_MASK).unwrap()
+}
+
+/// Splits the embedded-can identifier of a LeiA frame into the id, LeiA command
+/// code and counter value, placed as given by `layout`. Returns `None` for
+/// standard identifiers.
+pub fn leia_parse_id(id: Id, layout: &EidLayout) -> Option<(u16, LeiACmd, u16)> {
+    match id {
+        Id::Extended(id) => layout.parse(id.as_raw()),
+        Id::Standard(_) => None,
+    }
+}
+
+/// Error while transmitting the queued frames of a LeiA context.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransmitError<E> {
+    /// The peripheral failed to transmit a frame.
+    Can(E),
+    /// The frame with this id can't be represented by the peripheral, e.g. because
+    /// it is a CAN FD frame or has an invalid standard id. The frame is dropped.
+    InvalidFrame(u32),
+}
+
+/// Outcome of [receive_from](struct.LeiAContext.html#method.receive_from).
+#[derive(Debug)]
+pub struct Reception<E> {
+    /// Event of the received frame, `None` for remote frames and frames not
+    /// meant for the context.
+    pub event: Option<Event>,
+    /// Result of transmitting the frames sent in response.
+    pub transmitted: Result<(), TransmitError<E>>,
+}
+
+// Converts an id as passed to the send function to an embedded-can identifier.
+fn can_id(eid: u32) -> Option<Id> {
+    if eid & CAN_EFF_FLAG != 0 {
+        ExtendedId::new(eid & CAN_EFF_MASK).map(Id::Extended)
+    } else if eid <= u16::max_value() as u32 {
+        StandardId::new(eid as u16).map(Id::Standard)
+    } else {
+        None
+    }
+}
+
+// Converts an embedded-can identifier to an id as passed to auth_recv.
+fn can_eid(id: Id) -> u32 {
+    match id {
+        Id::Extended(id) => id.as_raw() | CAN_EFF_FLAG,
+        Id::Standard(id) => id.as_raw() as u32,
+    }
+}
+
+impl<S, F, T> LeiAContext<S, F, T>
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    /// Transmits all queued frames on `can`, waiting for room in its transmit
+    /// buffer. Frames replaced by the peripheral are transmitted again.
+    ///
+    /// Queueing has to be enabled with [with_queue](#method.with_queue). Frames
+    /// the peripheral can't represent, like CAN FD frames on most peripherals,
+    /// are dropped with an error, leaving the remaining frames queued.
+    pub fn transmit_on<C>(&mut self, can: &mut C) -> Result<(), TransmitError<C::Error>>
+    where
+        C: Can,
+    {
+        while let Some((eid, data)) = self.pop_frame() {
+            let frame = match can_id(eid).and_then(|id| C::Frame::new(id, &data)) {
+                Some(frame) => frame,
+                None => return Err(TransmitError::InvalidFrame(eid)),
+            };
+
+            let mut replaced = nb::block!(can.transmit(&frame)).map_err(TransmitError::Can)?;
+            while let Some(frame) = replaced {
+                replaced = nb::block!(can.transmit(&frame)).map_err(TransmitError::Can)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receives a frame from `can`, if available, and authenticates it.
+    ///
+    /// Frames sent in response, like AUTH_FAIL, are transmitted on `can` before
+    /// returning. The event is returned even if their transmission fails.
+    pub fn receive_from<C>(&mut self, can: &mut C) -> nb::Result<Reception<C::Error>, C::Error>
+    where
+        C: Can,
+    {
+        let frame = can.receive()?;
+
+        let event = if frame.is_remote_frame() {
+            None
+        } else {
+            self.auth_recv(can_eid(frame.id()), frame.data()).ok()
+        };
+
+        Ok(Reception {
+            event: event,
+            transmitted: self.transmit_on(can),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_can::ErrorKind;
+    use store::ArrayStore;
+
+    struct TestFrame {
+        id: Id,
+        data: CANPayload,
+        len: usize,
+    }
+
+    impl Frame for TestFrame {
+        fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+            if data.len() > CAN_PAYLOAD_SIZE {
+                return None;
+            }
+
+            let mut frame = TestFrame {
+                id: id.into(),
+                data: [0; CAN_PAYLOAD_SIZE],
+                len: data.len(),
+            };
+            frame.data[..data.len()].copy_from_slice(data);
+            Some(frame)
+        }
+
+        fn new_remote(_: impl Into<Id>, _: usize) -> Option<Self> {
+            None
+        }
+
+        fn is_extended(&self) -> bool {
+            match self.id {
+                Id::Extended(_) => true,
+                Id::Standard(_) => false,
+            }
+        }
+
+        fn is_remote_frame(&self) -> bool {
+            false
+        }
+
+        fn id(&self) -> Id {
+            self.id
+        }
+
+        fn dlc(&self) -> usize {
+            self.len
+        }
+
+        fn data(&self) -> &[u8] {
+            &self.data[..self.len]
+        }
+    }
+
+    // Peripheral receiving a single frame and failing every transmission.
+    struct BrokenCan {
+        rx: Option<TestFrame>,
+    }
+
+    impl Can for BrokenCan {
+        type Frame = TestFrame;
+        type Error = ErrorKind;
+
+        fn transmit(&mut self, _: &TestFrame) -> nb::Result<Option<TestFrame>, ErrorKind> {
+            Err(nb::Error::Other(ErrorKind::Other))
+        }
+
+        fn receive(&mut self) -> nb::Result<TestFrame, ErrorKind> {
+            self.rx.take().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    fn context(connection: LeiAConnection) -> LeiAContext<ArrayStore<u16, LeiAPending>> {
+        let connections = [connection.with_k_i(&[1; SANCUS_KEY_SIZE])];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new()).with_queue();
+        context.init();
+        context
+    }
+
+    #[test]
+    fn fd_frames_are_rejected() {
+        let mut context = context(LeiAConnection::new(0x100).with_fd(8));
+        let mut can = BrokenCan { rx: None };
+
+        context.auth_send(0x100, &[0; 16]);
+        match context.transmit_on(&mut can) {
+            Err(TransmitError::InvalidFrame(eid)) => {
+                assert_eq!(parse_eid(eid).map(|p| p.0), Some(0x100))
+            }
+            _ => panic!("CAN FD frame transmitted"),
+        }
+        assert!(context.pop_frame().is_none());
+    }
+
+    #[test]
+    fn event_survives_failed_transmission() {
+        let mut context = context(LeiAConnection::new(0x100));
+        let mut can = BrokenCan { rx: None };
+
+        can.rx = TestFrame::new(
+            leia_build_id(0x100, LeiACmd::Data, 1, context.layout()),
+            &[1],
+        );
+        assert!(context.receive_from(&mut can).is_ok());
+
+        // The wrong MAC causes an AUTH_FAIL, which can't be transmitted.
+        can.rx = TestFrame::new(
+            leia_build_id(0x101, LeiACmd::Mac, 1, context.layout()),
+            &[0; CAN_PAYLOAD_SIZE],
+        );
+        match context.receive_from(&mut can) {
+            Ok(Reception {
+                event: Some(Event::IncorrectMAC(_)),
+                transmitted: Err(TransmitError::Can(ErrorKind::Other)),
+            }) => {}
+            _ => panic!("Event lost"),
+        }
+    }
+
+    #[test]
+    fn ids_follow_context_layout() {
+        let layout = EidLayout::new(4, 4, 6, 11).with_base(0x18 << 24);
+        let context = context(LeiAConnection::new(0x100)).with_layout(layout);
+
+        let id = leia_build_id(0x100, LeiACmd::AecMac, 0x13, context.layout());
+        assert_eq!(id.as_raw(), 0x18 << 24 | 0x100 << 6 | 3 << 4 | 3);
+        assert_eq!(
+            leia_parse_id(Id::Extended(id), context.layout()),
+            Some((0x100, LeiACmd::AecMac, 3))
+        );
+        assert_ne!(
+            leia_parse_id(Id::Extended(id), &EidLayout::default()),
+            Some((0x100, LeiACmd::AecMac, 3))
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use vulcan::*;
+
+const TIMESYNC_TRIP_SHIFT: u32 = 48;
+const TIMESYNC_RESET_SHIFT: u32 = 24;
+const TIMESYNC_RESET_MASK: u64 = 0xFFFFFF;
+const TIMESYNC_RESET_MAX: u32 = 0xFFFFFF;
+const TIMESYNC_RESET_JUMP_MAX: u32 = 16;
+const TIMESYNC_MAC_SIZE: usize = 3;
+
+/// Trait representing a source of freshness values, such as a SecOC Freshness
+/// Value Manager.
+pub trait FreshnessSource {
+    /// Gets the complete freshness value to be used for the next message sent on `id`.
+    fn tx_freshness(&mut self, id: u16) -> u64;
+
+    /// Confirms that a message using `freshness` is sent on `id`. Contexts don't
+    /// send the message if the value could not be recorded.
+    fn tx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>>;
+
+    /// Reconstructs the complete freshness value of a received message from its
+    /// `bits` least significant bits. `attempt` counts previous failed
+    /// verifications of the same message.
+    fn rx_freshness(&mut self, id: u16, truncated: u64, bits: u32, attempt: u8) -> Option<u64>;
+
+    /// Confirms that a message using `freshness` has been verified on `id`.
+    /// Contexts reject the message if the value could not be recorded.
+    fn rx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>>;
+}
+
+/// Placeholder for contexts that don't use an external freshness source.
+pub enum NoFreshness {}
+
+impl FreshnessSource for NoFreshness {
+    fn tx_freshness(&mut self, _: u16) -> u64 {
+        match *self {}
+    }
+
+    fn tx_confirmation(&mut self, _: u16, _: u64) -> Result<(), CapacityError<u64>> {
+        match *self {}
+    }
+
+    fn rx_freshness(&mut self, _: u16, _: u64, _: u32, _: u8) -> Option<u64> {
+        match *self {}
+    }
+
+    fn rx_confirmation(&mut self, _: u16, _: u64) -> Result<(), CapacityError<u64>> {
+        match *self {}
+    }
+}
+
+// Reconstructs a complete freshness value from its `bits` least significant bits,
+// relative to the `reference` value.
+fn reconstruct(reference: u64, truncated: u64, bits: u32, attempt: u8) -> Option<u64> {
+    if bits >= 64 {
+        return Some(truncated);
+    }
+
+    let mask: u64 = (1 << bits) - 1;
+    let upper = reference & !mask;
+
+    // Assume the upper part rolled over if the truncated value did not
+    // increase, and once more for every failed attempt.
+    let rollovers = (if truncated > reference & mask { 0 } else { 1 }) + attempt as u64;
+
+    Some(upper.checked_add(rollovers.checked_mul(1 << bits)?)? | truncated)
+}
+
+/// Freshness source using a monotonic counter per id, stored in a
+/// [VulCANStore](trait.VulCANStore.html).
+pub struct CounterFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    counters: S,
+}
+
+impl<S> CounterFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    /// Creates a new counter based freshness source.
+    pub fn new(counters: S) -> Self {
+        Self { counters: counters }
+    }
+
+    fn latest(&self, id: u16) -> u64 {
+        self.counters.get(&id).map_or(0, |&c| c)
+    }
+}
+
+impl<S> FreshnessSource for CounterFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    fn tx_freshness(&mut self, id: u16) -> u64 {
+        self.latest(id) + 1
+    }
+
+    fn tx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>> {
+        self.counters.insert(id, freshness).map(|_| ())
+    }
+
+    fn rx_freshness(&mut self, id: u16, truncated: u64, bits: u32, attempt: u8) -> Option<u64> {
+        let latest = self.latest(id);
+        let freshness = reconstruct(latest, truncated, bits, attempt)?;
+
+        if freshness > latest {
+            Some(freshness)
+        } else {
+            None
+        }
+    }
+
+    fn rx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>> {
+        self.counters.insert(id, freshness).map(|_| ())
+    }
+}
+
+// Computes the MAC of a time synchronisation frame.
+fn timesync_mac(id: u16, key: &SancusKey, counters: &[u8]) -> [u8; SANCUS_KEY_SIZE] {
+    let mut ad = [0; 2 + CAN_PAYLOAD_SIZE - TIMESYNC_MAC_SIZE];
+    BigEndian::write_u16(&mut ad[0..2], id);
+    ad[2..].copy_from_slice(counters);
+
+    SpongentMac::mac(key, &ad)
+}
+
+/// Master node broadcasting authenticated time synchronisation frames.
+///
+/// A synchronisation frame carries the 16 bit trip counter and the 24 bit reset
+/// counter, followed by a truncated MAC. The trip counter is incremented every
+/// trip (e.g. ignition cycle) and must be persisted by the application; the reset
+/// counter is incremented by every synchronisation frame.
+pub struct TimeSyncMaster {
+    id: u16,
+    key: SancusKey,
+    trip: u16,
+    reset: u32,
+}
+
+impl TimeSyncMaster {
+    /// Creates a new time synchronisation master sending on `id`.
+    pub fn new(id: u16, key: &[u8], trip: u16) -> Self {
+        let mut k = SancusKey::default();
+        k.copy_from_slice(key);
+
+        Self {
+            id: id,
+            key: k,
+            trip: trip,
+            reset: 0,
+        }
+    }
+
+    /// Gets the id synchronisation frames are sent on.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Starts a new trip, returning the trip counter to be persisted, or `None`
+    /// once the trip counter is exhausted.
+    pub fn new_trip(&mut self) -> Option<u16> {
+        self.trip = self.trip.checked_add(1)?;
+        self.reset = 0;
+        Some(self.trip)
+    }
+
+    /// Builds the next synchronisation frame, to be sent unauthenticated on `id`,
+    /// or returns `None` once the trip counter is exhausted.
+    pub fn sync_frame(&mut self) -> Option<CANPayload> {
+        if self.reset == TIMESYNC_RESET_MAX {
+            self.new_trip()?;
+        }
+        self.reset += 1;
+
+        let mut frame = [0; CAN_PAYLOAD_SIZE];
+        BigEndian::write_u16(&mut frame[0..2], self.trip);
+        BigEndian::write_uint(&mut frame[2..5], self.reset as u64, 3);
+
+        let mac = timesync_mac(self.id, &self.key, &frame[..5]);
+        frame[5..].copy_from_slice(&mac[..TIMESYNC_MAC_SIZE]);
+
+        Some(frame)
+    }
+}
+
+/// Freshness source deriving freshness values from a trip counter and reset
+/// counter, both distributed by a [TimeSyncMaster](struct.TimeSyncMaster.html),
+/// and a message counter per id.
+///
+/// A complete freshness value consists of the trip counter (16 bits), the reset
+/// counter (24 bits) and the message counter (24 bits). As the upper parts are
+/// taken from the last synchronisation frame, a node that missed any number of
+/// messages recovers as soon as it receives the next one.
+pub struct TimeSyncFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    id: u16,
+    key: SancusKey,
+    trip: u16,
+    reset: u32,
+    max_reset_jump: u32,
+    synced: bool,
+    latest: S,
+}
+
+impl<S> TimeSyncFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    /// Creates a new freshness source synchronised by frames on `id`.
+    pub fn new(id: u16, key: &[u8], latest: S) -> Self {
+        let mut k = SancusKey::default();
+        k.copy_from_slice(key);
+
+        Self {
+            id: id,
+            key: k,
+            trip: 0,
+            reset: 0,
+            max_reset_jump: TIMESYNC_RESET_JUMP_MAX,
+            synced: false,
+            latest: latest,
+        }
+    }
+
+    /// Limits how far the reset counter may advance at once, within a trip or
+    /// into the next one. Defaults to 16 synchronisation frames.
+    pub fn with_max_reset_jump(mut self, jump: u32) -> Self {
+        self.max_reset_jump = jump;
+        self
+    }
+
+    /// Gets the id synchronisation frames are received on.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns whether a synchronisation frame has been received.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Receives a synchronisation frame, returning whether it has been accepted.
+    ///
+    /// The first frame is accepted as is. Later frames are ignored if their MAC is
+    /// incorrect or their counters aren't strictly higher than the current ones,
+    /// but not too far ahead, so a single forged frame can't push the freshness
+    /// values beyond those of the master.
+    pub fn recv_sync(&mut self, msg: &[u8]) -> bool {
+        if msg.len() != CAN_PAYLOAD_SIZE {
+            return false;
+        }
+
+        let mac = timesync_mac(self.id, &self.key, &msg[..5]);
+        if mac[..TIMESYNC_MAC_SIZE] != msg[5..] {
+            return false;
+        }
+
+        let trip = BigEndian::read_u16(&msg[0..2]);
+        let reset = BigEndian::read_uint(&msg[2..5], 3) as u32;
+
+        let accepted = if !self.synced {
+            true
+        } else if trip == self.trip {
+            reset > self.reset && reset - self.reset <= self.max_reset_jump
+        } else {
+            trip as u32 == self.trip as u32 + 1 && reset <= self.max_reset_jump
+        };
+
+        if !accepted {
+            return false;
+        }
+
+        self.trip = trip;
+        self.reset = reset;
+        self.synced = true;
+
+        true
+    }
+
+    fn base(&self) -> u64 {
+        (self.trip as u64) << TIMESYNC_TRIP_SHIFT | (self.reset as u64) << TIMESYNC_RESET_SHIFT
+    }
+
+    fn latest(&self, id: u16) -> u64 {
+        self.latest.get(&id).map_or(0, |&c| c)
+    }
+}
+
+impl<S> FreshnessSource for TimeSyncFreshness<S>
+where
+    S: VulCANStore<K = u16, V = u64>,
+{
+    fn tx_freshness(&mut self, id: u16) -> u64 {
+        self.latest(id).max(self.base()) + 1
+    }
+
+    fn tx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>> {
+        self.latest.insert(id, freshness).map(|_| ())
+    }
+
+    fn rx_freshness(&mut self, id: u16, truncated: u64, bits: u32, attempt: u8) -> Option<u64> {
+        if !self.synced {
+            return None;
+        }
+
+        let latest = self.latest(id);
+        let freshness = reconstruct(latest.max(self.base()), truncated, bits, attempt)?;
+
+        // Accept messages from the current trip, at most one synchronisation
+        // period ahead of our own view.
+        let trip = (freshness >> TIMESYNC_TRIP_SHIFT) as u16;
+        let reset = ((freshness >> TIMESYNC_RESET_SHIFT) & TIMESYNC_RESET_MASK) as u32;
+
+        if freshness > latest && trip == self.trip && reset <= self.reset + 1 {
+            Some(freshness)
+        } else {
+            None
+        }
+    }
+
+    fn rx_confirmation(&mut self, id: u16, freshness: u64) -> Result<(), CapacityError<u64>> {
+        self.latest.insert(id, freshness).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    fn slave() -> TimeSyncFreshness<ArrayStore<u16, u64>> {
+        TimeSyncFreshness::new(0x10, &[1; SANCUS_KEY_SIZE], ArrayStore::new())
+    }
+
+    #[test]
+    fn exhausted_trip_counter_stops_sync() {
+        let mut master = TimeSyncMaster::new(0x10, &[1; SANCUS_KEY_SIZE], 0xFFFE);
+        assert_eq!(master.new_trip(), Some(0xFFFF));
+        assert_eq!(master.new_trip(), None);
+
+        master.reset = TIMESYNC_RESET_MAX;
+        assert!(master.sync_frame().is_none());
+        assert_eq!(master.trip, 0xFFFF);
+    }
+
+    #[test]
+    fn forged_sync_is_ignored() {
+        let mut master = TimeSyncMaster::new(0x10, &[1; SANCUS_KEY_SIZE], 1);
+        let mut slave = slave();
+
+        assert!(slave.recv_sync(&master.sync_frame().unwrap()));
+
+        let mut forged = master.sync_frame().unwrap();
+        forged[CAN_PAYLOAD_SIZE - 1] ^= 1;
+        assert!(!slave.recv_sync(&forged));
+        assert_eq!((slave.trip, slave.reset), (1, 1));
+    }
+
+    #[test]
+    fn replayed_sync_is_ignored() {
+        let mut master = TimeSyncMaster::new(0x10, &[1; SANCUS_KEY_SIZE], 1);
+        let mut slave = slave();
+
+        let first = master.sync_frame().unwrap();
+        let second = master.sync_frame().unwrap();
+        assert!(slave.recv_sync(&first));
+        assert!(slave.recv_sync(&second));
+        assert!(!slave.recv_sync(&second));
+        assert!(!slave.recv_sync(&first));
+        assert_eq!((slave.trip, slave.reset), (1, 2));
+    }
+
+    #[test]
+    fn sync_jump_is_bounded() {
+        let mut master = TimeSyncMaster::new(0x10, &[1; SANCUS_KEY_SIZE], 1);
+        let mut slave = slave();
+        assert!(slave.recv_sync(&master.sync_frame().unwrap()));
+
+        // Missed synchronisation frames are tolerated up to the limit.
+        for _ in 0..TIMESYNC_RESET_JUMP_MAX - 1 {
+            master.sync_frame().unwrap();
+        }
+        assert!(slave.recv_sync(&master.sync_frame().unwrap()));
+
+        master.reset += TIMESYNC_RESET_JUMP_MAX;
+        assert!(!slave.recv_sync(&master.sync_frame().unwrap()));
+
+        // A new trip restarts the reset counter.
+        master.new_trip().unwrap();
+        assert!(slave.recv_sync(&master.sync_frame().unwrap()));
+        assert_eq!((slave.trip, slave.reset), (2, 1));
+
+        master.new_trip().unwrap();
+        master.new_trip().unwrap();
+        assert!(!slave.recv_sync(&master.sync_frame().unwrap()));
+
+        // A frame at the end of the trip counter doesn't lock out the master.
+        let mut forger = TimeSyncMaster::new(0x10, &[1; SANCUS_KEY_SIZE], 0xFFFF);
+        assert!(!slave.recv_sync(&forger.sync_frame().unwrap()));
+        assert_eq!(slave.trip, 2);
+    }
+}
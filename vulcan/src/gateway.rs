@@ -0,0 +1,675 @@
+use freshness::*;
+use leia::*;
+use vulcan::*;
+
+/// Direction in which a [GatewayRoute](struct.GatewayRoute.html) forwards frames.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GatewayDirection {
+    /// Frames from the legacy bus are authenticated on the secure bus.
+    ToSecure,
+    /// Authenticated frames from the secure bus are forwarded without MAC to the legacy bus.
+    ToLegacy,
+    /// Both of the above.
+    Both,
+}
+
+// Minimum interval between forwarded frames.
+#[derive(Copy, Clone, Debug, Default)]
+struct RateLimit {
+    min_interval: u64,
+    last: Option<u64>,
+}
+
+impl RateLimit {
+    // Checks whether a frame may be forwarded at `now`, recording it if so.
+    fn allow(&mut self, now: u64) -> Forward {
+        if let Some(last) = self.last {
+            if now.saturating_sub(last) < self.min_interval {
+                return Forward::RateLimited;
+            }
+        }
+
+        self.last = Some(now);
+        Forward::Forwarded
+    }
+}
+
+/// Id allowed to pass a [LeiAGateway](struct.LeiAGateway.html).
+#[derive(Copy, Clone, Debug)]
+pub struct GatewayRoute {
+    id: u16,
+    direction: GatewayDirection,
+    to_secure: RateLimit,
+    to_legacy: RateLimit,
+}
+
+impl GatewayRoute {
+    /// Creates a new route forwarding frames on `id` in `direction`.
+    ///
+    /// On the secure side, `id` must be the id of a LeiA connection of the gateway.
+    pub fn new(id: u16, direction: GatewayDirection) -> Self {
+        Self {
+            id: id,
+            direction: direction,
+            to_secure: Default::default(),
+            to_legacy: Default::default(),
+        }
+    }
+
+    /// Limits the rate of the route, dropping frames that follow the previous
+    /// forwarded frame in the same direction within `min_interval`.
+    pub fn with_min_interval(mut self, min_interval: u64) -> Self {
+        self.to_secure.min_interval = min_interval;
+        self.to_legacy.min_interval = min_interval;
+        self
+    }
+
+    /// Gets the id of the route.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    // Checks the rate limit for a frame in the given direction at `now`.
+    fn allow(&mut self, to_secure: bool, now: u64) -> Forward {
+        let (allowed, limit) = if to_secure {
+            (
+                self.direction != GatewayDirection::ToLegacy,
+                &mut self.to_secure,
+            )
+        } else {
+            (
+                self.direction != GatewayDirection::ToSecure,
+                &mut self.to_legacy,
+            )
+        };
+
+        if allowed {
+            limit.allow(now)
+        } else {
+            Forward::NotAllowed
+        }
+    }
+}
+
+/// Outcome of a frame received by a [LeiAGateway](struct.LeiAGateway.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Forward {
+    /// The frame has been forwarded.
+    Forwarded,
+    /// No route allows the frame in this direction.
+    NotAllowed,
+    /// The frame exceeds the rate limit of its route.
+    RateLimited,
+    /// The data doesn't fit in a frame on the other bus.
+    TooLong,
+    /// The frame was not (yet) authenticated and is not forwarded.
+    NotAuthenticated,
+}
+
+/// Gateway between a legacy CAN bus and a bus secured with LeiA.
+///
+/// Frames from the legacy bus on an allowed id are sent authenticated on the
+/// secure bus, while frames from the secure bus are only forwarded to the
+/// legacy bus, without MAC, once they have been authenticated.
+///
+/// Rate limits use the [Clock](trait.Clock.html) of the secure context.
+pub struct LeiAGateway<S, F = NoFreshness, T = fn() -> u64>
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    secure: LeiAContext<S, F, T>,
+    routes: [GatewayRoute; 16],
+    legacy_send: fn(u32, &[u8]),
+}
+
+impl<S, F, T> LeiAGateway<S, F, T>
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    /// Creates a new gateway.
+    ///
+    /// # Parameters
+    ///
+    /// - `secure` - The initialised [LeiAContext](struct.LeiAContext.html) of the secure bus.
+    /// - `routes` - The allow-list of [GatewayRoute](struct.GatewayRoute.html)s.
+    pub fn new(secure: LeiAContext<S, F, T>, routes: &[GatewayRoute]) -> Self {
+        // @Cleanup @Hardcode: Same limit as LeiAContext.
+        let mut rs = [GatewayRoute::new(0, GatewayDirection::Both); 16];
+        rs[..routes.len()].copy_from_slice(routes);
+        Self {
+            secure: secure,
+            routes: rs,
+            legacy_send: |_, _| {},
+        }
+    }
+
+    /// Sets the function to be used by the gateway to send frames on the legacy bus.
+    pub fn with_legacy_send(mut self, legacy_send: fn(u32, &[u8])) -> Self {
+        self.legacy_send = legacy_send;
+        self
+    }
+
+    /// Gets the LeiA context of the secure bus.
+    pub fn secure(&mut self) -> &mut LeiAContext<S, F, T> {
+        &mut self.secure
+    }
+
+    /// Receives a frame from the legacy bus.
+    pub fn recv_legacy(&mut self, id: u32, msg: &[u8]) -> Forward {
+        if id & CAN_EFF_FLAG != 0 || id > 0x7FF {
+            return Forward::NotAllowed;
+        }
+        let id = id as u16;
+
+        let max_len = match self.secure.connections().find(|c| c.id() == id) {
+            Some(connection) => connection.mode().max_data_len(),
+            None => return Forward::NotAllowed,
+        };
+
+        if msg.len() > max_len {
+            return Forward::TooLong;
+        }
+
+        let now = self.secure.now();
+        let forward = match self.find_route(id) {
+            Some(route) => route.allow(true, now),
+            None => Forward::NotAllowed,
+        };
+
+        if forward == Forward::Forwarded {
+            self.secure.auth_send(id, msg);
+        }
+
+        forward
+    }
+
+    /// Receives a frame from the secure bus, returning the event of the LeiA
+    /// context and whether the frame has been forwarded.
+    pub fn recv_secure(&mut self, eid: u32, msg: &[u8]) -> Result<(Event, Forward), ()> {
+        let event = self.secure.auth_recv(eid, msg)?;
+
+        let message = match event {
+            Event::Authenticated(message) => message,
+            _ => return Ok((event, Forward::NotAuthenticated)),
+        };
+
+        if message.data.len() > CAN_PAYLOAD_SIZE {
+            return Ok((event, Forward::TooLong));
+        }
+
+        let now = self.secure.now();
+        let forward = match self.find_route(message.id) {
+            Some(route) => route.allow(false, now),
+            None => Forward::NotAllowed,
+        };
+
+        if forward == Forward::Forwarded {
+            (self.legacy_send)(message.id as u32, &message.data);
+        }
+
+        Ok((event, forward))
+    }
+
+    // Finds the route with the specified id
+    fn find_route(&mut self, id: u16) -> Option<&mut GatewayRoute> {
+        self.routes.iter_mut().find(|r| r.id == id && id != 0)
+    }
+}
+
+/// One of the two buses of a [DomainGateway](struct.DomainGateway.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Domain {
+    A,
+    B,
+}
+
+/// Translation of an id authenticated in one domain to an id in the other.
+#[derive(Copy, Clone, Debug)]
+pub struct DomainRoute {
+    from: Domain,
+    ingress_id: u16,
+    egress_id: u16,
+    limit: RateLimit,
+}
+
+impl DomainRoute {
+    /// Creates a new route forwarding messages authenticated on `ingress_id` in
+    /// domain `from` to `egress_id` in the other domain.
+    pub fn new(from: Domain, ingress_id: u16, egress_id: u16) -> Self {
+        Self {
+            from: from,
+            ingress_id: ingress_id,
+            egress_id: egress_id,
+            limit: Default::default(),
+        }
+    }
+
+    /// Limits the rate of the route, dropping messages that follow the previous
+    /// forwarded message within `min_interval`.
+    pub fn with_min_interval(mut self, min_interval: u64) -> Self {
+        self.limit.min_interval = min_interval;
+        self
+    }
+
+    /// Gets the domain messages are received from.
+    pub fn from(&self) -> Domain {
+        self.from
+    }
+
+    /// Gets the id messages are received on.
+    pub fn ingress_id(&self) -> u16 {
+        self.ingress_id
+    }
+
+    /// Gets the id messages are forwarded on.
+    pub fn egress_id(&self) -> u16 {
+        self.egress_id
+    }
+}
+
+/// Gateway between two buses secured with LeiA using different keys.
+///
+/// Only messages authenticated in the ingress domain are forwarded, after being
+/// authenticated again with the keys of the egress domain.
+///
+/// Rate limits use the [Clock](trait.Clock.html) of the ingress context.
+pub struct DomainGateway<S, T, F = NoFreshness, G = NoFreshness, C = fn() -> u64, D = fn() -> u64>
+where
+    S: LeiAStore,
+    T: LeiAStore,
+    F: FreshnessSource,
+    G: FreshnessSource,
+    C: Clock,
+    D: Clock,
+{
+    a: LeiAContext<S, F, C>,
+    b: LeiAContext<T, G, D>,
+    routes: [DomainRoute; 16],
+}
+
+impl<S, T, F, G, C, D> DomainGateway<S, T, F, G, C, D>
+where
+    S: LeiAStore,
+    T: LeiAStore,
+    F: FreshnessSource,
+    G: FreshnessSource,
+    C: Clock,
+    D: Clock,
+{
+    /// Creates a new gateway between the initialised contexts `a` and `b`.
+    pub fn new(a: LeiAContext<S, F, C>, b: LeiAContext<T, G, D>, routes: &[DomainRoute]) -> Self {
+        // @Cleanup @Hardcode: Same limit as LeiAContext.
+        let mut rs = [DomainRoute::new(Domain::A, 0, 0); 16];
+        rs[..routes.len()].copy_from_slice(routes);
+        Self {
+            a: a,
+            b: b,
+            routes: rs,
+        }
+    }
+
+    /// Gets the LeiA context of domain A.
+    pub fn a(&mut self) -> &mut LeiAContext<S, F, C> {
+        &mut self.a
+    }
+
+    /// Gets the LeiA context of domain B.
+    pub fn b(&mut self) -> &mut LeiAContext<T, G, D> {
+        &mut self.b
+    }
+
+    /// Receives a frame from `domain`, returning the event of its LeiA context
+    /// and whether the message has been forwarded.
+    pub fn recv(&mut self, domain: Domain, eid: u32, msg: &[u8]) -> Result<(Event, Forward), ()> {
+        let event = match domain {
+            Domain::A => self.a.auth_recv(eid, msg)?,
+            Domain::B => self.b.auth_recv(eid, msg)?,
+        };
+
+        let message = match event {
+            Event::Authenticated(message) => message,
+            _ => return Ok((event, Forward::NotAuthenticated)),
+        };
+
+        let egress_id = match self.find_route(domain, message.id) {
+            Some(route) => route.egress_id,
+            None => return Ok((event, Forward::NotAllowed)),
+        };
+
+        let mode = match domain {
+            Domain::A => self
+                .b
+                .connections()
+                .find(|c| c.id() == egress_id)
+                .map(|c| c.mode()),
+            Domain::B => self
+                .a
+                .connections()
+                .find(|c| c.id() == egress_id)
+                .map(|c| c.mode()),
+        };
+        match mode {
+            Some(mode) if message.data.len() <= mode.max_data_len() => {}
+            Some(_) => return Ok((event, Forward::TooLong)),
+            None => return Ok((event, Forward::NotAllowed)),
+        }
+
+        let now = match domain {
+            Domain::A => self.a.now(),
+            Domain::B => self.b.now(),
+        };
+        let forward = self
+            .find_route(domain, message.id)
+            .unwrap()
+            .limit
+            .allow(now);
+
+        if forward == Forward::Forwarded {
+            match domain {
+                Domain::A => self.b.auth_send(egress_id, &message.data),
+                Domain::B => self.a.auth_send(egress_id, &message.data),
+            }
+        }
+
+        Ok((event, forward))
+    }
+
+    // Finds the route for messages authenticated on `id` in `domain`
+    fn find_route(&mut self, domain: Domain, id: u16) -> Option<&mut DomainRoute> {
+        self.routes
+            .iter_mut()
+            .find(|r| r.from == domain && r.ingress_id == id && id != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use store::ArrayStore;
+
+    #[derive(Copy, Clone)]
+    struct TestClock<'a>(&'a Cell<u64>);
+
+    impl<'a> Clock for TestClock<'a> {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    type TestContext<'a> = LeiAContext<ArrayStore<u16, LeiAPending>, NoFreshness, TestClock<'a>>;
+
+    // Context with connections 0x100 and 0x200, as used by the gateway and its peers.
+    fn clocked<'a>(clock: &'a Cell<u64>) -> TestContext<'a> {
+        let connections = [
+            LeiAConnection::new(0x100).with_k_i(&[1; SANCUS_KEY_SIZE]),
+            LeiAConnection::new(0x200).with_k_i(&[3; SANCUS_KEY_SIZE]),
+        ];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new())
+            .with_queue()
+            .with_clock(TestClock(clock));
+        context.init();
+        context
+    }
+
+    // Context of a domain with a single connection on `id`, using `key`.
+    fn domain<'a>(clock: &'a Cell<u64>, id: u16, key: u8) -> TestContext<'a> {
+        let connections = [LeiAConnection::new(id).with_k_i(&[key; SANCUS_KEY_SIZE])];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[key + 1; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new())
+            .with_queue()
+            .with_clock(TestClock(clock));
+        context.init();
+        context
+    }
+
+    // Passes the frames queued by `from` to `to`, returning the last event.
+    fn deliver(from: &mut TestContext, to: &mut TestContext) -> Option<Event> {
+        let mut event = None;
+        while let Some((eid, data)) = from.pop_frame() {
+            event = to.auth_recv(eid, &data).ok();
+        }
+        event
+    }
+
+    fn context() -> LeiAContext<ArrayStore<u16, LeiAPending>> {
+        let connections = [LeiAConnection::new(0x100).with_k_i(&[1; SANCUS_KEY_SIZE])];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new()).with_queue();
+        context.init();
+        context
+    }
+
+    #[test]
+    fn secure_mac_on_id_zero_is_unknown() {
+        let routes = [GatewayRoute::new(0x100, GatewayDirection::Both)];
+        let mut gateway = LeiAGateway::new(context(), &routes);
+
+        let eid = EidLayout::default().build(0, LeiACmd::Mac, 0);
+        match gateway.recv_secure(eid, &[0; CAN_PAYLOAD_SIZE]) {
+            Ok((Event::UnknownId(_), Forward::NotAuthenticated)) => {}
+            _ => panic!("MAC frame on id 0 accepted"),
+        }
+    }
+
+    #[test]
+    fn domain_mac_on_id_zero_is_unknown() {
+        let routes = [DomainRoute::new(Domain::A, 0x100, 0x100)];
+        let mut gateway = DomainGateway::new(context(), context(), &routes);
+
+        let eid = EidLayout::default().build(0, LeiACmd::Mac, 0);
+        for &domain in [Domain::A, Domain::B].iter() {
+            match gateway.recv(domain, eid, &[0; CAN_PAYLOAD_SIZE]) {
+                Ok((Event::UnknownId(_), Forward::NotAuthenticated)) => {}
+                _ => panic!("MAC frame on id 0 accepted"),
+            }
+        }
+    }
+
+    static LEGACY_SENT: AtomicUsize = AtomicUsize::new(0);
+
+    fn legacy_send(id: u32, msg: &[u8]) {
+        assert_eq!(id, 0x100);
+        assert_eq!(msg, &[1, 2, 3]);
+        LEGACY_SENT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn authenticated_frames_are_forwarded() {
+        let clock = Cell::new(0);
+        let routes = [GatewayRoute::new(0x100, GatewayDirection::ToLegacy)];
+        let mut gateway = LeiAGateway::new(clocked(&clock), &routes).with_legacy_send(legacy_send);
+        let mut node = clocked(&clock);
+
+        node.auth_send(0x100, &[1, 2, 3]);
+        let (eid, data) = node.pop_frame().unwrap();
+        match gateway.recv_secure(eid, &data) {
+            Ok((Event::Received(_), Forward::NotAuthenticated)) => {}
+            _ => panic!("data frame forwarded before its MAC"),
+        }
+        assert_eq!(LEGACY_SENT.load(Ordering::SeqCst), 0);
+
+        let (eid, data) = node.pop_frame().unwrap();
+        match gateway.recv_secure(eid, &data) {
+            Ok((Event::Authenticated(_), Forward::Forwarded)) => {}
+            _ => panic!("authenticated frame not forwarded"),
+        }
+        assert_eq!(LEGACY_SENT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn frames_off_the_allow_list_are_dropped() {
+        let clock = Cell::new(0);
+        let routes = [GatewayRoute::new(0x100, GatewayDirection::ToSecure)];
+        let mut gateway = LeiAGateway::new(clocked(&clock), &routes);
+        let mut node = clocked(&clock);
+
+        // No route, no connection or the wrong direction.
+        assert_eq!(gateway.recv_legacy(0x200, &[1]), Forward::NotAllowed);
+        assert_eq!(gateway.recv_legacy(0x300, &[1]), Forward::NotAllowed);
+        assert_eq!(
+            gateway.recv_legacy(0x100 | CAN_EFF_FLAG, &[1]),
+            Forward::NotAllowed
+        );
+        assert!(gateway.secure().pop_frame().is_none());
+
+        for &id in [0x100, 0x200].iter() {
+            node.auth_send(id, &[1]);
+            let mut last = None;
+            while let Some((eid, data)) = node.pop_frame() {
+                last = gateway.recv_secure(eid, &data).ok();
+            }
+            match last {
+                Some((Event::Authenticated(_), Forward::NotAllowed)) => {}
+                _ => panic!("frame forwarded without a route"),
+            }
+        }
+    }
+
+    #[test]
+    fn rate_limit_uses_context_clock() {
+        let clock = Cell::new(0);
+        let routes = [GatewayRoute::new(0x100, GatewayDirection::Both).with_min_interval(10)];
+        let mut gateway = LeiAGateway::new(clocked(&clock), &routes);
+
+        assert_eq!(gateway.recv_legacy(0x100, &[1]), Forward::Forwarded);
+        clock.set(5);
+        assert_eq!(gateway.recv_legacy(0x100, &[2]), Forward::RateLimited);
+        clock.set(10);
+        assert_eq!(gateway.recv_legacy(0x100, &[3]), Forward::Forwarded);
+    }
+
+    #[test]
+    fn legacy_frames_are_authenticated() {
+        let clock = Cell::new(0);
+        let routes = [GatewayRoute::new(0x100, GatewayDirection::ToSecure)];
+        let mut gateway = LeiAGateway::new(clocked(&clock), &routes);
+        let mut node = clocked(&clock);
+
+        assert_eq!(gateway.recv_legacy(0x100, &[1, 2, 3]), Forward::Forwarded);
+        match deliver(gateway.secure(), &mut node) {
+            Some(Event::Authenticated(message)) => {
+                assert_eq!(message.id, 0x100);
+                assert_eq!(&message.data[..], &[1, 2, 3]);
+            }
+            _ => panic!("forwarded frame not authenticated"),
+        }
+
+        let long = [0; CAN_PAYLOAD_SIZE + 1];
+        assert_eq!(gateway.recv_legacy(0x100, &long), Forward::TooLong);
+    }
+
+    #[test]
+    fn domain_messages_are_reauthenticated() {
+        let clock = Cell::new(0);
+        let routes = [DomainRoute::new(Domain::A, 0x100, 0x180)];
+        let mut gateway =
+            DomainGateway::new(domain(&clock, 0x100, 1), domain(&clock, 0x180, 5), &routes);
+        let mut node_a = domain(&clock, 0x100, 1);
+        let mut node_b = domain(&clock, 0x180, 5);
+
+        node_a.auth_send(0x100, &[1, 2, 3]);
+        let (eid, data) = node_a.pop_frame().unwrap();
+        match gateway.recv(Domain::A, eid, &data) {
+            Ok((Event::Received(_), Forward::NotAuthenticated)) => {}
+            _ => panic!("data frame forwarded before its MAC"),
+        }
+        assert!(gateway.b().pop_frame().is_none());
+
+        let (eid, data) = node_a.pop_frame().unwrap();
+        match gateway.recv(Domain::A, eid, &data) {
+            Ok((Event::Authenticated(_), Forward::Forwarded)) => {}
+            _ => panic!("authenticated message not forwarded"),
+        }
+        assert!(gateway.a().pop_frame().is_none());
+
+        // Only the keys of domain B verify the forwarded message.
+        match deliver(gateway.b(), &mut node_b) {
+            Some(Event::Authenticated(message)) => {
+                assert_eq!(message.id, 0x180);
+                assert_eq!(&message.data[..], &[1, 2, 3]);
+            }
+            _ => panic!("forwarded message not authenticated in domain B"),
+        }
+    }
+
+    #[test]
+    fn domain_forgeries_are_not_forwarded() {
+        let clock = Cell::new(0);
+        let routes = [
+            DomainRoute::new(Domain::A, 0x100, 0x180),
+            DomainRoute::new(Domain::B, 0x180, 0x100),
+        ];
+        let mut gateway =
+            DomainGateway::new(domain(&clock, 0x100, 1), domain(&clock, 0x180, 5), &routes);
+        let mut node_a = domain(&clock, 0x100, 1);
+
+        node_a.auth_send(0x100, &[1, 2, 3]);
+        let (eid, data) = node_a.pop_frame().unwrap();
+        gateway.recv(Domain::A, eid, &data).unwrap();
+        let (eid, data) = node_a.pop_frame().unwrap();
+        let mut mac = [0; CAN_PAYLOAD_SIZE];
+        mac[..data.len()].copy_from_slice(&data);
+        mac[0] ^= 1;
+        match gateway.recv(Domain::A, eid, &mac[..data.len()]) {
+            Ok((Event::IncorrectMAC(_), Forward::NotAuthenticated)) => {}
+            _ => panic!("tampered message forwarded"),
+        }
+        assert!(gateway.b().pop_frame().is_none());
+        // Only AUTH_FAIL goes out, on domain A.
+        while let Some((eid, _)) = gateway.a().pop_frame() {
+            assert_eq!(
+                EidLayout::default().parse(eid).map(|(id, _, _)| id),
+                Some(0x7ff)
+            );
+        }
+
+        // Messages authenticated under the keys of A are not accepted in B.
+        node_a.auth_send(0x100, &[1, 2, 3]);
+        while let Some((eid, data)) = node_a.pop_frame() {
+            match gateway.recv(Domain::B, eid, &data) {
+                Ok((_, Forward::NotAuthenticated)) | Err(()) => {}
+                _ => panic!("message of domain A forwarded from domain B"),
+            }
+        }
+        assert!(gateway.a().pop_frame().is_none());
+        assert!(gateway.b().pop_frame().is_none());
+    }
+
+    #[test]
+    fn domain_rate_limit_uses_ingress_clock() {
+        let (clock_a, clock_b) = (Cell::new(0), Cell::new(0));
+        let routes = [DomainRoute::new(Domain::A, 0x100, 0x180).with_min_interval(10)];
+        let mut gateway = DomainGateway::new(
+            domain(&clock_a, 0x100, 1),
+            domain(&clock_b, 0x180, 5),
+            &routes,
+        );
+        let mut node_a = domain(&clock_a, 0x100, 1);
+
+        let mut forwards = [None; 3];
+        for (i, &now) in [0, 5, 10].iter().enumerate() {
+            clock_a.set(now);
+            clock_b.set(0);
+            node_a.auth_send(0x100, &[i as u8]);
+            while let Some((eid, data)) = node_a.pop_frame() {
+                forwards[i] = gateway.recv(Domain::A, eid, &data).ok().map(|(_, f)| f);
+            }
+        }
+
+        assert_eq!(
+            forwards,
+            [
+                Some(Forward::Forwarded),
+                Some(Forward::RateLimited),
+                Some(Forward::Forwarded),
+            ]
+        );
+    }
+}
@@ -0,0 +1,438 @@
+// Rule based intrusion detection on the frames and events seen by a node, e.g.
+// the logging enclave.
+
+use core::cmp;
+
+use leia::*;
+use store::ArrayStore;
+use vulcan::*;
+
+/// Length of the window in which failures are counted, in microseconds.
+const IDS_WINDOW: u64 = 1_000_000;
+
+/// Failures per window and id before an alert is raised.
+const IDS_MAX_MAC_FAILURES: u32 = 3;
+const IDS_MAX_DESYNCS: u32 = 3;
+const IDS_MAX_MISSING_MACS: u32 = 3;
+/// Frames with an unknown id per window, over all ids.
+const IDS_MAX_UNKNOWN_IDS: u32 = 20;
+/// Factor by which a threshold must be exceeded to raise a critical alert.
+const IDS_CRITICAL_FACTOR: u32 = 4;
+
+/// Intervals used to learn the period of an id before it is checked.
+const IDS_LEARN_FRAMES: u32 = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Info = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AlertKind {
+    /// Too many messages with an incorrect MAC.
+    MacFailures = 0,
+    /// Too many messages with an old counter or freshness value.
+    Desyncs = 1,
+    /// Too many frames on ids without a connection.
+    UnknownIdFlood = 2,
+    /// A frame arrived much earlier than the learned period, e.g. because it was injected.
+    EarlyFrame = 3,
+    /// A frame arrived much later than the learned period, e.g. because frames were dropped.
+    LateFrame = 4,
+    /// Too many data frames without their MAC frame.
+    MacSuppression = 5,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub severity: Severity,
+    /// Id the alert relates to, the last unknown id for floods.
+    pub id: u16,
+    /// Occurrences in the current window, or the interval in microseconds for
+    /// timing alerts.
+    pub count: u32,
+}
+
+/// Frames whose period is learned by an [Ids](struct.Ids.html): the frames of a
+/// standard id, or the LeiA frames of an id and command code.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum IdsStream {
+    Standard(u16),
+    LeiA(u16, LeiACmd),
+}
+
+impl IdsStream {
+    /// Gets the stream of `eid`, or `None` for extended identifiers that don't
+    /// match `layout`.
+    pub fn new(eid: u32, layout: &EidLayout) -> Option<Self> {
+        if eid & CAN_EFF_FLAG == 0 {
+            return Some(IdsStream::Standard((eid & 0x7FF) as u16));
+        }
+
+        // Ignore the LeiA counter, so data and MAC frames are learned separately.
+        layout
+            .parse(eid)
+            .map(|(id, cmd, _)| IdsStream::LeiA(id, cmd))
+    }
+
+    /// Gets the id of the stream.
+    pub fn id(&self) -> u16 {
+        match *self {
+            IdsStream::Standard(id) | IdsStream::LeiA(id, _) => id,
+        }
+    }
+}
+
+// Number of occurrences within the current window.
+#[derive(Copy, Clone, Debug, Default)]
+struct Window {
+    start: u64,
+    count: u32,
+}
+
+impl Window {
+    // Counts an occurrence at `now`, returning the severity once a threshold is crossed.
+    fn hit(&mut self, now: u64, max: u32) -> Option<Severity> {
+        if now.saturating_sub(self.start) > IDS_WINDOW {
+            self.start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+
+        if self.count == max {
+            Some(Severity::Warning)
+        } else if self.count == max * IDS_CRITICAL_FACTOR {
+            Some(Severity::Critical)
+        } else {
+            None
+        }
+    }
+}
+
+/// Failure windows of an id, as kept by an [Ids](struct.Ids.html).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IdsWindows {
+    mac_failures: Window,
+    desyncs: Window,
+    missing_macs: Window,
+}
+
+/// Learned period of an [IdsStream](enum.IdsStream.html).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IdsPeriod {
+    last: Option<u64>,
+    period: u64,
+    samples: u32,
+}
+
+impl IdsPeriod {
+    // Records a frame at `now`, returning the kind of timing alert and the
+    // interval to the previous frame.
+    fn frame(&mut self, now: u64) -> Option<(AlertKind, u64)> {
+        let last = match self.last {
+            Some(last) => last,
+            None => {
+                self.last = Some(now);
+                return None;
+            }
+        };
+
+        let interval = now.saturating_sub(last);
+        self.last = Some(now);
+
+        // Frames sent back to back teach a period of zero, which can't be
+        // checked, so keep learning until the period is known.
+        if self.samples < IDS_LEARN_FRAMES || self.period == 0 {
+            let samples = cmp::min(self.samples, IDS_LEARN_FRAMES - 1) as u64;
+            self.period = (self.period * samples + interval) / (samples + 1);
+            self.samples = cmp::min(self.samples + 1, IDS_LEARN_FRAMES);
+            return None;
+        }
+
+        if interval < self.period / 2 {
+            Some((AlertKind::EarlyFrame, interval))
+        } else if interval > self.period * 4 {
+            Some((AlertKind::LateFrame, interval))
+        } else {
+            // Only follow drifting periods with regular frames, so injected
+            // frames can't teach us a new period.
+            self.period = (self.period * 7 + interval) / 8;
+            None
+        }
+    }
+}
+
+/// Intrusion detection on the frames and events of a VulCAN context.
+///
+/// Ids and streams that don't fit in the stores are not checked.
+pub struct Ids<S = ArrayStore<u16, IdsWindows>, P = ArrayStore<IdsStream, IdsPeriod>>
+where
+    S: VulCANStore<K = u16, V = IdsWindows>,
+    P: VulCANStore<K = IdsStream, V = IdsPeriod>,
+{
+    ids: S,
+    periods: P,
+    unknown_ids: Window,
+}
+
+impl<S, P> Ids<S, P>
+where
+    S: VulCANStore<K = u16, V = IdsWindows>,
+    P: VulCANStore<K = IdsStream, V = IdsPeriod>,
+{
+    /// Creates a new IDS keeping the failures per id in `ids` and the learned
+    /// periods in `periods`.
+    pub fn new(ids: S, periods: P) -> Self {
+        Ids {
+            ids: ids,
+            periods: periods,
+            unknown_ids: Default::default(),
+        }
+    }
+
+    /// Checks the timing of a raw frame received at `now`, splitting LeiA
+    /// identifiers according to `layout`.
+    pub fn frame<R>(&mut self, layout: &EidLayout, eid: u32, now: u64, mut report: R)
+    where
+        R: FnMut(Alert),
+    {
+        let stream = match IdsStream::new(eid, layout) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let alert = match self.periods.entry(stream).or_insert(Default::default()) {
+            Ok(p) => p.frame(now),
+            Err(_) => return,
+        };
+
+        if let Some((kind, interval)) = alert {
+            report(Alert {
+                kind: kind,
+                severity: if kind == AlertKind::EarlyFrame {
+                    Severity::Warning
+                } else {
+                    Severity::Info
+                },
+                id: stream.id(),
+                count: interval as u32,
+            });
+        }
+    }
+
+    /// Checks an event produced by the VulCAN context at `now`.
+    pub fn event<R>(&mut self, event: &Event, now: u64, mut report: R)
+    where
+        R: FnMut(Alert),
+    {
+        let (kind, id, max) = match *event {
+            Event::IncorrectMAC(ref f) => (AlertKind::MacFailures, f.id, IDS_MAX_MAC_FAILURES),
+            Event::Desync(ref f) => (AlertKind::Desyncs, f.id, IDS_MAX_DESYNCS),
+            Event::MissingMAC(ref f) => (AlertKind::MacSuppression, f.id, IDS_MAX_MISSING_MACS),
+            Event::UnknownId(ref f) => {
+                if let Some(severity) = self.unknown_ids.hit(now, IDS_MAX_UNKNOWN_IDS) {
+                    report(Alert {
+                        kind: AlertKind::UnknownIdFlood,
+                        severity: severity,
+                        id: f.id,
+                        count: self.unknown_ids.count,
+                    });
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        let state = match self.ids.entry(id).or_insert(Default::default()) {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let window = match kind {
+            AlertKind::MacFailures => &mut state.mac_failures,
+            AlertKind::Desyncs => &mut state.desyncs,
+            _ => &mut state.missing_macs,
+        };
+
+        if let Some(severity) = window.hit(now, max) {
+            report(Alert {
+                kind: kind,
+                severity: severity,
+                id: id,
+                count: window.count,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids() -> Ids {
+        Ids::new(ArrayStore::new(), ArrayStore::new())
+    }
+
+    fn failure(id: u16) -> Failure {
+        Failure {
+            id: id,
+            counter: 0,
+            reason: FailReason::MacMismatch,
+            timestamp: 0,
+        }
+    }
+
+    // Feeds frames on `eid` every `period` from time 0 up to the end of learning.
+    fn learn(ids: &mut Ids, layout: &EidLayout, eid: u32, period: u64) -> u64 {
+        let mut now = 0;
+        for _ in 0..IDS_LEARN_FRAMES + 1 {
+            ids.frame(layout, eid, now, |a| {
+                panic!("alert while learning: {:?}", a)
+            });
+            now += period;
+        }
+        now - period
+    }
+
+    #[test]
+    fn failures_raise_warning_then_critical() {
+        let mut ids = ids();
+        let mut alerts = [None; 2];
+        let mut n = 0;
+
+        for i in 0..IDS_MAX_MAC_FAILURES * IDS_CRITICAL_FACTOR {
+            ids.event(&Event::IncorrectMAC(failure(0x100)), i as u64, |a| {
+                alerts[n] = Some(a);
+                n += 1;
+            });
+        }
+
+        assert_eq!(n, 2);
+        let warning = alerts[0].unwrap();
+        assert_eq!(warning.kind, AlertKind::MacFailures);
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(warning.id, 0x100);
+        assert_eq!(warning.count, IDS_MAX_MAC_FAILURES);
+        assert_eq!(alerts[1].unwrap().severity, Severity::Critical);
+    }
+
+    #[test]
+    fn failures_are_counted_per_window_and_id() {
+        let mut ids = ids();
+        let mut alerts = 0;
+
+        for i in 0..IDS_MAX_DESYNCS - 1 {
+            ids.event(&Event::Desync(failure(0x100)), i as u64, |_| alerts += 1);
+            ids.event(&Event::Desync(failure(0x101)), i as u64, |_| alerts += 1);
+        }
+        // A new window starts the count over.
+        ids.event(&Event::Desync(failure(0x100)), IDS_WINDOW + 2, |_| {
+            alerts += 1
+        });
+        assert_eq!(alerts, 0);
+
+        ids.event(&Event::Desync(failure(0x101)), 2, |_| alerts += 1);
+        assert_eq!(alerts, 1);
+    }
+
+    #[test]
+    fn unknown_ids_are_counted_over_all_ids() {
+        let mut ids = ids();
+        let mut alert = None;
+
+        for i in 0..IDS_MAX_UNKNOWN_IDS {
+            ids.event(&Event::UnknownId(failure(i as u16)), 0, |a| alert = Some(a));
+        }
+
+        let alert = alert.unwrap();
+        assert_eq!(alert.kind, AlertKind::UnknownIdFlood);
+        assert_eq!(alert.id, (IDS_MAX_UNKNOWN_IDS - 1) as u16);
+        assert_eq!(alert.count, IDS_MAX_UNKNOWN_IDS);
+    }
+
+    #[test]
+    fn early_and_late_frames_are_reported() {
+        let layout = EidLayout::default();
+        let eid = layout.build(0x100, LeiACmd::Data, 1);
+        let mut ids = ids();
+        let mut alert = None;
+
+        let now = learn(&mut ids, &layout, eid, 100);
+        ids.frame(&layout, eid, now + 100, |a| alert = Some(a));
+        assert!(alert.is_none());
+
+        ids.frame(&layout, eid, now + 120, |a| alert = Some(a));
+        let early = alert.take().unwrap();
+        assert_eq!(early.kind, AlertKind::EarlyFrame);
+        assert_eq!(early.severity, Severity::Warning);
+        assert_eq!(early.id, 0x100);
+        assert_eq!(early.count, 20);
+
+        ids.frame(&layout, eid, now + 1000, |a| alert = Some(a));
+        let late = alert.take().unwrap();
+        assert_eq!(late.kind, AlertKind::LateFrame);
+        assert_eq!(late.severity, Severity::Info);
+    }
+
+    #[test]
+    fn frames_at_time_zero_are_learned() {
+        let layout = EidLayout::default();
+        let mut ids = ids();
+        let mut alerts = 0;
+
+        // The first frame at time 0 must not restart learning.
+        let now = learn(&mut ids, &layout, 0x100, 100);
+        ids.frame(&layout, 0x100, now + 10, |_| alerts += 1);
+        assert_eq!(alerts, 1);
+    }
+
+    #[test]
+    fn zero_period_is_not_checked() {
+        let layout = EidLayout::default();
+        let mut ids = ids();
+        let mut alerts = 0;
+
+        for _ in 0..IDS_LEARN_FRAMES * 2 {
+            ids.frame(&layout, 0x100, 50, |_| alerts += 1);
+        }
+        // Learning goes on until frames are spread out.
+        ids.frame(&layout, 0x100, 1000, |_| alerts += 1);
+        ids.frame(&layout, 0x100, 1059, |_| alerts += 1);
+        assert_eq!(alerts, 0);
+
+        ids.frame(&layout, 0x100, 1060, |_| alerts += 1);
+        assert_eq!(alerts, 1);
+    }
+
+    #[test]
+    fn streams_are_learned_separately() {
+        let layout = EidLayout::new(4, 4, 6, 11).with_base(0x18 << 24);
+        let data = layout.build(0x100, LeiACmd::Data, 1);
+        let mac = layout.build(0x100, LeiACmd::Mac, 1);
+        let mut ids = ids();
+        let mut alert = None;
+
+        let now = learn(&mut ids, &layout, data, 100);
+        learn(&mut ids, &layout, 0x100, 100);
+        learn(&mut ids, &layout, 0x101, 100);
+
+        // Only the first MAC frame, learned apart from the data frames.
+        ids.frame(&layout, mac, now + 10, |a| alert = Some(a));
+        assert!(alert.is_none());
+
+        ids.frame(&layout, data, now + 10, |a| alert = Some(a));
+        assert_eq!(alert.take().unwrap().id, 0x100);
+
+        ids.frame(&layout, 0x101, now + 10, |a| alert = Some(a));
+        assert_eq!(alert.take().unwrap().id, 0x101);
+
+        // Extended ids of another layout are ignored.
+        let other = EidLayout::default().build(0x100, LeiACmd::Data, 1);
+        ids.frame(&layout, other, 0, |a| alert = Some(a));
+        ids.frame(&layout, other, 1, |a| alert = Some(a));
+        assert!(alert.is_none());
+        assert_eq!(ids.periods.len(), 4);
+    }
+}
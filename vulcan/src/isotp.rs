@@ -0,0 +1,633 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use freshness::*;
+use leia::*;
+use vulcan::*;
+
+/// Maximum length of an ISO-TP message (12 bit FF_DL).
+pub const ISOTP_MAX_MESSAGE_SIZE: usize = 4095;
+/// Size of the counter and MAC appended to every authenticated message.
+pub const ISOTP_TRAILER_SIZE: usize = 2 + LEIA_MAC_SIZE;
+/// Maximum length of a message that can be sent over an authenticated ISO-TP channel.
+pub const ISOTP_MAX_PAYLOAD_SIZE: usize = ISOTP_MAX_MESSAGE_SIZE - ISOTP_TRAILER_SIZE;
+
+const ISOTP_BUF_SIZE: usize = LEIA_AD_HEADER_SIZE + ISOTP_MAX_MESSAGE_SIZE;
+
+pub(crate) const ISOTP_PCI_SF: u8 = 0x00;
+pub(crate) const ISOTP_PCI_FF: u8 = 0x10;
+pub(crate) const ISOTP_PCI_CF: u8 = 0x20;
+const ISOTP_PCI_FC: u8 = 0x30;
+
+const ISOTP_FC_CTS: u8 = 0x00;
+const ISOTP_FC_WAIT: u8 = 0x01;
+const ISOTP_FC_OVFLW: u8 = 0x02;
+
+// Largest separation time in ms, also used for reserved values.
+const ISOTP_ST_MIN_MAX: u8 = 0x7F;
+
+/// Errors returned by an [IsoTpChannel](struct.IsoTpChannel.html).
+pub enum IsoTpError {
+    /// The message does not fit in a single transfer.
+    TooLong,
+    /// A transmission is already in progress.
+    Busy,
+    /// The receiver aborted the transfer with an overflow flow control frame.
+    Overflow,
+    /// No flow control frame was received within the timeout (N_Bs), the
+    /// transfer is aborted.
+    Timeout,
+    /// The frame is malformed or not expected in the current state.
+    Protocol,
+    /// The reassembled message failed LeiA authentication.
+    Auth(Event),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum TxState {
+    Idle,
+    WaitFlowControl,
+    Sending,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum RxState {
+    Idle,
+    Receiving,
+}
+
+/// ISO 15765-2 channel carrying messages longer than one CAN frame, authenticated
+/// with the session key of a LeiA connection.
+///
+/// Every message is extended with the LeiA counter of the transfer and a single
+/// MAC over the whole message. Segmentation and flow control follow ISO-TP on
+/// the standard 11 bit ids `tx_id` and `rx_id`, which are also the ids of the
+/// LeiA connections used to authenticate outgoing and incoming messages.
+///
+/// Frames received on `rx_id` must be passed to [recv](#method.recv) instead of
+/// `auth_recv`. Consecutive frames delayed by the separation time of the receiver
+/// and the flow control timeout are handled by [poll](#method.poll), which
+/// assumes the clock of the context counts milliseconds.
+pub struct IsoTpChannel {
+    tx_id: u16,
+    rx_id: u16,
+    timeout: Option<u64>,
+
+    tx_buf: [u8; ISOTP_BUF_SIZE],
+    tx_len: usize,
+    tx_pos: usize,
+    tx_seq: u8,
+    tx_state: TxState,
+    // Consecutive frames left in the current block, zero if unlimited.
+    tx_block_left: u8,
+    tx_st_min: u64,
+    // Time the consecutive frame was sent, `None` at the start of a block.
+    tx_last: Option<u64>,
+    // Time the wait for a flow control frame started.
+    tx_time: u64,
+
+    rx_buf: [u8; ISOTP_BUF_SIZE],
+    rx_len: usize,
+    rx_pos: usize,
+    rx_seq: u8,
+    rx_state: RxState,
+}
+
+impl IsoTpChannel {
+    /// Creates a new channel sending on `tx_id` and receiving on `rx_id`.
+    pub fn new(tx_id: u16, rx_id: u16) -> Self {
+        Self {
+            tx_id: tx_id,
+            rx_id: rx_id,
+            timeout: None,
+
+            tx_buf: [0; ISOTP_BUF_SIZE],
+            tx_len: 0,
+            tx_pos: 0,
+            tx_seq: 0,
+            tx_state: TxState::Idle,
+            tx_block_left: 0,
+            tx_st_min: 0,
+            tx_last: None,
+            tx_time: 0,
+
+            rx_buf: [0; ISOTP_BUF_SIZE],
+            rx_len: 0,
+            rx_pos: 0,
+            rx_seq: 0,
+            rx_state: RxState::Idle,
+        }
+    }
+
+    /// Aborts a transfer that waits longer than `timeout` for a flow control
+    /// frame (N_Bs) once [poll](#method.poll) is called.
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Gets the id messages are sent on.
+    pub fn tx_id(&self) -> u16 {
+        self.tx_id
+    }
+
+    /// Gets the id messages are received on.
+    pub fn rx_id(&self) -> u16 {
+        self.rx_id
+    }
+
+    /// Checks whether a message is still being sent.
+    pub fn is_sending(&self) -> bool {
+        self.tx_state != TxState::Idle
+    }
+
+    /// Aborts the transfers in progress in both directions.
+    pub fn abort(&mut self) {
+        self.tx_state = TxState::Idle;
+        self.rx_state = RxState::Idle;
+    }
+
+    /// Sends the consecutive frames that were held back by the separation time,
+    /// and aborts a transfer whose flow control frame didn't arrive in time.
+    pub fn poll<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        match self.tx_state {
+            TxState::Sending => self.send_consecutive(context),
+            TxState::WaitFlowControl => {
+                let expired = self.timeout.map_or(false, |timeout| {
+                    context.now().saturating_sub(self.tx_time) > timeout
+                });
+
+                if expired {
+                    self.tx_state = TxState::Idle;
+                    return Err(IsoTpError::Timeout);
+                }
+            }
+            TxState::Idle => {}
+        }
+
+        Ok(())
+    }
+
+    /// Starts an authenticated transfer of `msg`.
+    ///
+    /// Only the first frame is sent; consecutive frames follow once the
+    /// receiver's flow control frame is passed to [recv](#method.recv).
+    pub fn send<S, F, T>(
+        &mut self,
+        context: &mut LeiAContext<S, F, T>,
+        msg: &[u8],
+    ) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if msg.len() > ISOTP_MAX_PAYLOAD_SIZE {
+            return Err(IsoTpError::TooLong);
+        }
+        if self.tx_state != TxState::Idle {
+            return Err(IsoTpError::Busy);
+        }
+
+        let msg_end = LEIA_AD_HEADER_SIZE + msg.len();
+        self.tx_buf[LEIA_AD_HEADER_SIZE..msg_end].copy_from_slice(msg);
+
+        let (counter, mac) = context
+            .leia_auth_segmented(self.tx_id, &mut self.tx_buf[..msg_end])
+            .map_err(IsoTpError::Auth)?;
+
+        LittleEndian::write_u16(&mut self.tx_buf[msg_end..msg_end + 2], counter);
+        self.tx_buf[msg_end + 2..msg_end + ISOTP_TRAILER_SIZE].copy_from_slice(&mac);
+
+        // The trailer alone doesn't fit in a single frame, so every transfer
+        // starts with a first frame.
+        self.tx_len = msg.len() + ISOTP_TRAILER_SIZE;
+
+        let mut frame = [0; CAN_PAYLOAD_SIZE];
+        frame[0] = ISOTP_PCI_FF | (self.tx_len >> 8) as u8;
+        frame[1] = self.tx_len as u8;
+        frame[2..].copy_from_slice(&self.tx_buf[LEIA_AD_HEADER_SIZE..LEIA_AD_HEADER_SIZE + 6]);
+        context.send(self.tx_id, &frame);
+
+        self.tx_pos = 6;
+        self.tx_seq = 1;
+        self.tx_state = TxState::WaitFlowControl;
+        self.tx_time = context.now();
+
+        Ok(())
+    }
+
+    /// Receives a frame sent on `rx_id`.
+    ///
+    /// Returns the message once it has been completely reassembled and
+    /// authenticated.
+    pub fn recv<S, F, T>(
+        &mut self,
+        context: &mut LeiAContext<S, F, T>,
+        data: &[u8],
+    ) -> Result<Option<&[u8]>, IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if data.is_empty() {
+            return Err(IsoTpError::Protocol);
+        }
+
+        match data[0] & 0xF0 {
+            ISOTP_PCI_SF => {
+                let len = (data[0] & 0x0F) as usize;
+                if len == 0 || len + 1 > data.len() {
+                    return Err(IsoTpError::Protocol);
+                }
+
+                self.rx_buf[LEIA_AD_HEADER_SIZE..LEIA_AD_HEADER_SIZE + len]
+                    .copy_from_slice(&data[1..1 + len]);
+                self.rx_len = len;
+                self.rx_pos = len;
+
+                self.verify(context).map(Some)
+            }
+            ISOTP_PCI_FF => {
+                if data.len() < CAN_PAYLOAD_SIZE {
+                    return Err(IsoTpError::Protocol);
+                }
+
+                let len = ((data[0] & 0x0F) as usize) << 8 | data[1] as usize;
+                if len < CAN_PAYLOAD_SIZE {
+                    return Err(IsoTpError::Protocol);
+                }
+
+                self.rx_buf[LEIA_AD_HEADER_SIZE..LEIA_AD_HEADER_SIZE + 6]
+                    .copy_from_slice(&data[2..CAN_PAYLOAD_SIZE]);
+                self.rx_len = len;
+                self.rx_pos = 6;
+                self.rx_seq = 1;
+                self.rx_state = RxState::Receiving;
+
+                // Accept the rest of the transfer without block size or separation time.
+                context.send(self.tx_id, &[ISOTP_PCI_FC | ISOTP_FC_CTS, 0x00, 0x00]);
+
+                Ok(None)
+            }
+            ISOTP_PCI_CF => {
+                if self.rx_state != RxState::Receiving {
+                    return Err(IsoTpError::Protocol);
+                }
+                if data[0] & 0x0F != self.rx_seq {
+                    self.rx_state = RxState::Idle;
+                    return Err(IsoTpError::Protocol);
+                }
+
+                let n = (self.rx_len - self.rx_pos).min(CAN_PAYLOAD_SIZE - 1);
+                if data.len() < n + 1 {
+                    self.rx_state = RxState::Idle;
+                    return Err(IsoTpError::Protocol);
+                }
+
+                let pos = LEIA_AD_HEADER_SIZE + self.rx_pos;
+                self.rx_buf[pos..pos + n].copy_from_slice(&data[1..1 + n]);
+                self.rx_pos += n;
+                self.rx_seq = (self.rx_seq + 1) & 0x0F;
+
+                if self.rx_pos < self.rx_len {
+                    return Ok(None);
+                }
+
+                self.rx_state = RxState::Idle;
+                self.verify(context).map(Some)
+            }
+            ISOTP_PCI_FC => {
+                if self.tx_state != TxState::WaitFlowControl || data.len() < 3 {
+                    return Err(IsoTpError::Protocol);
+                }
+
+                match data[0] & 0x0F {
+                    ISOTP_FC_CTS => {
+                        self.tx_block_left = data[1];
+                        self.tx_st_min = st_min(data[2]);
+                        self.tx_state = TxState::Sending;
+                        self.tx_last = None;
+                        self.send_consecutive(context);
+                        Ok(None)
+                    }
+                    ISOTP_FC_WAIT => {
+                        self.tx_time = context.now();
+                        Ok(None)
+                    }
+                    ISOTP_FC_OVFLW => {
+                        self.tx_state = TxState::Idle;
+                        Err(IsoTpError::Overflow)
+                    }
+                    _ => {
+                        self.tx_state = TxState::Idle;
+                        Err(IsoTpError::Protocol)
+                    }
+                }
+            }
+            _ => Err(IsoTpError::Protocol),
+        }
+    }
+
+    // Sends the consecutive frames due, up to the end of the current block. Without
+    // a separation time the whole block is sent at once.
+    fn send_consecutive<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>)
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        while self.tx_pos < self.tx_len {
+            let now = context.now();
+            let waiting = self
+                .tx_last
+                .map_or(false, |last| now.saturating_sub(last) < self.tx_st_min);
+            if waiting {
+                return;
+            }
+
+            let n = (self.tx_len - self.tx_pos).min(CAN_PAYLOAD_SIZE - 1);
+            let pos = LEIA_AD_HEADER_SIZE + self.tx_pos;
+
+            let mut frame = [0; CAN_PAYLOAD_SIZE];
+            frame[0] = ISOTP_PCI_CF | self.tx_seq;
+            frame[1..1 + n].copy_from_slice(&self.tx_buf[pos..pos + n]);
+            context.send(self.tx_id, &frame[..1 + n]);
+
+            self.tx_pos += n;
+            self.tx_seq = (self.tx_seq + 1) & 0x0F;
+            self.tx_last = Some(now);
+
+            if self.tx_block_left > 0 {
+                self.tx_block_left -= 1;
+                if self.tx_block_left == 0 && self.tx_pos < self.tx_len {
+                    self.tx_state = TxState::WaitFlowControl;
+                    self.tx_time = now;
+                    return;
+                }
+            }
+        }
+
+        self.tx_state = TxState::Idle;
+    }
+
+    // Verifies the counter and MAC at the end of the reassembled message.
+    fn verify<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>) -> Result<&[u8], IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        // Messages too short for counter and MAC, e.g. plain transfers, can't
+        // be authenticated.
+        if self.rx_len < ISOTP_TRAILER_SIZE {
+            return Err(IsoTpError::Auth(Event::MissingMAC(Failure {
+                id: self.rx_id,
+                counter: 0,
+                reason: FailReason::Truncated,
+                timestamp: context.now(),
+            })));
+        }
+
+        let msg_end = LEIA_AD_HEADER_SIZE + self.rx_len - ISOTP_TRAILER_SIZE;
+        let counter = LittleEndian::read_u16(&self.rx_buf[msg_end..msg_end + 2]);
+
+        let mut mac = [0; LEIA_MAC_SIZE];
+        mac.copy_from_slice(&self.rx_buf[msg_end + 2..msg_end + ISOTP_TRAILER_SIZE]);
+
+        context
+            .leia_verify_segmented(self.rx_id, counter, &mut self.rx_buf[..msg_end], &mac)
+            .map_err(IsoTpError::Auth)?;
+
+        Ok(&self.rx_buf[LEIA_AD_HEADER_SIZE..msg_end])
+    }
+}
+
+// Converts an STmin parameter to milliseconds. Sub-millisecond values are rounded
+// up, reserved values are treated as the largest separation time.
+fn st_min(value: u8) -> u64 {
+    if value <= ISOTP_ST_MIN_MAX {
+        value as u64
+    } else if value >= 0xF1 && value <= 0xF9 {
+        1
+    } else {
+        ISOTP_ST_MIN_MAX as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    use core::cell::Cell;
+
+    #[derive(Copy, Clone)]
+    struct TestClock<'a>(&'a Cell<u64>);
+
+    impl<'a> Clock for TestClock<'a> {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    type TestContext<'a> = LeiAContext<ArrayStore<u16, LeiAPending>, NoFreshness, TestClock<'a>>;
+
+    fn context<'a>(clock: &'a Cell<u64>) -> TestContext<'a> {
+        let connections = [
+            LeiAConnection::new(0x7e0).with_k_i(&[1; SANCUS_KEY_SIZE]),
+            LeiAConnection::new(0x7e8).with_k_i(&[2; SANCUS_KEY_SIZE]),
+        ];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[3; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new())
+            .with_queue()
+            .with_clock(TestClock(clock));
+        context.init();
+        context
+    }
+
+    // Pops the next frame sent by `context`, padded to a full frame.
+    fn pop(context: &mut TestContext) -> Option<(usize, CANPayload)> {
+        context.pop_frame().map(|(_, data)| {
+            let mut frame = [0; CAN_PAYLOAD_SIZE];
+            frame[..data.len()].copy_from_slice(&data);
+            (data.len(), frame)
+        })
+    }
+
+    // Delivers the first frame of a transfer and its flow control frame.
+    fn start(
+        tx: &mut IsoTpChannel,
+        tx_context: &mut TestContext,
+        rx: &mut IsoTpChannel,
+        rx_context: &mut TestContext,
+        msg: &[u8],
+    ) {
+        tx.send(tx_context, msg).ok().unwrap();
+        let (len, ff) = pop(tx_context).unwrap();
+        assert!(rx.recv(rx_context, &ff[..len]).ok().unwrap().is_none());
+        let (len, fc) = pop(rx_context).unwrap();
+        assert!(tx.recv(tx_context, &fc[..len]).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn segmented_message_is_reassembled() {
+        let clock = Cell::new(0);
+        let mut tx_context = context(&clock);
+        let mut rx_context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8);
+        let mut rx = IsoTpChannel::new(0x7e8, 0x7e0);
+
+        let mut msg = [0; 100];
+        for (i, b) in msg.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        start(&mut tx, &mut tx_context, &mut rx, &mut rx_context, &msg);
+        assert!(!tx.is_sending());
+
+        let mut received = false;
+        while let Some((len, cf)) = pop(&mut tx_context) {
+            assert!(!received);
+            if let Some(data) = rx.recv(&mut rx_context, &cf[..len]).ok().unwrap() {
+                assert_eq!(data, &msg[..]);
+                received = true;
+            }
+        }
+        assert!(received);
+    }
+
+    #[test]
+    fn flow_control_wait_and_overflow() {
+        let clock = Cell::new(0);
+        let mut context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8);
+
+        tx.send(&mut context, &[0; 20]).ok().unwrap();
+        pop(&mut context).unwrap();
+
+        assert!(tx
+            .recv(&mut context, &[ISOTP_PCI_FC | ISOTP_FC_WAIT, 0, 0])
+            .is_ok());
+        assert!(tx.is_sending());
+        assert!(pop(&mut context).is_none());
+
+        match tx.recv(&mut context, &[ISOTP_PCI_FC | ISOTP_FC_OVFLW, 0, 0]) {
+            Err(IsoTpError::Overflow) => {}
+            _ => panic!("Overflow not reported"),
+        }
+        assert!(!tx.is_sending());
+        assert!(tx.send(&mut context, &[0; 20]).is_ok());
+    }
+
+    #[test]
+    fn block_size_and_separation_time() {
+        let clock = Cell::new(0);
+        let mut context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8);
+
+        // 6 bytes in the first frame, 5 consecutive frames for the rest.
+        tx.send(&mut context, &[0; 17]).ok().unwrap();
+        pop(&mut context).unwrap();
+
+        let fc = [ISOTP_PCI_FC | ISOTP_FC_CTS, 2, 10];
+        assert!(tx.recv(&mut context, &fc).is_ok());
+        assert!(pop(&mut context).is_some());
+        tx.poll(&mut context).ok().unwrap();
+        assert!(pop(&mut context).is_none());
+
+        clock.set(10);
+        tx.poll(&mut context).ok().unwrap();
+        assert!(pop(&mut context).is_some());
+
+        // The block is complete, so nothing is sent before the next flow control.
+        clock.set(100);
+        tx.poll(&mut context).ok().unwrap();
+        assert!(pop(&mut context).is_none());
+
+        assert!(tx
+            .recv(&mut context, &[ISOTP_PCI_FC | ISOTP_FC_CTS, 0, 0])
+            .is_ok());
+        let mut sent = 0;
+        while pop(&mut context).is_some() {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+        assert!(!tx.is_sending());
+    }
+
+    #[test]
+    fn missing_flow_control_times_out() {
+        let clock = Cell::new(0);
+        let mut context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8).with_timeout(100);
+
+        tx.send(&mut context, &[0; 20]).ok().unwrap();
+        clock.set(100);
+        assert!(tx.poll(&mut context).is_ok());
+        match tx.send(&mut context, &[0; 20]) {
+            Err(IsoTpError::Busy) => {}
+            _ => panic!("Second transfer started"),
+        }
+
+        clock.set(101);
+        match tx.poll(&mut context) {
+            Err(IsoTpError::Timeout) => {}
+            _ => panic!("Timeout not reported"),
+        }
+        assert!(tx.send(&mut context, &[0; 20]).is_ok());
+
+        tx.abort();
+        assert!(!tx.is_sending());
+        assert!(tx.send(&mut context, &[0; 20]).is_ok());
+    }
+
+    #[test]
+    fn out_of_order_frame_is_rejected() {
+        let clock = Cell::new(0);
+        let mut tx_context = context(&clock);
+        let mut rx_context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8);
+        let mut rx = IsoTpChannel::new(0x7e8, 0x7e0);
+
+        start(&mut tx, &mut tx_context, &mut rx, &mut rx_context, &[0; 20]);
+        pop(&mut tx_context).unwrap();
+
+        while let Some((len, cf)) = pop(&mut tx_context) {
+            match rx.recv(&mut rx_context, &cf[..len]) {
+                Err(IsoTpError::Protocol) => {}
+                _ => panic!("Out of order frame accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let clock = Cell::new(0);
+        let mut tx_context = context(&clock);
+        let mut rx_context = context(&clock);
+        let mut tx = IsoTpChannel::new(0x7e0, 0x7e8);
+        let mut rx = IsoTpChannel::new(0x7e8, 0x7e0);
+
+        start(&mut tx, &mut tx_context, &mut rx, &mut rx_context, &[0; 20]);
+
+        let (len, mut cf) = pop(&mut tx_context).unwrap();
+        cf[1] ^= 1;
+        assert!(rx.recv(&mut rx_context, &cf[..len]).ok().unwrap().is_none());
+
+        let mut rejected = false;
+        while let Some((len, cf)) = pop(&mut tx_context) {
+            match rx.recv(&mut rx_context, &cf[..len]) {
+                Ok(None) => {}
+                Err(IsoTpError::Auth(Event::IncorrectMAC(_))) => rejected = true,
+                _ => panic!("Tampered message accepted"),
+            }
+        }
+        assert!(rejected);
+    }
+}
@@ -0,0 +1,956 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use freshness::*;
+use vulcan::*;
+
+use core::marker::PhantomData;
+
+/// Proprietary A PGN MAC frames are sent on by default.
+pub const J1939_MAC_PGN: u32 = 0xEF00;
+/// PGN of transport protocol connection management frames.
+pub const J1939_TP_CM_PGN: u32 = 0xEC00;
+/// PGN of transport protocol data transfer frames.
+pub const J1939_TP_DT_PGN: u32 = 0xEB00;
+/// Minimum length of a message sent with TP.BAM, shorter ones fit in a frame.
+pub const J1939_TP_MIN_SIZE: usize = 9;
+/// Maximum length of a message sent with TP.BAM.
+pub const J1939_TP_MAX_SIZE: usize = 1785;
+/// Global destination address.
+pub const J1939_GLOBAL: u8 = 0xFF;
+
+const J1939_PGN_MASK: u32 = 0x3FFFF;
+/// Largest PGN of data page 0, the PGNs that fit in the `u16` ids of the context.
+const J1939_PGN_MAX: u32 = 0xFFFF;
+const J1939_PDU2_PF: u32 = 240;
+const J1939_DEFAULT_PRIORITY: u8 = 6;
+const J1939_TP_PRIORITY: u8 = 7;
+
+const J1939_TP_CM_BAM: u8 = 0x20;
+const J1939_TP_DT_SIZE: usize = 7;
+
+const J1939_PGN_SIZE: usize = 3;
+const J1939_FRESHNESS_SIZE: usize = 1;
+const J1939_MAC_SIZE: usize = CAN_PAYLOAD_SIZE - J1939_PGN_SIZE - J1939_FRESHNESS_SIZE;
+const J1939_AD_HEADER_SIZE: usize = J1939_PGN_SIZE + 1 + 8;
+const J1939_BUF_SIZE: usize = J1939_AD_HEADER_SIZE + J1939_TP_MAX_SIZE;
+
+/// Number of freshness values tried before verification of a message fails.
+const J1939_VERIFICATION_ATTEMPTS: u8 = 2;
+
+/// Fields of a J1939 29 bit identifier.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct J1939Id {
+    pub priority: u8,
+    /// Parameter group number, without the destination address of PDU1 groups.
+    pub pgn: u32,
+    /// Destination address, `J1939_GLOBAL` for PDU2 groups.
+    pub da: u8,
+    /// Source address.
+    pub sa: u8,
+}
+
+impl J1939Id {
+    /// Creates an identifier of a broadcast message on `pgn` sent by `sa`.
+    pub fn new(priority: u8, pgn: u32, sa: u8) -> Self {
+        let pgn = pgn & J1939_PGN_MASK;
+
+        Self {
+            priority: priority,
+            pgn: if is_pdu1(pgn) { pgn & !0xFF } else { pgn },
+            da: J1939_GLOBAL,
+            sa: sa,
+        }
+    }
+
+    /// Splits an extended identifier into its J1939 fields. Returns `None` for
+    /// standard identifiers, which don't have `CAN_EFF_FLAG` set.
+    pub fn parse(eid: u32) -> Option<Self> {
+        if eid & CAN_EFF_FLAG == 0 {
+            return None;
+        }
+        let eid = eid & CAN_EFF_MASK;
+
+        let pgn = (eid >> 8) & J1939_PGN_MASK;
+        let (pgn, da) = if is_pdu1(pgn) {
+            (pgn & !0xFF, pgn as u8)
+        } else {
+            (pgn, J1939_GLOBAL)
+        };
+
+        Some(Self {
+            priority: (eid >> 26) as u8 & 0x07,
+            pgn: pgn,
+            da: da,
+            sa: eid as u8,
+        })
+    }
+
+    /// Builds the extended identifier, with the destination address in the PDU
+    /// specific field of PDU1 groups.
+    pub fn eid(&self) -> u32 {
+        let pgn = if is_pdu1(self.pgn) {
+            self.pgn & !0xFF | self.da as u32
+        } else {
+            self.pgn
+        };
+
+        ((self.priority as u32 & 0x07) << 26)
+            | (pgn & J1939_PGN_MASK) << 8
+            | self.sa as u32
+            | CAN_EFF_FLAG
+    }
+}
+
+// Checks whether the PDU specific field of `pgn` holds a destination address.
+fn is_pdu1(pgn: u32) -> bool {
+    (pgn >> 8) & 0xFF < J1939_PDU2_PF
+}
+
+/// Configuration of the authenticated messages of a source address.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct J1939Connection {
+    sa: u8,
+    key: SancusKey,
+}
+
+impl J1939Connection {
+    /// Creates a new connection for messages sent by `sa`.
+    pub fn new(sa: u8) -> Self {
+        Self {
+            sa: sa,
+            key: Default::default(),
+        }
+    }
+
+    /// Sets the key used to authenticate the messages of this source address.
+    pub fn with_key(mut self, key: &[u8]) -> Self {
+        self.key.copy_from_slice(key);
+        self
+    }
+
+    /// Gets the source address of the connection.
+    pub fn sa(&self) -> u8 {
+        self.sa
+    }
+}
+
+/// Message awaiting its MAC frame, as kept in a [J1939Store](trait.J1939Store.html).
+#[derive(Copy, Clone, Debug)]
+pub struct J1939Pending {
+    data: CANPayload,
+    len: usize,
+    timestamp: u64,
+}
+
+// Ergonomics. Use J1939Store as alias for specific VulCANStore.
+pub trait J1939Store: VulCANStore<K = u32, V = J1939Pending> {}
+impl<T> J1939Store for T where T: VulCANStore<K = u32, V = J1939Pending> {}
+
+/// Structure authenticating SAE J1939 messages on a single node.
+///
+/// Messages are sent unchanged, followed by a MAC frame on a proprietary PGN
+/// carrying the PGN of the message, the least significant byte of the freshness
+/// value of the sending source address and a 4 byte truncated MAC. The MAC is
+/// computed with the key of the source address over the PGN, the source address,
+/// the complete freshness value and the message.
+///
+/// Events carry the PGN of the message as id. Messages longer than a frame are
+/// sent with TP.BAM by a [J1939Bam](struct.J1939Bam.html).
+///
+/// Received frames are timestamped by a [Clock](trait.Clock.html), by default a
+/// plain function set with [with_time](#method.with_time).
+pub struct J1939Context<S, F, M = SpongentMac, T = fn() -> u64>
+where
+    S: J1939Store,
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    sa: u8,
+    priority: u8,
+    mac_pgn: u32,
+    connections: [J1939Connection; 16],
+    expected: S,
+    fvm: F,
+    send: fn(u32, &[u8]),
+    clock: T,
+    mac: PhantomData<M>,
+}
+
+impl<S, F, M> J1939Context<S, F, M>
+where
+    S: J1939Store,
+    F: FreshnessSource,
+    M: VulCANMac,
+{
+    /// Creates a new J1939 context.
+    ///
+    /// # Parameters
+    ///
+    /// - `sa` - The source address of the node, which needs a connection to send.
+    /// - `connections` - A list of [J1939Connection](struct.J1939Connection.html)
+    ///   to be managed by the context.
+    /// - `expected` - A structure implementing [VulCANStore](trait.VulCANStore.html).
+    /// - `fvm` - A structure implementing [FreshnessSource](trait.FreshnessSource.html),
+    ///   keeping a freshness value per source address.
+    pub fn new(sa: u8, connections: &[J1939Connection], expected: S, fvm: F) -> Self {
+        // @Cleanup @Hardcode: Same limit as LeiAContext.
+        let mut cs = [J1939Connection::new(J1939_GLOBAL); 16];
+        cs[..connections.len()].copy_from_slice(connections);
+        Self {
+            sa: sa,
+            priority: J1939_DEFAULT_PRIORITY,
+            mac_pgn: J1939_MAC_PGN,
+            connections: cs,
+            expected: expected,
+            fvm: fvm,
+            send: |_, _| {},
+            clock: || 0,
+            mac: PhantomData,
+        }
+    }
+}
+
+impl<S, F, M, T> J1939Context<S, F, M, T>
+where
+    S: J1939Store,
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    /// Sets the function to be used by the context to send messages.
+    pub fn with_send(mut self, send: fn(u32, &[u8])) -> Self {
+        self.send = send;
+        self
+    }
+
+    /// Sets the function to be used by the context to timestamp received frames.
+    pub fn with_time(self, time: fn() -> u64) -> J1939Context<S, F, M, fn() -> u64> {
+        self.with_clock(time)
+    }
+
+    /// Sets the clock to be used by the context to timestamp received frames.
+    pub fn with_clock<C>(self, clock: C) -> J1939Context<S, F, M, C>
+    where
+        C: Clock,
+    {
+        J1939Context {
+            sa: self.sa,
+            priority: self.priority,
+            mac_pgn: self.mac_pgn,
+            connections: self.connections,
+            expected: self.expected,
+            fvm: self.fvm,
+            send: self.send,
+            clock: clock,
+            mac: PhantomData,
+        }
+    }
+
+    /// Sets the priority of sent messages and MAC frames. Defaults to 6.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        assert!(priority <= 7);
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the PGN MAC frames are sent on. Defaults to Proprietary A.
+    pub fn with_mac_pgn(mut self, pgn: u32) -> Self {
+        self.mac_pgn = J1939Id::new(0, pgn, 0).pgn;
+        self
+    }
+
+    /// Gets the source address of the node.
+    pub fn sa(&self) -> u8 {
+        self.sa
+    }
+
+    /// Gets the freshness source of the context.
+    pub fn fvm(&mut self) -> &mut F {
+        &mut self.fvm
+    }
+
+    // Builds the MAC frame for the message in `ad` on `pgn`, sent by this node,
+    // unless it has no connection or its freshness value can't be recorded. `ad`
+    // starts with room for the header.
+    fn mac_frame(&mut self, pgn: u32, ad: &mut [u8]) -> Result<CANPayload, J1939Error> {
+        let sa = self.sa;
+        let key = match self.find_connection(sa) {
+            Some(connection) => connection.key,
+            None => return Err(J1939Error::NotConfigured),
+        };
+
+        let freshness = self.fvm.tx_freshness(sa as u16);
+        let mac = j1939_mac::<M>(&key, pgn, sa, freshness, ad);
+
+        let mut frame = [0; CAN_PAYLOAD_SIZE];
+        LittleEndian::write_uint(&mut frame[..J1939_PGN_SIZE], pgn as u64, J1939_PGN_SIZE);
+        frame[J1939_PGN_SIZE] = freshness as u8;
+        frame[J1939_PGN_SIZE + J1939_FRESHNESS_SIZE..].copy_from_slice(&mac[..J1939_MAC_SIZE]);
+
+        self.fvm
+            .tx_confirmation(sa as u16, freshness)
+            .map_err(|_| J1939Error::StoreFull)?;
+
+        Ok(frame)
+    }
+
+    // Verifies the MAC frame of the message in `ad` on `pgn`, sent by `sa`.
+    // Returns the complete freshness value.
+    fn verify_mac(&mut self, sa: u8, pgn: u32, ad: &mut [u8], frame: &[u8]) -> Result<u64, Event> {
+        let timestamp = self.clock.now();
+        let failure = |counter, reason| Failure {
+            id: pgn as u16,
+            counter: counter,
+            reason: reason,
+            timestamp: timestamp,
+        };
+
+        let key = match self.find_connection(sa) {
+            Some(connection) => connection.key,
+            None => return Err(Event::UnknownId(failure(0, FailReason::NotConfigured))),
+        };
+
+        if frame.len() != CAN_PAYLOAD_SIZE {
+            return Err(Event::IncorrectMAC(failure(0, FailReason::Truncated)));
+        }
+
+        let truncated = frame[J1939_PGN_SIZE] as u64;
+        let bits = (J1939_FRESHNESS_SIZE * 8) as u32;
+
+        for attempt in 0..J1939_VERIFICATION_ATTEMPTS {
+            let freshness = match self.fvm.rx_freshness(sa as u16, truncated, bits, attempt) {
+                Some(freshness) => freshness,
+                None => {
+                    return Err(Event::Desync(failure(
+                        truncated,
+                        FailReason::FreshnessRejected,
+                    )))
+                }
+            };
+
+            let mac = j1939_mac::<M>(&key, pgn, sa, freshness, ad);
+            if mac[..J1939_MAC_SIZE] == frame[J1939_PGN_SIZE + J1939_FRESHNESS_SIZE..] {
+                if self.fvm.rx_confirmation(sa as u16, freshness).is_err() {
+                    return Err(Event::Desync(failure(freshness, FailReason::StoreFull)));
+                }
+                return Ok(freshness);
+            }
+        }
+
+        Err(Event::IncorrectMAC(failure(
+            truncated,
+            FailReason::MacMismatch,
+        )))
+    }
+
+    // Finds the connection of the specified source address
+    fn find_connection(&self, sa: u8) -> Option<&J1939Connection> {
+        self.connections
+            .iter()
+            .find(|c| c.sa == sa && sa != J1939_GLOBAL)
+    }
+}
+
+// Key of a pending message in the store.
+fn pending_key(sa: u8, pgn: u32) -> u32 {
+    (sa as u32) << 18 | pgn
+}
+
+// Writes the header to `ad` and computes the MAC over it and the message after it.
+fn j1939_mac<M: VulCANMac>(
+    key: &SancusKey,
+    pgn: u32,
+    sa: u8,
+    freshness: u64,
+    ad: &mut [u8],
+) -> [u8; SANCUS_KEY_SIZE] {
+    LittleEndian::write_uint(&mut ad[..J1939_PGN_SIZE], pgn as u64, J1939_PGN_SIZE);
+    ad[J1939_PGN_SIZE] = sa;
+    LittleEndian::write_u64(&mut ad[J1939_PGN_SIZE + 1..J1939_AD_HEADER_SIZE], freshness);
+
+    M::mac(key, ad)
+}
+
+/// Implements J1939 message authentication as a VulCAN context. Ids are PGNs
+/// of data page 0, frames of other PGNs are not handled.
+impl<S, F, M, T> VulCANContext for J1939Context<S, F, M, T>
+where
+    S: J1939Store,
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    type ProtocolInfo = J1939Connection;
+
+    fn init(&mut self) {}
+
+    /// Sends nothing if the context has no connection for its source address.
+    fn auth_send(&mut self, id: u16, msg: &[u8]) {
+        assert!(
+            msg.len() <= CAN_PAYLOAD_SIZE,
+            "Messages longer than one frame need TP.BAM."
+        );
+
+        let pgn = id as u32;
+
+        let mut ad = [0; J1939_AD_HEADER_SIZE + CAN_PAYLOAD_SIZE];
+        ad[J1939_AD_HEADER_SIZE..J1939_AD_HEADER_SIZE + msg.len()].copy_from_slice(msg);
+        let frame = match self.mac_frame(pgn, &mut ad[..J1939_AD_HEADER_SIZE + msg.len()]) {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+
+        let mac_id = J1939Id::new(self.priority, self.mac_pgn, self.sa);
+        (self.send)(J1939Id::new(self.priority, pgn, self.sa).eid(), msg);
+        (self.send)(mac_id.eid(), &frame);
+    }
+
+    fn send(&mut self, id: u16, msg: &[u8]) {
+        (self.send)(J1939Id::new(self.priority, id as u32, self.sa).eid(), msg);
+    }
+
+    fn auth_recv(&mut self, eid: u32, msg: &[u8]) -> Result<Event, ()> {
+        let id = match J1939Id::parse(eid) {
+            Some(id) if id.pgn <= J1939_PGN_MAX => id,
+            _ => return Err(()),
+        };
+        let timestamp = self.clock.now();
+
+        if id.pgn != self.mac_pgn {
+            let failure = Failure {
+                id: id.pgn as u16,
+                counter: 0,
+                reason: FailReason::NotConfigured,
+                timestamp: timestamp,
+            };
+
+            if self.find_connection(id.sa).is_none() || msg.len() > CAN_PAYLOAD_SIZE {
+                return Ok(Event::UnknownId(failure));
+            }
+
+            let mut data = [0; CAN_PAYLOAD_SIZE];
+            data[..msg.len()].copy_from_slice(msg);
+            let pending = J1939Pending {
+                data: data,
+                len: msg.len(),
+                timestamp: timestamp,
+            };
+
+            // Messages of a PGN that isn't authenticated simply replace each other.
+            return match self.expected.insert(pending_key(id.sa, id.pgn), pending) {
+                Ok(_) => Ok(Event::Received(Message {
+                    id: id.pgn as u16,
+                    data: Payload::new(msg),
+                    counter: 0,
+                    epoch: 0,
+                    timestamp: timestamp,
+                })),
+                Err(_) => Ok(Event::MissingMAC(Failure {
+                    reason: FailReason::StoreFull,
+                    ..failure
+                })),
+            };
+        }
+
+        if msg.len() < J1939_PGN_SIZE {
+            return Ok(Event::IncorrectMAC(Failure {
+                id: id.pgn as u16,
+                counter: 0,
+                reason: FailReason::Truncated,
+                timestamp: timestamp,
+            }));
+        }
+
+        let pgn = LittleEndian::read_uint(&msg[..J1939_PGN_SIZE], J1939_PGN_SIZE) as u32;
+        if pgn > J1939_PGN_MAX {
+            return Err(());
+        }
+
+        let pending = match self.expected.remove(&pending_key(id.sa, pgn)) {
+            Some(pending) => pending,
+            None => {
+                return Ok(Event::UnexpectedMAC(Failure {
+                    id: pgn as u16,
+                    counter: 0,
+                    reason: FailReason::NoPendingData,
+                    timestamp: timestamp,
+                }))
+            }
+        };
+
+        let mut ad = [0; J1939_AD_HEADER_SIZE + CAN_PAYLOAD_SIZE];
+        let ad_len = J1939_AD_HEADER_SIZE + pending.len;
+        ad[J1939_AD_HEADER_SIZE..ad_len].copy_from_slice(&pending.data[..pending.len]);
+
+        match self.verify_mac(id.sa, pgn, &mut ad[..ad_len], msg) {
+            Ok(freshness) => Ok(Event::Authenticated(Message {
+                id: pgn as u16,
+                data: Payload::new(&pending.data[..pending.len]),
+                counter: freshness,
+                epoch: 0,
+                timestamp: pending.timestamp,
+            })),
+            Err(event) => Ok(event),
+        }
+    }
+}
+
+/// Errors returned by a [J1939Bam](struct.J1939Bam.html).
+pub enum J1939Error {
+    /// The message fits in a single frame and is sent with `auth_send`.
+    TooShort,
+    /// The message does not fit in a single transfer.
+    TooLong,
+    /// A transmission is already in progress.
+    Busy,
+    /// The PGN is not in data page 0.
+    Pgn,
+    /// The context has no connection for its source address.
+    NotConfigured,
+    /// The freshness value of the message could not be recorded.
+    StoreFull,
+    /// The frame is malformed or not expected in the current state.
+    Protocol,
+    /// The frame is not part of a transfer of the source address.
+    NotTransport,
+    /// The reassembled message failed authentication.
+    Auth(Event),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum BamState {
+    Idle,
+    Transfer,
+    WaitMac,
+}
+
+/// Broadcast Announce Message transfers of J1939 messages longer than a frame,
+/// authenticated by a single MAC frame after the last TP.DT frame.
+///
+/// Transfers are received from the source address `sa`. Its TP.CM and TP.DT
+/// frames, as well as its MAC frames, must be passed to [recv](#method.recv)
+/// first, which returns `NotTransport` for frames to be passed to `auth_recv`.
+pub struct J1939Bam {
+    sa: u8,
+
+    tx_buf: [u8; J1939_BUF_SIZE],
+    tx_len: usize,
+    tx_pgn: u32,
+    tx_seq: u8,
+    tx_mac: CANPayload,
+    tx_state: BamState,
+
+    rx_buf: [u8; J1939_BUF_SIZE],
+    rx_len: usize,
+    rx_pgn: u32,
+    rx_seq: u8,
+    rx_state: BamState,
+}
+
+impl J1939Bam {
+    /// Creates a new channel receiving transfers from `sa`.
+    pub fn new(sa: u8) -> Self {
+        Self {
+            sa: sa,
+
+            tx_buf: [0; J1939_BUF_SIZE],
+            tx_len: 0,
+            tx_pgn: 0,
+            tx_seq: 0,
+            tx_mac: [0; CAN_PAYLOAD_SIZE],
+            tx_state: BamState::Idle,
+
+            rx_buf: [0; J1939_BUF_SIZE],
+            rx_len: 0,
+            rx_pgn: 0,
+            rx_seq: 0,
+            rx_state: BamState::Idle,
+        }
+    }
+
+    /// Gets the source address transfers are received from.
+    pub fn sa(&self) -> u8 {
+        self.sa
+    }
+
+    /// Starts an authenticated transfer of `msg` on `pgn`.
+    ///
+    /// Only the TP.CM_BAM frame is sent; the TP.DT frames and the MAC frame follow
+    /// with every call of [send_next](#method.send_next).
+    pub fn send<S, F, M, T>(
+        &mut self,
+        context: &mut J1939Context<S, F, M, T>,
+        pgn: u32,
+        msg: &[u8],
+    ) -> Result<(), J1939Error>
+    where
+        S: J1939Store,
+        F: FreshnessSource,
+        M: VulCANMac,
+        T: Clock,
+    {
+        if msg.len() < J1939_TP_MIN_SIZE {
+            return Err(J1939Error::TooShort);
+        }
+        if msg.len() > J1939_TP_MAX_SIZE {
+            return Err(J1939Error::TooLong);
+        }
+        if pgn > J1939_PGN_MAX {
+            return Err(J1939Error::Pgn);
+        }
+        if self.tx_state != BamState::Idle {
+            return Err(J1939Error::Busy);
+        }
+
+        let msg_end = J1939_AD_HEADER_SIZE + msg.len();
+        self.tx_buf[J1939_AD_HEADER_SIZE..msg_end].copy_from_slice(msg);
+        self.tx_mac = context.mac_frame(pgn, &mut self.tx_buf[..msg_end])?;
+
+        self.tx_len = msg.len();
+        self.tx_pgn = pgn;
+        self.tx_seq = 0;
+        self.tx_state = BamState::Transfer;
+
+        let mut frame = [0; CAN_PAYLOAD_SIZE];
+        frame[0] = J1939_TP_CM_BAM;
+        LittleEndian::write_u16(&mut frame[1..3], msg.len() as u16);
+        frame[3] = packets(msg.len());
+        frame[4] = 0xFF;
+        LittleEndian::write_uint(&mut frame[5..], pgn as u64, J1939_PGN_SIZE);
+
+        let id = J1939Id::new(J1939_TP_PRIORITY, J1939_TP_CM_PGN, context.sa);
+        (context.send)(id.eid(), &frame);
+
+        Ok(())
+    }
+
+    /// Sends the next TP.DT frame of the transfer, or its MAC frame after the last
+    /// one. Returns whether frames remain to be sent.
+    ///
+    /// J1939-21 requires 50 to 200 ms between the frames of a transfer.
+    pub fn send_next<S, F, M, T>(&mut self, context: &mut J1939Context<S, F, M, T>) -> bool
+    where
+        S: J1939Store,
+        F: FreshnessSource,
+        M: VulCANMac,
+        T: Clock,
+    {
+        if self.tx_state != BamState::Transfer {
+            return false;
+        }
+
+        if self.tx_seq == packets(self.tx_len) {
+            let id = J1939Id::new(context.priority, context.mac_pgn, context.sa);
+            (context.send)(id.eid(), &self.tx_mac);
+            self.tx_state = BamState::Idle;
+            return false;
+        }
+
+        let start = J1939_AD_HEADER_SIZE + self.tx_seq as usize * J1939_TP_DT_SIZE;
+        let end = (start + J1939_TP_DT_SIZE).min(J1939_AD_HEADER_SIZE + self.tx_len);
+        self.tx_seq += 1;
+
+        let mut frame = [0xFF; CAN_PAYLOAD_SIZE];
+        frame[0] = self.tx_seq;
+        frame[1..1 + end - start].copy_from_slice(&self.tx_buf[start..end]);
+
+        let id = J1939Id::new(J1939_TP_PRIORITY, J1939_TP_DT_PGN, context.sa);
+        (context.send)(id.eid(), &frame);
+
+        true
+    }
+
+    /// Handles a frame of the source address.
+    ///
+    /// Returns the PGN and message once a transfer has been received and
+    /// authenticated.
+    pub fn recv<S, F, M, T>(
+        &mut self,
+        context: &mut J1939Context<S, F, M, T>,
+        eid: u32,
+        data: &[u8],
+    ) -> Result<Option<(u32, &[u8])>, J1939Error>
+    where
+        S: J1939Store,
+        F: FreshnessSource,
+        M: VulCANMac,
+        T: Clock,
+    {
+        let id = match J1939Id::parse(eid) {
+            Some(id) if id.sa == self.sa => id,
+            _ => return Err(J1939Error::NotTransport),
+        };
+
+        match id.pgn {
+            J1939_TP_CM_PGN if data.len() == CAN_PAYLOAD_SIZE && data[0] == J1939_TP_CM_BAM => {
+                let len = LittleEndian::read_u16(&data[1..3]) as usize;
+                let pgn = LittleEndian::read_uint(&data[5..], J1939_PGN_SIZE) as u32;
+                if len < J1939_TP_MIN_SIZE || len > J1939_TP_MAX_SIZE || data[3] != packets(len) {
+                    self.rx_state = BamState::Idle;
+                    return Err(J1939Error::Protocol);
+                }
+                if pgn > J1939_PGN_MAX {
+                    self.rx_state = BamState::Idle;
+                    return Err(J1939Error::Pgn);
+                }
+
+                // A new announcement aborts the previous transfer.
+                self.rx_len = len;
+                self.rx_pgn = pgn;
+                self.rx_seq = 0;
+                self.rx_state = BamState::Transfer;
+
+                Ok(None)
+            }
+            J1939_TP_DT_PGN if self.rx_state == BamState::Transfer => {
+                if data.len() != CAN_PAYLOAD_SIZE
+                    || self.rx_seq >= packets(self.rx_len)
+                    || data[0] != self.rx_seq + 1
+                {
+                    self.rx_state = BamState::Idle;
+                    return Err(J1939Error::Protocol);
+                }
+
+                let start = J1939_AD_HEADER_SIZE + self.rx_seq as usize * J1939_TP_DT_SIZE;
+                let end = (start + J1939_TP_DT_SIZE).min(J1939_AD_HEADER_SIZE + self.rx_len);
+                self.rx_buf[start..end].copy_from_slice(&data[1..1 + end - start]);
+                self.rx_seq += 1;
+
+                if self.rx_seq == packets(self.rx_len) {
+                    self.rx_state = BamState::WaitMac;
+                }
+
+                Ok(None)
+            }
+            pgn if pgn == context.mac_pgn && self.rx_state == BamState::WaitMac => {
+                if data.len() < J1939_PGN_SIZE
+                    || LittleEndian::read_uint(&data[..J1939_PGN_SIZE], J1939_PGN_SIZE) as u32
+                        != self.rx_pgn
+                {
+                    return Err(J1939Error::NotTransport);
+                }
+
+                self.rx_state = BamState::Idle;
+
+                let msg_end = J1939_AD_HEADER_SIZE + self.rx_len;
+                context
+                    .verify_mac(self.sa, self.rx_pgn, &mut self.rx_buf[..msg_end], data)
+                    .map_err(J1939Error::Auth)?;
+
+                Ok(Some((
+                    self.rx_pgn,
+                    &self.rx_buf[J1939_AD_HEADER_SIZE..msg_end],
+                )))
+            }
+            _ => Err(J1939Error::NotTransport),
+        }
+    }
+}
+
+// Number of TP.DT frames needed for a message of `len` bytes.
+fn packets(len: usize) -> u8 {
+    ((len + J1939_TP_DT_SIZE - 1) / J1939_TP_DT_SIZE) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use store::ArrayStore;
+
+    type TestContext =
+        J1939Context<ArrayStore<u32, J1939Pending>, CounterFreshness<ArrayStore<u16, u64>>>;
+
+    fn context(sa: u8) -> TestContext {
+        let connections = [
+            J1939Connection::new(0x10).with_key(&[1; SANCUS_KEY_SIZE]),
+            J1939Connection::new(0x20).with_key(&[2; SANCUS_KEY_SIZE]),
+        ];
+        J1939Context::new(
+            sa,
+            &connections,
+            ArrayStore::new(),
+            CounterFreshness::new(ArrayStore::new()),
+        )
+    }
+
+    // Builds the MAC frame `context` sends after `msg` on `pgn`.
+    fn mac_frame(context: &mut TestContext, pgn: u32, msg: &[u8]) -> CANPayload {
+        let mut ad = [0; J1939_BUF_SIZE];
+        ad[J1939_AD_HEADER_SIZE..J1939_AD_HEADER_SIZE + msg.len()].copy_from_slice(msg);
+        context
+            .mac_frame(pgn, &mut ad[..J1939_AD_HEADER_SIZE + msg.len()])
+            .ok()
+            .unwrap()
+    }
+
+    fn cm_bam(len: u16, packets: u8, pgn: u32) -> CANPayload {
+        let mut frame = [0xFF; CAN_PAYLOAD_SIZE];
+        frame[0] = J1939_TP_CM_BAM;
+        LittleEndian::write_u16(&mut frame[1..3], len);
+        frame[3] = packets;
+        LittleEndian::write_uint(&mut frame[5..], pgn as u64, J1939_PGN_SIZE);
+        frame
+    }
+
+    #[test]
+    fn pdu_ids_round_trip() {
+        let id = J1939Id::parse(0x18FEF110 | CAN_EFF_FLAG).unwrap();
+        assert_eq!(
+            (id.priority, id.pgn, id.da, id.sa),
+            (6, 0xFEF1, J1939_GLOBAL, 0x10)
+        );
+        assert_eq!(id.eid(), 0x18FEF110 | CAN_EFF_FLAG);
+
+        let id = J1939Id::parse(0x0CEA2010 | CAN_EFF_FLAG).unwrap();
+        assert_eq!((id.priority, id.pgn, id.da, id.sa), (3, 0xEA00, 0x20, 0x10));
+        assert_eq!(id.eid(), 0x0CEA2010 | CAN_EFF_FLAG);
+
+        // PDU1 groups are sent to all nodes unless addressed.
+        let id = J1939Id::new(6, 0xEA20, 0x10);
+        assert_eq!((id.pgn, id.da), (0xEA00, J1939_GLOBAL));
+        assert_eq!(id.eid(), 0x18EAFF10 | CAN_EFF_FLAG);
+
+        // Standard and extended identifiers are told apart by the flag.
+        assert!(J1939Id::parse(0x7FF).is_none());
+        assert!(J1939Id::parse(0x18FEF110).is_none());
+        let id = J1939Id::parse(0x00000110 | CAN_EFF_FLAG).unwrap();
+        assert_eq!((id.priority, id.pgn, id.da, id.sa), (0, 0, 0x01, 0x10));
+    }
+
+    #[test]
+    fn mac_is_verified() {
+        let mut sender = context(0x10);
+        let mut receiver = context(0x20);
+        let eid = J1939Id::new(6, 0xFEF1, 0x10).eid();
+        let eid_mac = J1939Id::new(6, J1939_MAC_PGN, 0x10).eid();
+
+        let mac = mac_frame(&mut sender, 0xFEF1, &[1, 2, 3]);
+        receiver.auth_recv(eid, &[1, 2, 3]).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(&m.data[..], &[1, 2, 3]),
+            _ => panic!("Message not authenticated"),
+        }
+
+        let mac = mac_frame(&mut sender, 0xFEF1, &[1, 2, 3]);
+        receiver.auth_recv(eid, &[1, 2, 4]).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::IncorrectMAC(failure)) => assert_eq!(failure.reason, FailReason::MacMismatch),
+            _ => panic!("Tampered message accepted"),
+        }
+    }
+
+    #[test]
+    fn bam_is_reassembled_and_verified() {
+        let mut sender = context(0x10);
+        let mut receiver = context(0x20);
+        let mut bam = J1939Bam::new(0x10);
+        let cm = J1939Id::new(7, J1939_TP_CM_PGN, 0x10).eid();
+        let dt = J1939Id::new(7, J1939_TP_DT_PGN, 0x10).eid();
+        let eid_mac = J1939Id::new(6, J1939_MAC_PGN, 0x10).eid();
+
+        let mut msg = [0; 20];
+        for (i, b) in msg.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        for &tampered in [false, true].iter() {
+            let mut mac = mac_frame(&mut sender, 0xFEF1, &msg);
+            if tampered {
+                mac[CAN_PAYLOAD_SIZE - 1] ^= 1;
+            }
+
+            assert!(bam.recv(&mut receiver, cm, &cm_bam(20, 3, 0xFEF1)).is_ok());
+            for (seq, chunk) in msg.chunks(J1939_TP_DT_SIZE).enumerate() {
+                let mut frame = [0xFF; CAN_PAYLOAD_SIZE];
+                frame[0] = seq as u8 + 1;
+                frame[1..1 + chunk.len()].copy_from_slice(chunk);
+                assert!(bam.recv(&mut receiver, dt, &frame).is_ok());
+            }
+
+            match bam.recv(&mut receiver, eid_mac, &mac) {
+                Ok(Some((pgn, data))) if !tampered => {
+                    assert_eq!(pgn, 0xFEF1);
+                    assert_eq!(data, &msg[..]);
+                }
+                Err(J1939Error::Auth(Event::IncorrectMAC(_))) if tampered => {}
+                _ => panic!("Unexpected outcome of transfer"),
+            }
+        }
+    }
+
+    #[test]
+    fn short_bam_is_rejected() {
+        let mut receiver = context(0x20);
+        let mut bam = J1939Bam::new(0x10);
+        let cm = J1939Id::new(7, J1939_TP_CM_PGN, 0x10).eid();
+        let dt = J1939Id::new(7, J1939_TP_DT_PGN, 0x10).eid();
+
+        for &(len, packets) in [(0, 0), (8, 2)].iter() {
+            match bam.recv(&mut receiver, cm, &cm_bam(len, packets, 0xFEF1)) {
+                Err(J1939Error::Protocol) => {}
+                _ => panic!("Short transfer announced"),
+            }
+        }
+
+        for seq in 1..3 {
+            match bam.recv(&mut receiver, dt, &[seq, 0, 0, 0, 0, 0, 0, 0]) {
+                Err(J1939Error::NotTransport) => {}
+                _ => panic!("Data of rejected transfer accepted"),
+            }
+        }
+
+        let mut sender = context(0x10);
+        match bam.send(&mut sender, 0xFEF1, &[0; 8]) {
+            Err(J1939Error::TooShort) => {}
+            _ => panic!("Short message sent with TP.BAM"),
+        }
+    }
+
+    #[test]
+    fn data_page_1_is_not_handled() {
+        let mut sender = context(0x10);
+        let mut receiver = context(0x20);
+        let mut bam = J1939Bam::new(0x10);
+        let cm = J1939Id::new(7, J1939_TP_CM_PGN, 0x10).eid();
+        let eid_mac = J1939Id::new(6, J1939_MAC_PGN, 0x10).eid();
+
+        assert!(receiver
+            .auth_recv(J1939Id::new(6, 0x1FEF1, 0x10).eid(), &[1])
+            .is_err());
+
+        let mut mac = [0; CAN_PAYLOAD_SIZE];
+        LittleEndian::write_uint(&mut mac[..J1939_PGN_SIZE], 0x1FEF1, J1939_PGN_SIZE);
+        assert!(receiver.auth_recv(eid_mac, &mac).is_err());
+
+        match bam.recv(&mut receiver, cm, &cm_bam(20, 3, 0x1FEF1)) {
+            Err(J1939Error::Pgn) => {}
+            _ => panic!("Transfer on data page 1 announced"),
+        }
+        match bam.send(&mut sender, 0x1FEF1, &[0; 20]) {
+            Err(J1939Error::Pgn) => {}
+            _ => panic!("Transfer on data page 1 sent"),
+        }
+    }
+
+    static SENT: AtomicUsize = AtomicUsize::new(0);
+
+    fn send(_: u32, _: &[u8]) {
+        SENT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn unconfigured_source_address_sends_nothing() {
+        let mut context = context(0x30).with_send(send);
+        let mut bam = J1939Bam::new(0x10);
+
+        context.auth_send(0xFEF1, &[1, 2, 3]);
+        match bam.send(&mut context, 0xFEF1, &[0; 20]) {
+            Err(J1939Error::NotConfigured) => {}
+            _ => panic!("Transfer sent without a connection"),
+        }
+        assert!(!bam.send_next(&mut context));
+        assert_eq!(SENT.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,13 +1,21 @@
 use byteorder::{ByteOrder, LittleEndian};
 use spongent::spongent_mac;
 
+use freshness::*;
 use vulcan::*;
 
 use core::convert::From;
-
-const LEIA_AD_SIZE: usize = 12;
+use core::fmt;
+use core::mem;
+
+pub(crate) const LEIA_AD_HEADER_SIZE: usize = 4;
+const LEIA_AD_SIZE: usize = LEIA_AD_HEADER_SIZE + CAN_PAYLOAD_SIZE;
+const LEIA_FD_AD_SIZE: usize = LEIA_AD_HEADER_SIZE + CAN_FD_PAYLOAD_SIZE;
+const LEIA_FRESH_AD_SIZE: usize = 10 + CAN_FD_PAYLOAD_SIZE;
+pub(crate) const LEIA_MAC_SIZE: usize = SANCUS_KEY_SIZE;
 const LEIA_COUNT_MAX: u16 = 0xFFFF;
 const LEIA_EPOCH_MAX: u64 = 0xFFFFFFFFFFFFFF;
+const LEIA_EPOCH_JUMP_MAX: u64 = 0xFFFF;
 const LEIA_CMD_MASK: u32 = 0x03;
 
 /// Structure representing a LeiA connection.
@@ -15,11 +23,92 @@ const LEIA_CMD_MASK: u32 = 0x03;
 pub struct LeiAConnection {
     id: u16,
     c: u16,
+    c_max: u16,
     epoch: u64,
     k_i: SancusKey,
     k_e: SancusKey,
+    mode: LeiAMode,
+    stats: LeiAStats,
+    max_epoch_jump: u64,
+    epoch_exhausted: bool,
+    rekey_requested: bool,
 
     auth_fail_in_progress: bool,
+    auth_fail_sent_at: Option<u64>,
+    auth_fail_retries: u8,
+    auth_fail_interval: u64,
+    auth_fail_suppressed: u64,
+
+    // AEC frame awaiting its MAC, kept apart from the data frames so that
+    // unverified frames of one kind can't displace the other.
+    aec_pending: Option<LeiAPending>,
+}
+
+/// Health counters of a LeiA connection.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LeiAStats {
+    /// Authenticated messages sent.
+    pub sent: u64,
+    /// Messages authenticated.
+    pub authenticated: u64,
+    /// Messages rejected because of an incorrect MAC.
+    pub incorrect_macs: u64,
+    /// Data frames whose MAC frame never arrived.
+    pub missing_macs: u64,
+    /// MAC frames received without a preceding data frame.
+    pub unexpected_macs: u64,
+    /// Messages rejected because of an old counter or freshness value.
+    pub desyncs: u64,
+    /// New epochs accepted from a response to AUTH_FAIL.
+    pub resyncs: u64,
+    /// AUTH_FAIL frames sent for this connection.
+    pub auth_fail_sent: u64,
+    /// AUTH_FAIL frames received for this connection.
+    pub auth_fail_received: u64,
+    /// AUTH_FAIL frames not sent because of the rate limit or a resync in progress.
+    pub auth_fail_suppressed: u64,
+    /// Session key changes after initialisation.
+    pub epoch_changes: u64,
+}
+
+/// Frame format used to transmit authenticated messages on a LeiA connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LeiAMode {
+    /// Data and MAC are sent as two classic CAN frames on `id` and `id + 1`.
+    Classic,
+    /// Data and a MAC truncated to `mac_len` bytes are sent together in a single
+    /// CAN FD frame on `id`. The MAC occupies the last `mac_len` bytes of the frame;
+    /// frames longer than 8 bytes carry the data length in the byte before the MAC.
+    Fd { mac_len: usize },
+    /// Data and a MAC truncated to `mac_len` bytes are sent together in a single
+    /// classic CAN frame on `id`, leaving `8 - mac_len` bytes for the data.
+    SingleFrame { mac_len: usize },
+}
+
+impl LeiAMode {
+    // Returns the MAC length and maximum frame length if data and MAC share a frame.
+    fn combined(&self) -> Option<(usize, usize)> {
+        match *self {
+            LeiAMode::Classic => None,
+            LeiAMode::Fd { mac_len } => Some((mac_len, CAN_FD_PAYLOAD_SIZE)),
+            LeiAMode::SingleFrame { mac_len } => Some((mac_len, CAN_PAYLOAD_SIZE)),
+        }
+    }
+
+    /// Returns the maximum length of the data in a single authenticated frame.
+    pub fn max_data_len(&self) -> usize {
+        match self.combined() {
+            Some((mac_len, max_len)) if max_len > CAN_PAYLOAD_SIZE => max_len - 1 - mac_len,
+            Some((mac_len, max_len)) => max_len - mac_len,
+            None => CAN_PAYLOAD_SIZE,
+        }
+    }
+}
+
+impl Default for LeiAMode {
+    fn default() -> Self {
+        LeiAMode::Classic
+    }
 }
 
 impl LeiAConnection {
@@ -28,14 +117,56 @@ impl LeiAConnection {
         Self {
             id: id,
             c: 0,
+            c_max: LEIA_COUNT_MAX,
             epoch: 0,
             k_i: Default::default(),
             k_e: Default::default(),
+            mode: LeiAMode::Classic,
+            stats: Default::default(),
+            max_epoch_jump: LEIA_EPOCH_JUMP_MAX,
+            epoch_exhausted: false,
+            rekey_requested: false,
 
             auth_fail_in_progress: false,
+            auth_fail_sent_at: None,
+            auth_fail_retries: 0,
+            auth_fail_interval: 0,
+            auth_fail_suppressed: 0,
+
+            aec_pending: None,
         }
     }
 
+    /// Switches this connection to CAN FD mode, carrying data and a `mac_len`
+    /// byte MAC in the same frame.
+    pub fn with_fd(mut self, mac_len: usize) -> Self {
+        assert!(mac_len > 0 && mac_len <= LEIA_MAC_SIZE);
+        self.mode = LeiAMode::Fd { mac_len: mac_len };
+        self
+    }
+
+    /// Switches this connection to single-frame mode, carrying short signals
+    /// and a MAC truncated to `mac_len` bytes in one classic CAN frame.
+    pub fn with_single_frame(mut self, mac_len: usize) -> Self {
+        assert!(mac_len > 0 && mac_len < CAN_PAYLOAD_SIZE);
+        self.mode = LeiAMode::SingleFrame { mac_len: mac_len };
+        self
+    }
+
+    /// Limits the AUTH_FAIL frames sent for this connection after rejected frames
+    /// to one per `interval`, as measured by the clock of the context.
+    pub fn with_auth_fail_interval(mut self, interval: u64) -> Self {
+        self.auth_fail_interval = interval;
+        self
+    }
+
+    /// Limits how far the epoch of this connection may advance at once in response
+    /// to AUTH_FAIL. Defaults to 65535 epochs.
+    pub fn with_max_epoch_jump(mut self, jump: u64) -> Self {
+        self.max_epoch_jump = jump;
+        self
+    }
+
     /// Sets k_i of this connection.
     pub fn with_k_i(mut self, key: &[u8]) -> Self {
         self.k_i.copy_from_slice(key);
@@ -51,23 +182,80 @@ impl LeiAConnection {
     pub fn counter(&self) -> u16 {
         self.c
     }
+
+    /// Gets the current epoch of the connection.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Checks whether the connection used up all epochs of its k_i. No frames are
+    /// sent or accepted on the connection until a new k_i is installed with
+    /// [rekey](struct.LeiAContext.html#method.rekey).
+    pub fn epoch_exhausted(&self) -> bool {
+        self.epoch_exhausted
+    }
+
+    /// Gets the frame format of the connection.
+    pub fn mode(&self) -> LeiAMode {
+        self.mode
+    }
+
+    /// Gets the health counters of the connection.
+    pub fn stats(&self) -> LeiAStats {
+        self.stats
+    }
+
+    /// Checks whether the connection sent AUTH_FAIL and awaits the new epoch.
+    pub fn auth_fail_in_progress(&self) -> bool {
+        self.auth_fail_in_progress
+    }
 }
 
 /// Structure managing multiple LeiA connections on a single node.
-pub struct LeiAContext<S>
+///
+/// By default, freshness is provided by the counter and epoch of each connection.
+/// A [FreshnessSource](trait.FreshnessSource.html) can be set with
+/// [with_freshness](#method.with_freshness) instead.
+///
+/// With [with_buffering](#method.with_buffering), the data of a classic data frame
+/// is only handed to the application once its MAC frame has been verified.
+///
+/// Received frames are timestamped by a [Clock](trait.Clock.html), by default a
+/// plain function set with [with_time](#method.with_time).
+pub struct LeiAContext<S, F = NoFreshness, T = fn() -> u64>
 where
-    S: VulCANStore<K = u16, V = [u8; CAN_PAYLOAD_SIZE]>,
+    S: VulCANStore<K = u16, V = LeiAPending>,
+    F: FreshnessSource,
+    T: Clock,
 {
     connections: [LeiAConnection; 16],
     aec: LeiAConnection,
     expected: S,
     send: fn(u32, &[u8]),
+    clock: T,
+    release: Option<fn(&Message)>,
+    timeout: Option<u64>,
+    auth_fail_retry: Option<(u64, u8)>,
+    rekey_request: Option<fn(u16)>,
+    layout: EidLayout,
+    queue: Option<Queue<(u32, Payload)>>,
+    dropped_frames: u64,
+    freshness: Option<F>,
+}
+
+/// Data frame awaiting its MAC frame, as kept in a [LeiAStore](trait.LeiAStore.html).
+#[derive(Copy, Clone, Debug)]
+pub struct LeiAPending {
+    mac: CANPayload,
+    data: Payload,
+    counter: u64,
+    timestamp: u64,
 }
 
 // Ergonomics. Use LeiAStore as alias for specific VulCANStore.
 // @Fixme: Use trait aliasing https://github.com/rust-lang/rust/issues/41517
-pub trait LeiAStore: VulCANStore<K = u16, V = [u8; CAN_PAYLOAD_SIZE]> {}
-impl<T> LeiAStore for T where T: VulCANStore<K = u16, V = [u8; CAN_PAYLOAD_SIZE]> {}
+pub trait LeiAStore: VulCANStore<K = u16, V = LeiAPending> {}
+impl<T> LeiAStore for T where T: VulCANStore<K = u16, V = LeiAPending> {}
 
 impl<'a, S> LeiAContext<S>
 where
@@ -92,8 +280,63 @@ where
             aec: aec,
             expected: expected,
             send: |_, _| {},
+            clock: || 0,
+            release: None,
+            timeout: None,
+            auth_fail_retry: None,
+            rekey_request: None,
+            layout: Default::default(),
+            queue: None,
+            dropped_frames: 0,
+            freshness: None,
+        }
+    }
+}
+
+impl<S, T> LeiAContext<S, NoFreshness, T>
+where
+    S: LeiAStore,
+    T: Clock,
+{
+    /// Sets the freshness source used for data frames instead of the connection
+    /// counters.
+    ///
+    /// The counter field of data and MAC frames then carries the 16 least
+    /// significant bits of the freshness value, while the MAC covers the complete
+    /// value. Messages with an unacceptable freshness value or an incorrect MAC
+    /// are rejected without sending AUTH_FAIL.
+    pub fn with_freshness<F>(self, freshness: F) -> LeiAContext<S, F, T>
+    where
+        F: FreshnessSource,
+    {
+        LeiAContext {
+            connections: self.connections,
+            aec: self.aec,
+            expected: self.expected,
+            send: self.send,
+            clock: self.clock,
+            release: self.release,
+            timeout: self.timeout,
+            auth_fail_retry: self.auth_fail_retry,
+            rekey_request: self.rekey_request,
+            layout: self.layout,
+            queue: self.queue,
+            dropped_frames: self.dropped_frames,
+            freshness: Some(freshness),
         }
     }
+}
+
+impl<S, F, T> LeiAContext<S, F, T>
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    /// Gets the freshness source of the context, if any.
+    pub fn freshness(&mut self) -> Option<&mut F> {
+        self.freshness.as_mut()
+    }
 
     /// Sets the function to be used by the context to send messages.
     pub fn with_send(mut self, send: fn(u32, &[u8])) -> Self {
@@ -101,7 +344,333 @@ where
         self
     }
 
+    /// Sets the function to be used by the context to timestamp received frames.
+    pub fn with_time(self, time: fn() -> u64) -> LeiAContext<S, F, fn() -> u64> {
+        self.with_clock(time)
+    }
+
+    /// Sets the clock to be used by the context to timestamp received frames.
+    pub fn with_clock<C>(self, clock: C) -> LeiAContext<S, F, C>
+    where
+        C: Clock,
+    {
+        LeiAContext {
+            connections: self.connections,
+            aec: self.aec,
+            expected: self.expected,
+            send: self.send,
+            clock: clock,
+            release: self.release,
+            timeout: self.timeout,
+            auth_fail_retry: self.auth_fail_retry,
+            rekey_request: self.rekey_request,
+            layout: self.layout,
+            queue: self.queue,
+            dropped_frames: self.dropped_frames,
+            freshness: self.freshness,
+        }
+    }
+
+    // Gets the current time according to the clock.
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Gets the connections managed by the context.
+    pub fn connections(&self) -> impl Iterator<Item = &LeiAConnection> {
+        self.connections.iter().filter(|c| c.id != 0)
+    }
+
+    /// Gets the health counters of connection `id`.
+    pub fn stats(&mut self, id: u16) -> Option<LeiAStats> {
+        self.find_connection(id).map(|c| c.stats)
+    }
+
+    /// Resets the health counters of all connections.
+    pub fn reset_stats(&mut self) {
+        for connection in self.connections.iter_mut() {
+            connection.stats = Default::default();
+        }
+        self.aec.stats = Default::default();
+    }
+
+    /// Enables buffered receiving.
+    ///
+    /// Data frames are held by the context and `release` is called with their
+    /// data once the matching MAC frame has been verified. `auth_recv` then
+    /// returns `Buffered` instead of `Received` for data frames. Frames whose MAC
+    /// doesn't arrive within `timeout`, as measured by the time function, are
+    /// discarded.
+    pub fn with_buffering(mut self, release: fn(&Message), timeout: u64) -> Self {
+        self.release = Some(release);
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the time after which data frames whose MAC hasn't arrived are
+    /// discarded by [discard_expired](#method.discard_expired), without buffering
+    /// their data.
+    pub fn with_mac_timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the layout of the extended identifiers of LeiA frames.
+    ///
+    /// Counters of the connections wrap around at the largest value carried by
+    /// the layout, moving to the next epoch. The layout applies to all connections
+    /// of the context, including the AEC.
+    pub fn with_layout(mut self, layout: EidLayout) -> Self {
+        for connection in self.connections.iter_mut() {
+            connection.c_max = layout.counter_max();
+        }
+        self.aec.c_max = layout.counter_max();
+
+        self.layout = layout;
+        self
+    }
+
+    /// Gets the layout of the extended identifiers of LeiA frames.
+    pub fn layout(&self) -> &EidLayout {
+        &self.layout
+    }
+
+    /// Queues outgoing frames instead of passing them to the send function.
+    ///
+    /// Queued frames are retrieved with [pop_frame](#method.pop_frame). At most
+    /// 32 frames can be queued, further frames are dropped and counted by
+    /// [dropped_frames](#method.dropped_frames).
+    pub fn with_queue(mut self) -> Self {
+        self.queue = Some(Queue::new());
+        self
+    }
+
+    /// Takes the oldest queued outgoing frame, if any.
+    pub fn pop_frame(&mut self) -> Option<(u32, Payload)> {
+        self.queue.as_mut().and_then(|queue| queue.pop())
+    }
+
+    /// Gets the number of outgoing frames dropped because the queue was full.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Discards all data frames that have been waiting for their MAC longer than
+    /// the timeout, passing a `MissingMAC` failure to `report` for each.
+    pub fn discard_expired<R>(&mut self, report: R)
+    where
+        R: FnMut(Failure),
+    {
+        let now = self.now();
+        self.expire(now, report);
+    }
+
+    /// Retries AUTH_FAIL frames that weren't answered by a new epoch.
+    ///
+    /// AUTH_FAIL is sent again by [poll](#method.poll) after `timeout`, doubling
+    /// the timeout for every retry. After `max_retries` retries, the connection
+    /// stops waiting for the new epoch. While waiting, rejected frames don't
+    /// cause additional AUTH_FAIL frames.
+    pub fn with_auth_fail_retry(mut self, timeout: u64, max_retries: u8) -> Self {
+        self.auth_fail_retry = Some((timeout, max_retries));
+        self
+    }
+
+    /// Sets the function called with the id of a connection that used up all
+    /// epochs of its k_i, to request a new k_i, e.g. from the attestation
+    /// subsystem. The new key is installed with [rekey](#method.rekey).
+    pub fn with_rekey_request(mut self, request: fn(u16)) -> Self {
+        self.rekey_request = Some(request);
+        self
+    }
+
+    /// Installs `k_i` as the new long-term key of connection `id`, restarting
+    /// its epochs. The other nodes on the connection need to install the same key.
+    pub fn rekey(&mut self, id: u16, k_i: &[u8]) {
+        {
+            let connection = self
+                .find_connection(id)
+                .expect("No connection with specified id.");
+            connection.k_i.copy_from_slice(k_i);
+            connection.epoch = 0;
+            connection.epoch_exhausted = false;
+            connection.rekey_requested = false;
+            connection.auth_fail_in_progress = false;
+            connection.aec_pending = None;
+            session_key_gen(connection);
+            connection.stats.epoch_changes += 1;
+        }
+
+        // Pending data frames were authenticated with the old key.
+        self.expected.remove(&id);
+    }
+
+    /// Handles the timeouts of the context at the current time of its clock,
+    /// passing an event to `report` for each.
+    ///
+    /// Data frames waiting for their MAC longer than the MAC timeout are
+    /// discarded and reported as `MissingMAC`. Unanswered AUTH_FAIL frames are
+    /// retried as configured with [with_auth_fail_retry](#method.with_auth_fail_retry),
+    /// reporting a `Desync` for every retry and when giving up. AUTH_FAIL frames
+    /// suppressed since the previous call are reported as `AuthFailSuppressed`.
+    pub fn poll<R>(&mut self, mut report: R)
+    where
+        R: FnMut(Event),
+    {
+        let now = self.now();
+        self.expire(now, |failure| report(Event::MissingMAC(failure)));
+
+        for connection in self.connections.iter_mut() {
+            if connection.auth_fail_suppressed > 0 {
+                report(Event::AuthFailSuppressed(
+                    connection.id,
+                    connection.auth_fail_suppressed,
+                ));
+                connection.auth_fail_suppressed = 0;
+            }
+        }
+
+        let (timeout, max_retries) = match self.auth_fail_retry {
+            Some(retry) => retry,
+            None => return,
+        };
+
+        for i in 0..self.connections.len() {
+            let (id, retry) = {
+                let connection = &mut self.connections[i];
+
+                if connection.id == 0 || !connection.auth_fail_in_progress {
+                    continue;
+                }
+
+                let backoff = timeout.saturating_mul(1 << connection.auth_fail_retries.min(32));
+                let sent_at = connection.auth_fail_sent_at.unwrap_or(now);
+                if now.saturating_sub(sent_at) <= backoff {
+                    continue;
+                }
+
+                if connection.auth_fail_retries < max_retries {
+                    connection.auth_fail_retries += 1;
+                } else {
+                    connection.auth_fail_in_progress = false;
+                }
+
+                (connection.id, connection.auth_fail_in_progress)
+            };
+
+            report(self.record(Event::Desync(Failure {
+                id: id,
+                counter: 0,
+                reason: FailReason::ResyncTimeout,
+                timestamp: now,
+            })));
+
+            if retry {
+                self.leia_auth_fail_send(id);
+                self.connections[i].auth_fail_sent_at = Some(now);
+            }
+        }
+    }
+
+    /// Gets the earliest time at which [poll](#method.poll) has something to
+    /// do, or `None` if no timeout is pending, e.g. to arm a timer.
+    ///
+    /// Suppressed AUTH_FAIL frames are due right away.
+    pub fn next_timeout(&self) -> Option<u64> {
+        let mut next = None;
+        let mut due = |at: u64| {
+            next = Some(next.map_or(at, |next: u64| next.min(at)));
+        };
+
+        if let Some(timeout) = self.timeout {
+            let expiry = |pending: &LeiAPending| pending.timestamp.saturating_add(timeout + 1);
+
+            for connection in self.connections.iter().chain(Some(&self.aec)) {
+                if let Some(ref pending) = connection.aec_pending {
+                    due(expiry(pending));
+                }
+            }
+            self.expected.for_each(|_, pending| due(expiry(pending)));
+        }
+
+        for connection in self.connections.iter() {
+            if connection.auth_fail_suppressed > 0 {
+                due(self.now());
+            }
+
+            if let (Some((timeout, _)), Some(sent_at)) =
+                (self.auth_fail_retry, connection.auth_fail_sent_at)
+            {
+                if connection.id != 0 && connection.auth_fail_in_progress {
+                    let backoff = timeout.saturating_mul(1 << connection.auth_fail_retries.min(32));
+                    due(sent_at.saturating_add(backoff).saturating_add(1));
+                }
+            }
+        }
+
+        next
+    }
+
+    // Discards data frames that have been waiting for their MAC longer than the
+    // timeout at time `now`.
+    fn expire<R>(&mut self, now: u64, mut report: R)
+    where
+        R: FnMut(Failure),
+    {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        // AEC frames are held apart from the data frames, also by the AEC itself.
+        for connection in self.connections.iter_mut().chain(Some(&mut self.aec)) {
+            let expired = match connection.aec_pending {
+                Some(pending) if now.saturating_sub(pending.timestamp) > timeout => pending,
+                _ => continue,
+            };
+
+            connection.aec_pending = None;
+
+            let failure = Failure {
+                id: connection.id,
+                counter: expired.counter,
+                reason: FailReason::Timeout,
+                timestamp: now,
+            };
+            if let Some((_, count)) = stats_counter(&Event::MissingMAC(failure)) {
+                count(&mut connection.stats);
+            }
+            report(failure);
+        }
+
+        let connections = &mut self.connections;
+
+        self.expected.retain(|&id, pending| {
+            if now.saturating_sub(pending.timestamp) <= timeout {
+                return true;
+            }
+
+            let failure = Failure {
+                id: id,
+                counter: pending.counter,
+                reason: FailReason::Timeout,
+                timestamp: now,
+            };
+            let connection = connections.iter_mut().find(|c| c.id == id);
+            if let (Some(connection), Some((_, count))) =
+                (connection, stats_counter(&Event::MissingMAC(failure)))
+            {
+                count(&mut connection.stats);
+            }
+            report(failure);
+
+            false
+        });
+    }
+
     /// Sends authenticated message on provided id.
+    ///
+    /// Nothing is sent on a connection that used up all epochs of its k_i.
     pub fn leia_auth_send(&mut self, id: u16, msg: &[u8], is_aec: bool) {
         let (cmd, cmd_mac) = if !is_aec {
             (LeiACmd::Data, LeiACmd::Mac)
@@ -109,37 +678,85 @@ where
             (LeiACmd::AecEpoch, LeiACmd::AecMac)
         };
 
-        let send = self.send;
+        if self.exhausted(id) {
+            return;
+        }
 
-        let connection = self.find_connection(id).unwrap();
+        if !is_aec {
+            self.find_connection(id).unwrap().stats.sent += 1;
+        }
 
-        let eid = build_eid(connection.id, cmd, connection.c);
-        (send)(eid, msg);
+        if !is_aec && self.freshness.is_some() {
+            self.leia_fresh_send(id, msg);
+            return;
+        }
 
-        let id_mac = if is_aec {
-            connection.id
-        } else {
-            connection.id + 1
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+
+        let layout = self.layout;
+
+        let combined = {
+            let connection = self.find_connection(id).unwrap();
+
+            match (is_aec, connection.mode.combined()) {
+                (false, Some((mac_len, max_len))) => Some(leia_combined_build(
+                    &mut frame, connection, msg, mac_len, max_len, &layout,
+                )),
+                _ => None,
+            }
         };
 
-        let eid_mac = build_eid(id_mac, cmd_mac, connection.c);
-        let msg_mac = mac_create(&connection.k_e, connection.id, msg, connection.c);
-        (send)(eid_mac, &msg_mac);
+        if let Some((eid, len)) = combined {
+            self.transmit(eid, &frame[..len]);
+            self.exhausted(id);
+            return;
+        }
 
-        update_counters(connection);
+        let (eid, eid_mac, msg_mac) = {
+            let connection = self.find_connection(id).unwrap();
+
+            let id_mac = if is_aec {
+                connection.id
+            } else {
+                connection.id + 1
+            };
+
+            let eid = layout.build(connection.id, cmd, connection.c);
+            let eid_mac = layout.build(id_mac, cmd_mac, connection.c);
+            let msg_mac = mac_create(&connection.k_e, connection.id, msg, connection.c);
+
+            update_counters(connection);
+
+            (eid, eid_mac, msg_mac)
+        };
+
+        self.transmit(eid, msg);
+        self.transmit(eid_mac, &msg_mac);
+        self.exhausted(id);
     }
 
     /// Sends AUTH_FAIL error frame on provided id.
     pub fn leia_auth_fail_send(&mut self, id: u16) {
+        if self.exhausted(id) {
+            return;
+        }
+
         let aec_id = self.aec.id;
         let aec_epoch = self.aec.epoch;
 
+        let now = self.now();
+
         // Zero counter to indicate connection awaits AUTH_FAIL response
         // @TODO not anymore -> auth_fail_in_progress
         {
             let connection = self.find_connection(id).unwrap();
             connection.c = 0;
+            if !connection.auth_fail_in_progress {
+                connection.auth_fail_retries = 0;
+            }
             connection.auth_fail_in_progress = true;
+            connection.auth_fail_sent_at = Some(now);
+            connection.stats.auth_fail_sent += 1;
         }
 
         // NOTE: We currently do not implement AEC resynchronisation
@@ -156,24 +773,75 @@ where
         self.leia_auth_send(aec_id, &msg, true);
     }
 
-    /// Called when an auth fail response has been received.
-    pub fn leia_auth_fail_receive(&mut self, id: u16, epoch: u64) {
+    // Sends AUTH_FAIL for a rejected frame on `id`, unless the rate limit of the
+    // connection was reached or a resync is in progress that's retried by `poll`.
+    fn leia_auth_fail_request(&mut self, id: u16) {
+        let now = self.now();
+        let retrying = self.auth_fail_retry.is_some();
+
+        let suppressed = {
+            let connection = self.find_connection(id).unwrap();
+            let interval = connection.auth_fail_interval;
+            let limited = connection
+                .auth_fail_sent_at
+                .map_or(false, |sent_at| now.saturating_sub(sent_at) < interval);
+            let resyncing = retrying && connection.auth_fail_in_progress;
+
+            if limited || resyncing {
+                connection.auth_fail_suppressed += 1;
+                connection.stats.auth_fail_suppressed += 1;
+            }
+
+            limited || resyncing
+        };
+
+        if !suppressed {
+            self.leia_auth_fail_send(id);
+        }
+    }
+
+    /// Called when an auth fail response has been received. Returns whether the
+    /// new epoch was accepted.
+    pub fn leia_auth_fail_receive(&mut self, id: u16, epoch: u64) -> bool {
         let connection = self.find_connection(id).unwrap();
 
-        // @NOTE: New epoch should be strictly higher to prevent replay attacks
-        if epoch > connection.epoch {
-            connection.epoch = epoch - 1;
-            session_key_gen(connection);
+        // @NOTE: New epoch should be strictly higher to prevent replay attacks, and
+        // not too far ahead, so the epochs can't be used up by a single frame.
+        if epoch <= connection.epoch
+            || epoch > LEIA_EPOCH_MAX
+            || epoch - connection.epoch > connection.max_epoch_jump
+        {
+            return false;
         }
+
+        connection.epoch = epoch - 1;
+        session_key_gen(connection);
+        connection.stats.epoch_changes += 1;
+
+        true
     }
 
     /// Responds to a received AUTH_FAIL frame.
     pub fn leia_auth_fail_send_response(&mut self, id: u16) {
         let epoch = {
             let connection = self.find_connection(id).unwrap();
+            connection.stats.auth_fail_received += 1;
             session_key_gen(connection);
 
-            connection.epoch
+            if connection.epoch_exhausted {
+                None
+            } else {
+                connection.stats.epoch_changes += 1;
+                Some(connection.epoch)
+            }
+        };
+
+        let epoch = match epoch {
+            Some(epoch) => epoch,
+            None => {
+                self.exhausted(id);
+                return;
+            }
         };
 
         let mut msg: [u8; 8] = [0; 8];
@@ -182,83 +850,643 @@ where
         self.leia_auth_send(id, &msg, true);
     }
 
-    // Finds the connection with the specified id
-    fn find_connection(&mut self, id: u16) -> Option<&mut LeiAConnection> {
-        // @Cleanup: When Rust-sgx-sdk compiles with a newer version of rustc
-        // than 1.22 nightly, something like this could be used with option filter:
-        // self.connections.iter_mut()
-        //     .find(|ref x| x.id == id)
-        //     .or(Some(&mut self.aec).filter(|ref c| c.id == id))
+    /// Authenticates a message longer than a single frame on connection `id`,
+    /// consuming one counter value for the whole transfer.
+    ///
+    /// `ad` must contain the message after its first `LEIA_AD_HEADER_SIZE` bytes,
+    /// which are overwritten with counter and id. Returns the counter used
+    /// together with the full-length MAC.
+    pub(crate) fn leia_auth_segmented(
+        &mut self,
+        id: u16,
+        ad: &mut [u8],
+    ) -> Result<(u16, [u8; LEIA_MAC_SIZE]), Event> {
+        let failure = self.failure(id, 0, FailReason::NotConfigured);
+        self.find_connection(id).ok_or(Event::UnknownId(failure))?;
+
+        if self.exhausted(id) {
+            return Err(Event::Desync(self.failure(
+                id,
+                0,
+                FailReason::EpochExhausted,
+            )));
+        }
 
-        let aec_id = self.aec.id;
+        let connection = self.find_connection(id).unwrap();
+        let counter = connection.c;
 
-        let connection_opt = self.connections.iter_mut().find(|ref x| x.id == id);
+        leia_ad_header(ad, id, counter);
+        let mac = spongent_mac(&connection.k_e, ad).unwrap();
 
-        let aec_opt = if self.aec.id == id {
-            Some(&mut self.aec)
-        } else {
-            None
-        };
+        connection.stats.sent += 1;
+        update_counters(connection);
+        self.exhausted(id);
 
-        connection_opt.or(aec_opt)
+        Ok((counter, mac))
     }
 
-    // Calculate mac for message and put it in the map of expected messages
-    fn add_expected_msg(&mut self, id: u16, counter: u16, data: &[u8]) -> [u8; 8] {
-        let mac = {
-            let mut connection = self.find_connection(id).unwrap();
+    /// Verifies a reassembled message authenticated with
+    /// [leia_auth_segmented](#method.leia_auth_segmented).
+    ///
+    /// On success the counter of the connection moves past `counter`, so a
+    /// transfer can't be accepted twice.
+    pub(crate) fn leia_verify_segmented(
+        &mut self,
+        id: u16,
+        counter: u16,
+        ad: &mut [u8],
+        mac: &[u8],
+    ) -> Result<(), Event> {
+        let (connection_counter, k_e) = match self.find_connection(id) {
+            Some(connection) => (connection.c, connection.k_e),
+            None => {
+                let failure = self.failure(id, counter as u64, FailReason::NotConfigured);
+                return Err(Event::UnknownId(failure));
+            }
+        };
 
-            // Set our counter to be the same as the incomming message.
-            // This allows us to deal with desyncs < 1 epoch.
-            connection.c = counter;
+        if self.exhausted(id) {
+            let failure = self.failure(id, counter as u64, FailReason::EpochExhausted);
+            return Err(self.record(Event::Desync(failure)));
+        }
 
-            mac_create(&connection.k_e, id, data, counter)
-        };
+        if counter < connection_counter {
+            self.leia_auth_fail_request(id);
+            let failure = self.failure(id, counter as u64, FailReason::CounterTooOld);
+            return Err(self.record(Event::Desync(failure)));
+        }
 
-        self.expected.insert(&id, mac);
+        leia_ad_header(ad, id, counter);
+        let expected = spongent_mac(&k_e, ad).unwrap();
 
-        mac
-    }
-}
+        if mac.len() != LEIA_MAC_SIZE || expected != *mac {
+            self.leia_auth_fail_request(id);
+            let failure = self.failure(id, counter as u64, FailReason::MacMismatch);
+            return Err(self.record(Event::IncorrectMAC(failure)));
+        }
 
-fn update_counters(connection: &mut LeiAConnection) {
-    if connection.c == LEIA_COUNT_MAX {
-        assert!(connection.epoch != LEIA_EPOCH_MAX);
-        session_key_gen(connection);
-    } else {
-        connection.c += 1;
+        {
+            let connection = self.find_connection(id).unwrap();
+            connection.c = counter;
+            connection.stats.authenticated += 1;
+            update_counters(connection);
+        }
+        self.exhausted(id);
+
+        Ok(())
     }
-}
 
-pub fn session_key_gen(cur: &mut LeiAConnection) {
-    // 1. Increment epoch
-    cur.epoch += 1;
+    // Sends an authenticated message using the freshness source.
+    fn leia_fresh_send(&mut self, id: u16, msg: &[u8]) {
+        let freshness = self.freshness.as_mut().unwrap().tx_freshness(id);
 
-    // 2. Apply MAC algorithm on the epoch
-    let mut epoch_buf = [0; 8];
-    LittleEndian::write_u64(&mut epoch_buf, cur.epoch);
-    let mac = spongent_mac(&cur.k_i, &epoch_buf).unwrap();
-    cur.k_e.copy_from_slice(&mac);
+        let (k_e, mode) = {
+            let connection = self.find_connection(id).unwrap();
+            (connection.k_e, connection.mode)
+        };
 
-    // 3. Reset counter
-    cur.c = 1;
-}
+        // Messages whose freshness value can't be recorded are not sent, as the
+        // value would be used again.
+        if self
+            .freshness
+            .as_mut()
+            .unwrap()
+            .tx_confirmation(id, freshness)
+            .is_err()
+        {
+            return;
+        }
 
-// TODO id and counter are from cur?
-pub fn mac_create(k_e: &SancusKey, id: u16, msg: &[u8], counter: u16) -> [u8; CAN_PAYLOAD_SIZE] {
-    let mut ad = [0; LEIA_AD_SIZE];
-    let mut buf = [0; 2];
+        let counter = freshness as u16;
+        let eid = self.layout.build(id, LeiACmd::Data, counter);
 
-    // Write counter to AD buffer
-    LittleEndian::write_u16(&mut buf, counter);
-    ad[0..2].copy_from_slice(&buf);
+        if let Some((mac_len, max_len)) = mode.combined() {
+            let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+            let len = leia_combined_frame(&mut frame, msg, mac_len, max_len, |data| {
+                mac_create_fresh(&k_e, id, data, freshness)
+            });
+            self.transmit(eid, &frame[..len]);
+        } else {
+            let mac = mac_create_fresh(&k_e, id, msg, freshness);
+            self.transmit(eid, msg);
+            self.transmit(
+                self.layout.build(id + 1, LeiACmd::Mac, counter),
+                &mac[CAN_PAYLOAD_SIZE..],
+            );
+        }
+    }
+
+    // Receives a data or MAC frame for the message on `msg_id`, authenticated
+    // using the freshness source.
+    fn leia_fresh_recv(&mut self, msg_id: u16, cmd: LeiACmd, counter: u16, msg: &[u8]) -> Event {
+        let freshness = match self.freshness.as_mut().unwrap().rx_freshness(
+            msg_id,
+            counter as u64,
+            self.layout.counter_bits(),
+            0,
+        ) {
+            Some(freshness) => freshness,
+            None => {
+                let failure = self.failure(msg_id, counter as u64, FailReason::FreshnessRejected);
+                return Event::Desync(failure);
+            }
+        };
+
+        let (k_e, mode) = {
+            let connection = self.find_connection(msg_id).unwrap();
+            (connection.k_e, connection.mode)
+        };
+
+        let ret = match (cmd, mode.combined()) {
+            (LeiACmd::Data, Some((mac_len, _))) => {
+                let verified = leia_combined_check(msg, mac_len, |data| {
+                    mac_create_fresh(&k_e, msg_id, data, freshness)
+                });
+
+                if let Some(data) = verified {
+                    Event::Authenticated(self.message(msg_id, data, freshness))
+                } else {
+                    Event::IncorrectMAC(self.failure(
+                        msg_id,
+                        freshness,
+                        combined_fail_reason(msg, mac_len),
+                    ))
+                }
+            }
+            (LeiACmd::Data, None) => {
+                let mut mac = [0; CAN_PAYLOAD_SIZE];
+                mac.copy_from_slice(
+                    &mac_create_fresh(&k_e, msg_id, msg, freshness)[CAN_PAYLOAD_SIZE..],
+                );
+
+                // The freshness value is only confirmed once the MAC frame arrives.
+                return self.add_pending(msg_id, mac, msg, freshness);
+            }
+            _ => match self.take_pending(msg_id) {
+                Some(pending) => {
+                    if pending.mac == msg {
+                        self.release_pending(msg_id, &pending)
+                    } else {
+                        Event::IncorrectMAC(self.failure(
+                            msg_id,
+                            freshness,
+                            FailReason::MacMismatch,
+                        ))
+                    }
+                }
+                None => {
+                    Event::UnexpectedMAC(self.failure(msg_id, freshness, FailReason::NoPendingData))
+                }
+            },
+        };
+
+        // Confirm the freshness value of the authenticated data, not the
+        // unauthenticated counter bits of a MAC frame.
+        if let Event::Authenticated(m) = ret {
+            let confirmed = self
+                .freshness
+                .as_mut()
+                .unwrap()
+                .rx_confirmation(msg_id, m.counter);
+
+            // A freshness value that can't be recorded could be replayed.
+            if confirmed.is_err() {
+                return Event::Desync(self.failure(msg_id, m.counter, FailReason::StoreFull));
+            }
+        }
+
+        ret
+    }
+
+    // Updates the health counters of the connection an event relates to.
+    fn record(&mut self, event: Event) -> Event {
+        if let Some((id, count)) = stats_counter(&event) {
+            if let Some(connection) = self.find_connection(id) {
+                count(&mut connection.stats);
+            }
+        }
+
+        event
+    }
+
+    // Builds a message received on `id` now.
+    fn message(&mut self, id: u16, data: &[u8], counter: u64) -> Message {
+        let epoch = self.find_connection(id).map_or(0, |c| c.epoch);
+
+        Message {
+            id: id,
+            data: Payload::new(data),
+            counter: counter,
+            epoch: epoch,
+            timestamp: self.now(),
+        }
+    }
+
+    // Builds the message of a pending data frame whose MAC has been verified.
+    fn pending_message(&mut self, id: u16, pending: &LeiAPending) -> Message {
+        let epoch = self.find_connection(id).map_or(0, |c| c.epoch);
+
+        Message {
+            id: id,
+            data: pending.data,
+            counter: pending.counter,
+            epoch: epoch,
+            timestamp: pending.timestamp,
+        }
+    }
+
+    // Hands a pending data frame whose MAC has been verified to the application.
+    fn release_pending(&mut self, id: u16, pending: &LeiAPending) -> Event {
+        let message = self.pending_message(id, pending);
+
+        if let Some(release) = self.release {
+            let elapsed = self.now().saturating_sub(pending.timestamp);
+            if self.timeout.map_or(false, |timeout| elapsed > timeout) {
+                return Event::MissingMAC(self.failure(id, pending.counter, FailReason::Timeout));
+            }
+
+            release(&message);
+        }
+
+        Event::Authenticated(message)
+    }
+
+    // Builds a failure for a frame received on `id` now.
+    fn failure(&self, id: u16, counter: u64, reason: FailReason) -> Failure {
+        Failure {
+            id: id,
+            counter: counter,
+            reason: reason,
+            timestamp: self.now(),
+        }
+    }
+
+    // Stores a data frame until its MAC frame arrives.
+    fn add_pending(&mut self, id: u16, mac: CANPayload, data: &[u8], counter: u64) -> Event {
+        let message = self.message(id, data, counter);
+
+        let pending = LeiAPending {
+            mac: mac,
+            data: message.data,
+            counter: counter,
+            timestamp: message.timestamp,
+        };
+
+        match self.expected.insert(id, pending) {
+            Ok(Some(_)) => Event::MissingMAC(self.failure(id, counter, FailReason::MacNotReceived)),
+            Ok(None) if self.release.is_some() => Event::Buffered(id),
+            Ok(None) => Event::Received(message),
+            Err(_) => Event::MissingMAC(self.failure(id, counter, FailReason::StoreFull)),
+        }
+    }
+
+    // Stores an AEC frame until its MAC frame arrives, without acting on it.
+    fn add_pending_aec(&mut self, id: u16, counter: u16, data: &[u8]) -> Event {
+        let aec_id = self.aec.id;
+
+        let mac = {
+            let connection = self.find_connection(id).unwrap();
+
+            // AUTH_FAIL frames are authenticated with the current session key
+            // of the AEC, new epochs with the session key of that epoch.
+            let k_e = if id == aec_id {
+                connection.k_e
+            } else {
+                session_key(&connection.k_i, LittleEndian::read_u64(data))
+            };
+
+            mac_create(&k_e, id, data, counter)
+        };
+
+        let pending = LeiAPending {
+            mac: mac,
+            data: Payload::new(data),
+            counter: counter as u64,
+            timestamp: self.now(),
+        };
+
+        let replaced = {
+            let connection = self.find_connection(id).unwrap();
+            mem::replace(&mut connection.aec_pending, Some(pending))
+        };
+
+        match replaced {
+            Some(_) => {
+                Event::MissingMAC(self.failure(id, counter as u64, FailReason::MacNotReceived))
+            }
+            None => Event::Buffered(id),
+        }
+    }
+
+    // Takes the data frame of `id` awaiting its MAC.
+    fn take_pending(&mut self, id: u16) -> Option<LeiAPending> {
+        self.expected.remove(&id)
+    }
+
+    // Takes the AEC frame of `id` awaiting its MAC.
+    fn take_pending_aec(&mut self, id: u16) -> Option<LeiAPending> {
+        self.find_connection(id)
+            .and_then(|connection| connection.aec_pending.take())
+    }
+
+    // Acts on an AEC frame whose MAC has been verified.
+    fn leia_aec_verified(&mut self, id: u16, pending: &LeiAPending) -> Event {
+        if id == self.aec.id {
+            // AUTH_FAIL: Respond with a new epoch for the failing id.
+            self.aec.c = pending.counter as u16;
+            update_counters(&mut self.aec);
+
+            let failing_id = LittleEndian::read_u16(&pending.data[6..]);
+            if failing_id != 0 && failing_id != id && self.find_connection(failing_id).is_some() {
+                self.leia_auth_fail_send_response(failing_id);
+            }
+
+            return Event::Debug(self.aec.epoch);
+        }
+
+        let epoch = LittleEndian::read_u64(&pending.data);
+        if !self.leia_auth_fail_receive(id, epoch) {
+            return Event::Desync(self.failure(id, epoch, FailReason::EpochRejected));
+        }
+
+        {
+            let connection = self.find_connection(id).unwrap();
+            connection.c = pending.counter as u16;
+            update_counters(connection);
+            connection.auth_fail_in_progress = false;
+        }
+        self.exhausted(id);
+
+        Event::Resynced(id)
+    }
+
+    // Passes a frame to the send function, or queues it if queueing is enabled.
+    fn transmit(&mut self, eid: u32, data: &[u8]) {
+        match self.queue {
+            Some(ref mut queue) => {
+                if queue.push((eid, Payload::new(data))).is_err() {
+                    self.dropped_frames += 1;
+                }
+            }
+            None => (self.send)(eid, data),
+        }
+    }
+
+    // Finds the connection with the specified id
+    fn find_connection(&mut self, id: u16) -> Option<&mut LeiAConnection> {
+        // @Cleanup: When Rust-sgx-sdk compiles with a newer version of rustc
+        // than 1.22 nightly, something like this could be used with option filter:
+        // self.connections.iter_mut()
+        //     .find(|ref x| x.id == id)
+        //     .or(Some(&mut self.aec).filter(|ref c| c.id == id))
+
+        let aec_id = self.aec.id;
+
+        // Unused connection slots are padded with id 0.
+        if id == 0 {
+            return None;
+        }
+
+        let connection_opt = self.connections.iter_mut().find(|ref x| x.id == id);
+
+        let aec_opt = if self.aec.id == id {
+            Some(&mut self.aec)
+        } else {
+            None
+        };
+
+        connection_opt.or(aec_opt)
+    }
+
+    // Checks whether connection `id` used up all epochs of its k_i, requesting a
+    // new k_i the first time.
+    fn exhausted(&mut self, id: u16) -> bool {
+        let request = match self.find_connection(id) {
+            Some(connection) if connection.epoch_exhausted => {
+                let request = !connection.rekey_requested;
+                connection.rekey_requested = true;
+                request
+            }
+            _ => return false,
+        };
+
+        if let (true, Some(rekey_request)) = (request, self.rekey_request) {
+            rekey_request(id);
+        }
+
+        true
+    }
+
+    // Calculate mac for message and put it in the map of expected messages
+    fn add_expected_msg(&mut self, id: u16, counter: u16, data: &[u8]) -> Event {
+        let mac = {
+            let connection = self.find_connection(id).unwrap();
+
+            // Set our counter to be the same as the incomming message.
+            // This allows us to deal with desyncs < 1 epoch.
+            connection.c = counter;
+
+            mac_create(&connection.k_e, id, data, counter)
+        };
+
+        self.add_pending(id, mac, data, counter as u64)
+    }
+}
+
+// Builds the single frame carrying data and truncated MAC of at most `max_len` bytes
+// into `frame` and returns its extended id and length.
+fn leia_combined_build(
+    frame: &mut CANFDPayload,
+    connection: &mut LeiAConnection,
+    msg: &[u8],
+    mac_len: usize,
+    max_len: usize,
+    layout: &EidLayout,
+) -> (u32, usize) {
+    let k_e = connection.k_e;
+    let id = connection.id;
+    let counter = connection.c;
+
+    let len = leia_combined_frame(frame, msg, mac_len, max_len, |data| {
+        mac_create_fd(&k_e, id, data, counter)
+    });
+
+    update_counters(connection);
+
+    (layout.build(id, LeiACmd::Data, counter), len)
+}
+
+// Builds a frame of at most `max_len` bytes carrying data and truncated MAC and
+// returns its length. `mac` computes the MAC over the (padded) data.
+fn leia_combined_frame<M>(
+    frame: &mut CANFDPayload,
+    msg: &[u8],
+    mac_len: usize,
+    max_len: usize,
+    mac: M,
+) -> usize
+where
+    M: FnOnce(&[u8]) -> [u8; LEIA_MAC_SIZE],
+{
+    // Frames longer than a classic frame may be padded, so they end their data
+    // with its length. Every length up to 8 is valid, so classic frames are never padded.
+    let classic = msg.len() + mac_len <= CAN_PAYLOAD_SIZE;
+    let needed = if classic {
+        msg.len() + mac_len
+    } else {
+        msg.len() + 1 + mac_len
+    };
+
+    assert!(
+        needed <= max_len,
+        "Message does not fit in a single frame together with its MAC."
+    );
+
+    let len = canfd_len(needed);
+    let mac_pos = len - mac_len;
+
+    frame[..msg.len()].copy_from_slice(msg);
+    if !classic {
+        frame[mac_pos - 1] = msg.len() as u8;
+    }
+
+    let mac = mac(&frame[..mac_pos]);
+    frame[mac_pos..len].copy_from_slice(&mac[..mac_len]);
+
+    len
+}
+
+// Returns the data of a frame carrying both data and truncated MAC, without padding.
+fn leia_combined_data(frame: &[u8], mac_len: usize) -> Option<&[u8]> {
+    if frame.len() < mac_len {
+        return None;
+    }
+
+    let mac_pos = frame.len() - mac_len;
+    if frame.len() <= CAN_PAYLOAD_SIZE {
+        return Some(&frame[..mac_pos]);
+    }
+
+    if mac_pos == 0 || frame[mac_pos - 1] as usize >= mac_pos {
+        return None;
+    }
+
+    Some(&frame[..frame[mac_pos - 1] as usize])
+}
+
+// Checks the truncated MAC at the end of a frame carrying both data and MAC and
+// returns the authenticated data.
+fn leia_combined_check<M>(frame: &[u8], mac_len: usize, mac: M) -> Option<&[u8]>
+where
+    M: FnOnce(&[u8]) -> [u8; LEIA_MAC_SIZE],
+{
+    let data = leia_combined_data(frame, mac_len)?;
+    let mac_pos = frame.len() - mac_len;
+
+    if mac(&frame[..mac_pos])[..mac_len] == frame[mac_pos..] {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+// Distinguishes malformed frames from frames with a wrong MAC.
+fn combined_fail_reason(frame: &[u8], mac_len: usize) -> FailReason {
+    if leia_combined_data(frame, mac_len).is_none() {
+        FailReason::Truncated
+    } else {
+        FailReason::MacMismatch
+    }
+}
+
+// Verifies a single frame carrying both data and truncated MAC and returns its data.
+fn leia_combined_verify<'a>(
+    connection: &mut LeiAConnection,
+    counter: u16,
+    frame: &'a [u8],
+    mac_len: usize,
+) -> Option<&'a [u8]> {
+    let k_e = connection.k_e;
+    let id = connection.id;
+
+    let data = leia_combined_check(frame, mac_len, |data| {
+        mac_create_fd(&k_e, id, data, counter)
+    });
+
+    if data.is_some() {
+        connection.c = counter;
+        update_counters(connection);
+    }
+
+    data
+}
+
+fn update_counters(connection: &mut LeiAConnection) {
+    if connection.c >= connection.c_max {
+        session_key_gen(connection);
+        if !connection.epoch_exhausted {
+            connection.stats.epoch_changes += 1;
+        }
+    } else {
+        connection.c += 1;
+    }
+}
+
+pub fn session_key_gen(cur: &mut LeiAConnection) {
+    // 0. Stop at the last epoch, a new k_i is needed
+    if cur.epoch >= LEIA_EPOCH_MAX {
+        cur.epoch_exhausted = true;
+        return;
+    }
+
+    // 1. Increment epoch
+    cur.epoch += 1;
+
+    // 2. Apply MAC algorithm on the epoch
+    cur.k_e = session_key(&cur.k_i, cur.epoch);
+
+    // 3. Reset counter
+    cur.c = 1;
+}
+
+// Derives the session key of `epoch` from k_i.
+fn session_key(k_i: &SancusKey, epoch: u64) -> SancusKey {
+    let mut epoch_buf = [0; 8];
+    LittleEndian::write_u64(&mut epoch_buf, epoch);
+
+    let mut k_e: SancusKey = Default::default();
+    k_e.copy_from_slice(&spongent_mac(k_i, &epoch_buf).unwrap());
+    k_e
+}
+
+// Writes counter and id to the header of the AD buffer.
+fn leia_ad_header(ad: &mut [u8], id: u16, counter: u16) {
+    // Write counter to AD buffer
+    LittleEndian::write_u16(&mut ad[0..2], counter);
 
     // Write id to AD buffer
-    LittleEndian::write_u16(&mut buf, id);
-    ad[2..4].copy_from_slice(&buf);
+    LittleEndian::write_u16(&mut ad[2..4], id);
+}
+
+// Writes counter, id and message to the AD buffer and returns the used length.
+fn leia_ad(ad: &mut [u8], id: u16, msg: &[u8], counter: u16) -> usize {
+    leia_ad_header(ad, id, counter);
 
     let msg_len = msg.len();
-    ad[4..4 + msg_len].copy_from_slice(&msg);
+    ad[LEIA_AD_HEADER_SIZE..LEIA_AD_HEADER_SIZE + msg_len].copy_from_slice(&msg);
+
+    LEIA_AD_HEADER_SIZE + msg_len
+}
+
+// TODO id and counter are from cur?
+pub fn mac_create(k_e: &SancusKey, id: u16, msg: &[u8], counter: u16) -> [u8; CAN_PAYLOAD_SIZE] {
+    assert!(
+        msg.len() <= CAN_PAYLOAD_SIZE,
+        "Messages longer than one frame need the ISO-TP transport."
+    );
+
+    let mut ad = [0; LEIA_AD_SIZE];
+    leia_ad(&mut ad, id, msg, counter);
 
     let mac = spongent_mac(k_e, &ad).unwrap();
     let mut truncated_mac = [0; CAN_PAYLOAD_SIZE];
@@ -267,10 +1495,41 @@ pub fn mac_create(k_e: &SancusKey, id: u16, msg: &[u8], counter: u16) -> [u8; CA
     truncated_mac
 }
 
+/// Creates the full-length MAC for a CAN FD message of up to 64 bytes. This MAC
+/// is also used, truncated, in single-frame mode.
+///
+/// Unlike [mac_create](fn.mac_create.html), the AD is not padded, so the
+/// message length is authenticated as well.
+pub fn mac_create_fd(k_e: &SancusKey, id: u16, msg: &[u8], counter: u16) -> [u8; LEIA_MAC_SIZE] {
+    let mut ad = [0; LEIA_FD_AD_SIZE];
+    let ad_len = leia_ad(&mut ad, id, msg, counter);
+
+    spongent_mac(k_e, &ad[..ad_len]).unwrap()
+}
+
+/// Creates the full-length MAC for a message of up to 64 bytes using a complete
+/// freshness value from a [FreshnessSource](trait.FreshnessSource.html).
+pub fn mac_create_fresh(
+    k_e: &SancusKey,
+    id: u16,
+    msg: &[u8],
+    freshness: u64,
+) -> [u8; LEIA_MAC_SIZE] {
+    let mut ad = [0; LEIA_FRESH_AD_SIZE];
+
+    LittleEndian::write_u64(&mut ad[0..8], freshness);
+    LittleEndian::write_u16(&mut ad[8..10], id);
+    ad[10..10 + msg.len()].copy_from_slice(msg);
+
+    spongent_mac(k_e, &ad[..10 + msg.len()]).unwrap()
+}
+
 /// Implements LeiA as a VulCAN context.
-impl<'a, S> VulCANContext for LeiAContext<S>
+impl<'a, S, F, T> VulCANContext for LeiAContext<S, F, T>
 where
-    S: VulCANStore<K = u16, V = [u8; CAN_PAYLOAD_SIZE]>,
+    S: VulCANStore<K = u16, V = LeiAPending>,
+    F: FreshnessSource,
+    T: Clock,
 {
     type ProtocolInfo = LeiAConnection;
 
@@ -286,113 +1545,178 @@ where
     }
 
     fn send(&mut self, id: u16, msg: &[u8]) {
-        (self.send)(id as u32, msg);
+        self.transmit(id as u32, msg);
     }
 
     fn auth_recv(&mut self, eid: u32, msg: &[u8]) -> Result<Event, ()> {
+        self.leia_auth_recv(eid, msg)
+            .map(|event| self.record(event))
+    }
+}
+
+impl<S, F, T> LeiAContext<S, F, T>
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    // Receives a frame, without updating the health counters.
+    fn leia_auth_recv(&mut self, eid: u32, msg: &[u8]) -> Result<Event, ()> {
         // @TODO @Cleanup: Unwrap handling
-        let (id, cmd, counter) = parse_eid(eid).ok_or(())?;
+        let (id, cmd, counter) = self.layout.parse(eid).ok_or(())?;
+
+        // MAC frames are sent on the id following the one of their data frame.
+        let msg_id = match cmd {
+            LeiACmd::Mac => id.checked_sub(1),
+            _ => Some(id),
+        };
 
         // @TODO: Also aec variants here?
-        if (self.find_connection(id).is_none() && cmd == LeiACmd::Data)
-            || (self.find_connection(id - 1).is_none() && cmd == LeiACmd::Mac)
-        {
-            return Ok(Event::UnknownId(id));
-        }
+        if cmd == LeiACmd::Data || cmd == LeiACmd::Mac {
+            let msg_id = match msg_id {
+                Some(msg_id) if self.find_connection(msg_id).is_some() => msg_id,
+                _ => {
+                    let failure = self.failure(id, counter as u64, FailReason::NotConfigured);
+                    return Ok(Event::UnknownId(failure));
+                }
+            };
 
-        let mut ret = Event::Received(id, None);
+            if self.freshness.is_some() {
+                if cmd == LeiACmd::Data && self.exhausted(msg_id) {
+                    let failure = self.failure(msg_id, counter as u64, FailReason::EpochExhausted);
+                    return Ok(Event::Desync(failure));
+                }
+
+                return Ok(self.leia_fresh_recv(msg_id, cmd, counter, msg));
+            }
+        }
 
-        // @TODO Cleanup with one in LeiACmd::Mac
-        let msg_id = if id == self.aec.id { id } else { id - 1 };
+        let ret;
 
         match cmd {
             LeiACmd::Data => {
-                let connection_counter = self.find_connection(id).unwrap().c;
+                let (connection_counter, mode) = {
+                    let connection = self.find_connection(id).unwrap();
+                    (connection.c, connection.mode)
+                };
+                if self.exhausted(id) {
+                    let failure = self.failure(id, counter as u64, FailReason::EpochExhausted);
+                    return Ok(Event::Desync(failure));
+                }
+
                 if counter < connection_counter {
-                    self.leia_auth_fail_send(id);
-                    return Ok(Event::Desync(id));
+                    self.leia_auth_fail_request(id);
+                    let failure = self.failure(id, counter as u64, FailReason::CounterTooOld);
+                    return Ok(Event::Desync(failure));
                 }
 
-                if self.expected.contains_key(&id) {
-                    let mac = self.add_expected_msg(id, counter, &msg);
-                    ret = Event::MissingMAC(id);
-                } else {
-                    let mac = self.add_expected_msg(id, counter, &msg);
-                    ret = Event::Received(id, Some(mac));
+                if let Some((mac_len, _)) = mode.combined() {
+                    let verified = {
+                        let connection = self.find_connection(id).unwrap();
+                        leia_combined_verify(connection, counter, msg, mac_len)
+                    };
+
+                    if let Some(data) = verified {
+                        return Ok(Event::Authenticated(self.message(id, data, counter as u64)));
+                    } else {
+                        self.leia_auth_fail_request(id);
+                        let reason = combined_fail_reason(msg, mac_len);
+                        return Ok(Event::IncorrectMAC(self.failure(
+                            id,
+                            counter as u64,
+                            reason,
+                        )));
+                    }
                 }
+
+                ret = self.add_expected_msg(id, counter, &msg);
             }
             LeiACmd::Mac => {
                 // @Temp @Hack: Accounting for bug in demo application log file.
                 // @Temp @Hack: msg_id should always be id - 1.
-                let msg_id = if self.expected.contains_key(&(id - 1)) {
-                    id - 1
-                } else {
-                    id
+                let msg_id = match msg_id {
+                    Some(msg_id) if self.expected.contains_key(&msg_id) => msg_id,
+                    _ => id,
                 };
-                if self.expected.contains_key(&msg_id) {
-                    if self.expected.get(&msg_id).unwrap() == msg {
-                        ret = Event::Authenticated(msg_id);
-
-                        // @TODO: Do this in update_counters?
-                        let connection = self.find_connection(msg_id).unwrap();
-                    // update_counters(connection);
-                    } else {
-                        ret = Event::IncorrectMAC(msg_id);
-
-                        self.leia_auth_fail_send(msg_id);
+                match self.take_pending(msg_id) {
+                    Some(pending) => {
+                        if pending.mac == msg {
+                            ret = self.release_pending(msg_id, &pending);
+                        } else {
+                            let failure =
+                                self.failure(msg_id, pending.counter, FailReason::MacMismatch);
+                            ret = Event::IncorrectMAC(failure);
+
+                            self.leia_auth_fail_request(msg_id);
+                        }
+                    }
+                    None => {
+                        let failure =
+                            self.failure(msg_id, counter as u64, FailReason::NoPendingData);
+                        ret = Event::UnexpectedMAC(failure);
                     }
-                } else {
-                    ret = Event::UnexpectedMAC(msg_id);
                 }
-
-                self.expected.remove(&msg_id);
             }
             LeiACmd::AecEpoch => {
-                // @TODO Only after MAC?
-                self.leia_auth_fail_receive(id, LittleEndian::read_u64(msg));
-                ret = Event::Resynced(id);
-                self.add_expected_msg(id, counter, &msg);
-            }
-            LeiACmd::AecMac => {
-                if self.expected.contains_key(&msg_id) {
-                    if self.expected.get(&msg_id).unwrap() == msg {
-                        ret = Event::Authenticated(msg_id);
-
-                        // @TODO @Cleanup
-                        let auth_fail_in_progress = {
-                            let connection = self.find_connection(msg_id).unwrap();
-                            update_counters(connection);
-
-                            connection.auth_fail_in_progress
-                        };
-
-                        if (!auth_fail_in_progress) {
-                            self.leia_auth_fail_send_response(LittleEndian::read_u16(&msg[6..]));
-                        }
-
-                        let debug = {
-                            let connection = self
-                                .find_connection(msg_id)
-                                .expect("No connection with specified id.");
-                            connection.auth_fail_in_progress = false;
-
-                            connection.epoch
-                        };
-
-                        ret = Event::Debug(debug);
-                    }
+                // AUTH_FAIL frames carry the epoch of the AEC and the failing id,
+                // responses the new epoch of the connection. Neither is acted on
+                // before its MAC has been verified.
+                let reason = if self.find_connection(id).is_none() {
+                    Some(FailReason::NotConfigured)
+                } else if msg.len() != CAN_PAYLOAD_SIZE {
+                    Some(FailReason::EpochRejected)
+                } else if id == self.aec.id && counter < self.aec.c {
+                    Some(FailReason::CounterTooOld)
                 } else {
-                    ret = Event::UnexpectedMAC(msg_id);
-                }
+                    None
+                };
 
-                self.expected.remove(&msg_id);
+                ret = match reason {
+                    Some(FailReason::NotConfigured) => Event::UnknownId(self.failure(
+                        id,
+                        counter as u64,
+                        FailReason::NotConfigured,
+                    )),
+                    Some(reason) => Event::Desync(self.failure(id, counter as u64, reason)),
+                    None => self.add_pending_aec(id, counter, msg),
+                };
             }
+            LeiACmd::AecMac => match self.take_pending_aec(id) {
+                Some(pending) => {
+                    if pending.mac == msg {
+                        ret = self.leia_aec_verified(id, &pending);
+                    } else {
+                        let failure = self.failure(id, pending.counter, FailReason::MacMismatch);
+                        ret = Event::IncorrectMAC(failure);
+                    }
+                }
+                None => {
+                    let failure = self.failure(id, counter as u64, FailReason::NoPendingData);
+                    ret = Event::UnexpectedMAC(failure);
+                }
+            },
         }
 
         Ok(ret)
     }
 }
 
+// Gets the connection an event relates to and the health counter it increments.
+// Suppressed AUTH_FAIL frames are counted when they're suppressed.
+fn stats_counter(event: &Event) -> Option<(u16, fn(&mut LeiAStats))> {
+    let counter: (u16, fn(&mut LeiAStats)) = match *event {
+        Event::Authenticated(ref m) => (m.id, |s| s.authenticated += 1),
+        Event::MissingMAC(ref f) => (f.id, |s| s.missing_macs += 1),
+        Event::UnexpectedMAC(ref f) => (f.id, |s| s.unexpected_macs += 1),
+        Event::IncorrectMAC(ref f) => (f.id, |s| s.incorrect_macs += 1),
+        Event::Desync(ref f) => (f.id, |s| s.desyncs += 1),
+        Event::Resynced(id) => (id, |s| s.resyncs += 1),
+        _ => return None,
+    };
+
+    Some(counter)
+}
+
 fn leia_auth_fail_response(cur: &mut LeiAConnection, epoch: u64) {
     if epoch <= cur.epoch {
         panic!("Received AUTH_FAIL epoch can't be smaller than current one.");
@@ -402,28 +1726,225 @@ fn leia_auth_fail_response(cur: &mut LeiAConnection, epoch: u64) {
     session_key_gen(cur);
 }
 
-fn parse_eid(eid: u32) -> Option<(u16, LeiACmd, u16)> {
-    let eid = eid & CAN_EFF_MASK;
-    if eid > 0x7FF {
-        let id: u16 = (eid >> 18) as u16;
-        let cmd: LeiACmd = ((eid >> 16) & LEIA_CMD_MASK).into();
-        let counter = (eid & 0xFFFF) as u16;
+/// Splits a LeiA extended identifier into the 11 bit id, LeiA command code and
+/// 16 bit counter value. Returns `None` for standard identifiers.
+pub fn parse_eid(eid: u32) -> Option<(u16, LeiACmd, u16)> {
+    EidLayout::default().parse(eid)
+}
+
+/// Placement of the id, command code and counter of LeiA frames in the 29 bit
+/// extended identifier.
+///
+/// The counter occupies the least significant bits, the 2 bit command code and
+/// the id are placed above it. Remaining bits are taken from the base identifier,
+/// e.g. to set a priority. The default layout packs an 11 bit id, the command code
+/// and a 16 bit counter into all 29 bits.
+///
+/// Ids are limited to 16 bits, as connections are identified by a `u16`. A context
+/// has a single layout, so all of its connections share one base: connections that
+/// need different bases, e.g. different priorities, need a context each.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EidLayout {
+    counter_bits: u32,
+    cmd_shift: u32,
+    id_shift: u32,
+    id_bits: u32,
+    base: u32,
+}
+
+impl EidLayout {
+    /// Creates a layout with a counter of `counter_bits`, the command code at bit
+    /// `cmd_shift` and an id of `id_bits` at bit `id_shift`.
+    pub fn new(counter_bits: u32, cmd_shift: u32, id_shift: u32, id_bits: u32) -> Self {
+        assert!(counter_bits > 0 && counter_bits <= 16);
+        assert!(id_bits > 0 && id_bits <= 16);
+        assert!(
+            cmd_shift < 32 && id_shift < 32,
+            "Fields of the layout don't fit in 29 bits."
+        );
+
+        let layout = Self {
+            counter_bits: counter_bits,
+            cmd_shift: cmd_shift,
+            id_shift: id_shift,
+            id_bits: id_bits,
+            base: 0,
+        };
+
+        let fields = layout.counter_mask() | layout.cmd_mask() | layout.id_mask();
+        let size = counter_bits + 2 + id_bits;
+        assert!(
+            fields.count_ones() == size && fields & !CAN_EFF_MASK == 0,
+            "Fields of the layout overlap or don't fit in 29 bits."
+        );
+
+        layout
+    }
+
+    /// Sets the bits of the identifier not used by id, command code and counter.
+    pub fn with_base(mut self, base: u32) -> Self {
+        let fields = self.counter_mask() | self.cmd_mask() | self.id_mask();
+        self.base = base & CAN_EFF_MASK & !fields;
+        self
+    }
+
+    /// Gets the largest counter value carried by the layout.
+    pub fn counter_max(&self) -> u16 {
+        self.counter_mask() as u16
+    }
+
+    /// Gets the number of counter bits carried by the layout.
+    pub fn counter_bits(&self) -> u32 {
+        self.counter_bits
+    }
+
+    /// Builds an extended identifier from an id, LeiA command code and counter
+    /// value. Counter bits beyond the layout are dropped.
+    pub fn build(&self, id: u16, cmd: LeiACmd, counter: u16) -> u32 {
+        let cmdu: u16 = cmd.into();
+
+        self.base
+            | ((id as u32) << self.id_shift) & self.id_mask()
+            | (cmdu as u32) << self.cmd_shift
+            | (counter as u32) & self.counter_mask()
+            | CAN_EFF_FLAG
+    }
+
+    /// Splits an extended identifier into id, LeiA command code and counter value.
+    /// Returns `None` for standard identifiers and identifiers with another base.
+    pub fn parse(&self, eid: u32) -> Option<(u16, LeiACmd, u16)> {
+        let eid = eid & CAN_EFF_MASK;
+        let fields = self.counter_mask() | self.cmd_mask() | self.id_mask();
+
+        if eid <= 0x7FF || eid & !fields != self.base {
+            return None;
+        }
+
+        let id = ((eid & self.id_mask()) >> self.id_shift) as u16;
+        let cmd: LeiACmd = ((eid >> self.cmd_shift) & LEIA_CMD_MASK).into();
+        let counter = (eid & self.counter_mask()) as u16;
 
         Some((id, cmd, counter))
-    } else {
-        None
+    }
+
+    fn counter_mask(&self) -> u32 {
+        (1 << self.counter_bits) - 1
+    }
+
+    fn cmd_mask(&self) -> u32 {
+        LEIA_CMD_MASK << self.cmd_shift
+    }
+
+    fn id_mask(&self) -> u32 {
+        ((1 << self.id_bits) - 1) << self.id_shift
     }
 }
 
+impl Default for EidLayout {
+    fn default() -> Self {
+        Self::new(16, 16, 18, 11)
+    }
+}
+
+/// Payload of a decoded LeiA frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LeiAPayload {
+    /// Application data, possibly followed by a truncated MAC in combined modes.
+    Data(Payload),
+    /// MAC of the preceding data frame.
+    Mac(Payload),
+    /// AUTH_FAIL sent on the authentication error channel, carrying the epoch of
+    /// the channel and the id of the failed connection.
+    AuthFail { epoch: u64, id: u16 },
+    /// Response to an AUTH_FAIL, carrying the new epoch of the connection.
+    Epoch(u64),
+    /// AEC frame whose payload is too short to be decoded.
+    Malformed(Payload),
+}
+
+/// LeiA frame decoded from its extended identifier and payload.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LeiAFrame {
+    pub id: u16,
+    pub cmd: LeiACmd,
+    pub counter: u16,
+    pub payload: LeiAPayload,
+}
+
+/// Decodes a LeiA frame, given the id of the authentication error channel and
+/// the layout of the extended identifiers.
+///
+/// Returns `None` for frames with a standard identifier.
+pub fn leia_decode(eid: u32, data: &[u8], aec_id: u16, layout: &EidLayout) -> Option<LeiAFrame> {
+    let (id, cmd, counter) = layout.parse(eid)?;
+
+    let payload = match cmd {
+        LeiACmd::Data => LeiAPayload::Data(Payload::new(data)),
+        LeiACmd::Mac | LeiACmd::AecMac => LeiAPayload::Mac(Payload::new(data)),
+        LeiACmd::AecEpoch if data.len() != CAN_PAYLOAD_SIZE => {
+            LeiAPayload::Malformed(Payload::new(data))
+        }
+        LeiACmd::AecEpoch if id == aec_id => LeiAPayload::AuthFail {
+            // The upper bytes of the epoch are replaced by the id.
+            epoch: LittleEndian::read_uint(&data[..6], 6),
+            id: LittleEndian::read_u16(&data[6..]),
+        },
+        LeiACmd::AecEpoch => LeiAPayload::Epoch(LittleEndian::read_u64(data)),
+    };
+
+    Some(LeiAFrame {
+        id: id,
+        cmd: cmd,
+        counter: counter,
+        payload: payload,
+    })
+}
+
+impl fmt::Display for LeiAFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:03X} {:?} #{}", self.id, self.cmd, self.counter)?;
+
+        match self.payload {
+            LeiAPayload::Data(ref data) => {
+                write!(f, " data ")?;
+                write_bytes(f, data)
+            }
+            LeiAPayload::Mac(ref mac) => {
+                write!(f, " mac ")?;
+                write_bytes(f, mac)
+            }
+            LeiAPayload::AuthFail { epoch, id } => {
+                write!(f, " AUTH_FAIL for 0x{:03X} (aec epoch {})", id, epoch)
+            }
+            LeiAPayload::Epoch(epoch) => write!(f, " new epoch {}", epoch),
+            LeiAPayload::Malformed(ref data) => {
+                write!(f, " malformed ")?;
+                write_bytes(f, data)
+            }
+        }
+    }
+}
+
+// Writes bytes as a list of hexadecimal values, as `{:02X?}` would on newer rustc.
+fn write_bytes(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    write!(f, "[")?;
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{:02X}", byte)?;
+    }
+    write!(f, "]")
+}
+
 /// Builds a LeiA extended identifier from the 11 bit id, a LeiA command code
 /// and 16 bit counter value.
-fn build_eid(id: u16, cmd: LeiACmd, counter: u16) -> u32 {
-    let cmdu: u16 = cmd.into();
-    (((id as u32) << 18 | (cmdu as u32) << 16) | counter as u32) | CAN_EFF_FLAG
+pub fn build_eid(id: u16, cmd: LeiACmd, counter: u16) -> u32 {
+    EidLayout::default().build(id, cmd, counter)
 }
 
 /// LeiA command codes.
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum LeiACmd {
     Data,
     Mac,
@@ -453,3 +1974,614 @@ impl From<LeiACmd> for u16 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    type TestContext = LeiAContext<ArrayStore<u16, LeiAPending>>;
+
+    fn context(connection: LeiAConnection) -> TestContext {
+        let connections = [connection.with_k_i(&[1; SANCUS_KEY_SIZE])];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[2; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new()).with_queue();
+        context.init();
+        context
+    }
+
+    #[derive(Copy, Clone)]
+    struct TestClock<'a>(&'a Cell<u64>);
+
+    impl<'a> Clock for TestClock<'a> {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    type ClockedContext<'a> = LeiAContext<ArrayStore<u16, LeiAPending>, NoFreshness, TestClock<'a>>;
+
+    fn clocked<'a>(connection: LeiAConnection, clock: &'a Cell<u64>) -> ClockedContext<'a> {
+        context(connection).with_clock(TestClock(clock))
+    }
+
+    // Passes a data frame of `sender` to `receiver` with a corrupted MAC frame.
+    fn corrupt_mac<S, T>(sender: &mut LeiAContext<S, NoFreshness, T>, receiver: &mut ClockedContext)
+    where
+        S: LeiAStore,
+        T: Clock,
+    {
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, _) = sender.pop_frame().unwrap();
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(eid_mac, &[0; CAN_PAYLOAD_SIZE]) {
+            Ok(Event::IncorrectMAC(_)) => {}
+            _ => panic!("Corrupted MAC accepted"),
+        }
+    }
+
+    // Counts the frames queued by `context`, dropping them.
+    fn sent_frames<S, T>(context: &mut LeiAContext<S, NoFreshness, T>) -> usize
+    where
+        S: LeiAStore,
+        T: Clock,
+    {
+        let mut count = 0;
+        while context.pop_frame().is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn combined_replay_is_rejected() {
+        for &connection in [
+            LeiAConnection::new(0x100).with_fd(8),
+            LeiAConnection::new(0x100).with_single_frame(4),
+        ]
+        .iter()
+        {
+            let mut sender = context(connection);
+            let mut receiver = context(connection);
+
+            sender.auth_send(0x100, &[1, 2, 3, 4]);
+            let (eid, frame) = sender.pop_frame().unwrap();
+
+            match receiver.auth_recv(eid, &frame) {
+                Ok(Event::Authenticated(m)) => assert_eq!(&m.data[..], &[1, 2, 3, 4]),
+                _ => panic!("Frame not authenticated"),
+            }
+            match receiver.auth_recv(eid, &frame) {
+                Ok(Event::Desync(failure)) => assert_eq!(failure.reason, FailReason::CounterTooOld),
+                _ => panic!("Replayed frame accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn single_frame_tampering_is_rejected() {
+        let connection = LeiAConnection::new(0x100).with_single_frame(4);
+        let mut sender = context(connection);
+        let mut receiver = context(connection);
+
+        sender.auth_send(0x100, &[1, 2, 3, 4]);
+        let (eid, frame) = sender.pop_frame().unwrap();
+        assert_eq!(frame.len(), CAN_PAYLOAD_SIZE);
+
+        let mut tampered = [0; CAN_PAYLOAD_SIZE];
+        tampered.copy_from_slice(&frame);
+        tampered[0] ^= 1;
+
+        match receiver.auth_recv(eid, &tampered) {
+            Ok(Event::IncorrectMAC(failure)) => assert_eq!(failure.reason, FailReason::MacMismatch),
+            _ => panic!("Tampered frame accepted"),
+        }
+        match receiver.auth_recv(eid, &frame[..3]) {
+            Ok(Event::IncorrectMAC(failure)) => assert_eq!(failure.reason, FailReason::Truncated),
+            _ => panic!("Truncated frame accepted"),
+        }
+    }
+
+    #[test]
+    fn fresh_mac_counter_is_not_confirmed() {
+        let connection = LeiAConnection::new(0x100);
+        let mut sender =
+            context(connection).with_freshness(CounterFreshness::new(ArrayStore::new()));
+        let mut receiver =
+            context(connection).with_freshness(CounterFreshness::new(ArrayStore::new()));
+
+        // The counter bits of a MAC frame are not authenticated.
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (_, mac) = sender.pop_frame().unwrap();
+        let forged = EidLayout::default().build(0x101, LeiACmd::Mac, 0xFFFF);
+
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(forged, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(m.counter, 1),
+            _ => panic!("Message not authenticated"),
+        }
+
+        sender.auth_send(0x100, &[2]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(m.counter, 2),
+            _ => panic!("Next message rejected"),
+        }
+    }
+
+    #[test]
+    fn id_zero_is_unknown() {
+        let mut context = context(LeiAConnection::new(0x100));
+
+        for &cmd in [LeiACmd::Data, LeiACmd::Mac, LeiACmd::AecEpoch].iter() {
+            let eid = EidLayout::default().build(0, cmd, 0x1000);
+            match context.auth_recv(eid, &[0; CAN_PAYLOAD_SIZE]) {
+                Ok(Event::UnknownId(failure)) => assert_eq!(failure.id, 0),
+                _ => panic!("Frame on id 0 accepted"),
+            }
+        }
+
+        match context.auth_recv(0x00010000, &[0]) {
+            Ok(Event::UnknownId(_)) => {}
+            _ => panic!("Frame on id 0 accepted"),
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_frames() {
+        let mut context = context(LeiAConnection::new(0x100));
+
+        // Every classic message takes two frames.
+        for _ in 0..17 {
+            context.auth_send(0x100, &[1]);
+        }
+        assert_eq!(context.dropped_frames(), 2);
+
+        let mut queued = 0;
+        while context.pop_frame().is_some() {
+            queued += 1;
+        }
+        assert_eq!(queued, 32);
+    }
+
+    #[test]
+    fn full_freshness_store_rejects_messages() {
+        let mut full = ArrayStore::new();
+        for id in 1..17 {
+            full.insert(id, 0).ok().unwrap();
+        }
+
+        let connection = LeiAConnection::new(0x100);
+        let mut sender =
+            context(connection).with_freshness(CounterFreshness::new(ArrayStore::new()));
+        let mut receiver = context(connection).with_freshness(CounterFreshness::new(full));
+
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Desync(failure)) => assert_eq!(failure.reason, FailReason::StoreFull),
+            _ => panic!("Unrecorded freshness value accepted"),
+        }
+
+        // Nothing is sent with a freshness value that can't be recorded.
+        let mut sender = context(connection).with_freshness(CounterFreshness::new(full));
+        sender.auth_send(0x100, &[1]);
+        assert!(sender.pop_frame().is_none());
+    }
+
+    #[test]
+    fn fd_padding_is_not_authenticated_data() {
+        let connection = LeiAConnection::new(0x100).with_fd(8);
+        let mut sender = context(connection);
+        let mut receiver = context(connection);
+
+        for len in 0..connection.mode().max_data_len() + 1 {
+            let msg = [0xAA; CAN_FD_PAYLOAD_SIZE];
+            sender.auth_send(0x100, &msg[..len]);
+            let (eid, frame) = sender.pop_frame().unwrap();
+            assert_eq!(frame.len(), canfd_len(frame.len()));
+
+            match receiver.auth_recv(eid, &frame) {
+                Ok(Event::Authenticated(m)) => assert_eq!(&m.data[..], &msg[..len]),
+                _ => panic!("Frame not authenticated"),
+            }
+        }
+    }
+
+    #[test]
+    fn forged_epoch_has_no_effect() {
+        let mut context = context(LeiAConnection::new(0x100));
+        let epoch = context.connections().next().unwrap().epoch();
+
+        let mut msg = [0; CAN_PAYLOAD_SIZE];
+        LittleEndian::write_u64(&mut msg, epoch + 1);
+        let eid = EidLayout::default().build(0x100, LeiACmd::AecEpoch, 1);
+        let eid_mac = EidLayout::default().build(0x100, LeiACmd::AecMac, 1);
+
+        match context.auth_recv(eid, &msg) {
+            Ok(Event::Buffered(0x100)) => {}
+            _ => panic!("Epoch frame not buffered"),
+        }
+        assert_eq!(context.connections().next().unwrap().epoch(), epoch);
+
+        match context.auth_recv(eid_mac, &[0; CAN_PAYLOAD_SIZE]) {
+            Ok(Event::IncorrectMAC(failure)) => assert_eq!(failure.reason, FailReason::MacMismatch),
+            _ => panic!("Forged epoch accepted"),
+        }
+        assert_eq!(context.connections().next().unwrap().epoch(), epoch);
+    }
+
+    #[test]
+    fn replayed_epoch_is_rejected() {
+        let connection = LeiAConnection::new(0x100);
+        let mut sender = context(connection);
+        let mut receiver = context(connection);
+
+        sender.leia_auth_fail_send_response(0x100);
+        let (eid, msg) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &msg).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Resynced(0x100)) => {}
+            _ => panic!("Epoch not accepted"),
+        }
+
+        receiver.auth_recv(eid, &msg).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Desync(failure)) => assert_eq!(failure.reason, FailReason::EpochRejected),
+            _ => panic!("Replayed epoch accepted"),
+        }
+    }
+
+    #[test]
+    fn pending_frames_are_not_evicted() {
+        let connection = LeiAConnection::new(0x100);
+        let mut sender = context(connection);
+        let mut receiver = context(connection);
+        let forged_epoch = EidLayout::default().build(0x100, LeiACmd::AecEpoch, 1);
+        let forged_data = EidLayout::default().build(0x100, LeiACmd::Data, 1);
+
+        // An unverified epoch doesn't displace a data frame...
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &data).unwrap();
+        receiver
+            .auth_recv(forged_epoch, &[0xFF; CAN_PAYLOAD_SIZE])
+            .unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(&m.data[..], &[1]),
+            _ => panic!("Data frame evicted"),
+        }
+
+        // ...and an unverified data frame doesn't displace an epoch.
+        sender.leia_auth_fail_send_response(0x100);
+        let (eid, msg) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &msg).unwrap();
+        receiver.auth_recv(forged_data, &[0xFF]).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Resynced(0x100)) => {}
+            _ => panic!("Epoch frame evicted"),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn layout_shift_out_of_range() {
+        EidLayout::new(4, 40, 6, 11);
+    }
+
+    static RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+    fn release(message: &Message) {
+        assert_eq!(&message.data[..], &[1, 2]);
+        RELEASED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn buffered_data_waits_for_mac() {
+        let connection = LeiAConnection::new(0x100);
+        let mut sender = context(connection);
+        let mut receiver = context(connection).with_buffering(release, 100);
+
+        sender.auth_send(0x100, &[1, 2]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+
+        match receiver.auth_recv(eid, &data) {
+            Ok(Event::Buffered(0x100)) => {}
+            _ => panic!("Data not buffered"),
+        }
+        assert_eq!(RELEASED.load(Ordering::SeqCst), 0);
+
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(&m.data[..], &[1, 2]),
+            _ => panic!("Data not authenticated"),
+        }
+        assert_eq!(RELEASED.load(Ordering::SeqCst), 1);
+
+        // Data whose MAC doesn't match is dropped without being released.
+        sender.auth_send(0x100, &[3]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, _) = sender.pop_frame().unwrap();
+
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(eid_mac, &[0; CAN_PAYLOAD_SIZE]) {
+            Ok(Event::IncorrectMAC(_)) => {}
+            _ => panic!("Forged MAC accepted"),
+        }
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::UnexpectedMAC(_)) => {}
+            _ => panic!("Dropped data released"),
+        }
+        assert_eq!(RELEASED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn stats_count_poll_events() {
+        let connection = LeiAConnection::new(0x100);
+        let clock = Cell::new(0);
+        let mut sender = context(connection);
+        let mut receiver = clocked(connection, &clock)
+            .with_mac_timeout(10)
+            .with_auth_fail_retry(5, 1);
+
+        // The second AUTH_FAIL is suppressed while waiting for the new epoch.
+        for _ in 0..2 {
+            sender.auth_send(0x100, &[1]);
+            let (eid, data) = sender.pop_frame().unwrap();
+            let (eid_mac, _) = sender.pop_frame().unwrap();
+            receiver.auth_recv(eid, &data).unwrap();
+            receiver.auth_recv(eid_mac, &[0; CAN_PAYLOAD_SIZE]).unwrap();
+        }
+        let (eid_fail, fail) = receiver.pop_frame().unwrap();
+        let (eid_fail_mac, fail_mac) = receiver.pop_frame().unwrap();
+
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        sender.pop_frame().unwrap();
+        receiver.auth_recv(eid, &data).unwrap();
+
+        let mut events = 0;
+        clock.set(11);
+        receiver.poll(|_| events += 1);
+        assert_eq!(events, 3);
+
+        sender.auth_recv(eid_fail, &fail).unwrap();
+        sender.auth_recv(eid_fail_mac, &fail_mac).unwrap();
+        while let Some((eid, data)) = sender.pop_frame() {
+            receiver.auth_recv(eid, &data).unwrap();
+        }
+
+        let stats = receiver.stats(0x100).unwrap();
+        assert_eq!(stats.incorrect_macs, 2);
+        assert_eq!(stats.missing_macs, 1);
+        assert_eq!(stats.auth_fail_sent, 2);
+        assert_eq!(stats.auth_fail_suppressed, 1);
+        assert_eq!(stats.desyncs, 1);
+        assert_eq!(stats.resyncs, 1);
+    }
+
+    #[test]
+    fn poll_expires_missing_macs() {
+        let connection = LeiAConnection::new(0x100);
+        let clock = Cell::new(0);
+        let mut sender = context(connection);
+        let mut receiver = clocked(connection, &clock).with_mac_timeout(10);
+
+        sender.auth_send(0x100, &[1]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+        clock.set(5);
+        receiver.auth_recv(eid, &data).unwrap();
+
+        let mut events = 0;
+        clock.set(15);
+        receiver.poll(|_| events += 1);
+        assert_eq!(events, 0);
+
+        clock.set(16);
+        receiver.poll(|event| match event {
+            Event::MissingMAC(failure) => {
+                assert_eq!(failure.id, 0x100);
+                assert_eq!(failure.reason, FailReason::Timeout);
+                assert_eq!(failure.timestamp, 16);
+                events += 1;
+            }
+            _ => panic!("Unexpected event {:?}", event),
+        });
+        assert_eq!(events, 1);
+
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::UnexpectedMAC(_)) => {}
+            _ => panic!("Expired data frame authenticated"),
+        }
+        assert_eq!(receiver.next_timeout(), None);
+    }
+
+    #[test]
+    fn poll_retries_auth_fail_with_backoff() {
+        let connection = LeiAConnection::new(0x100);
+        let clock = Cell::new(0);
+        let mut sender = context(connection);
+        let mut receiver = clocked(connection, &clock).with_auth_fail_retry(5, 2);
+
+        corrupt_mac(&mut sender, &mut receiver);
+        assert_eq!(sent_frames(&mut receiver), 2);
+
+        // Retried after 5, 10 and 20 time units, giving up after two retries.
+        let mut desyncs = 0;
+        for &(now, retried, total) in [
+            (5, false, 0),
+            (6, true, 1),
+            (16, false, 1),
+            (17, true, 2),
+            (37, false, 2),
+            (38, false, 3),
+            (100, false, 3),
+        ]
+        .iter()
+        {
+            clock.set(now);
+            receiver.poll(|event| match event {
+                Event::Desync(failure) => {
+                    assert_eq!(failure.reason, FailReason::ResyncTimeout);
+                    desyncs += 1;
+                }
+                _ => panic!("Unexpected event {:?}", event),
+            });
+            assert_eq!(desyncs, total);
+            assert_eq!(sent_frames(&mut receiver), if retried { 2 } else { 0 });
+        }
+
+        assert!(!receiver
+            .connections()
+            .next()
+            .unwrap()
+            .auth_fail_in_progress());
+        assert_eq!(receiver.stats(0x100).unwrap().auth_fail_sent, 3);
+
+        // A new failure starts over.
+        corrupt_mac(&mut sender, &mut receiver);
+        assert_eq!(sent_frames(&mut receiver), 2);
+    }
+
+    #[test]
+    fn auth_fail_rate_limit_suppresses_and_reports() {
+        let connection = LeiAConnection::new(0x100);
+        let clock = Cell::new(0);
+        let mut sender = context(connection);
+        let mut receiver = clocked(connection.with_auth_fail_interval(10), &clock);
+
+        corrupt_mac(&mut sender, &mut receiver);
+        assert_eq!(sent_frames(&mut receiver), 2);
+
+        // Within the interval the AUTH_FAIL is counted instead of sent.
+        clock.set(5);
+        corrupt_mac(&mut sender, &mut receiver);
+        assert_eq!(sent_frames(&mut receiver), 0);
+        assert_eq!(receiver.stats(0x100).unwrap().auth_fail_suppressed, 1);
+
+        let mut reports = 0;
+        receiver.poll(|event| match event {
+            Event::AuthFailSuppressed(id, count) => {
+                assert_eq!((id, count), (0x100, 1));
+                reports += 1;
+            }
+            _ => panic!("Unexpected event {:?}", event),
+        });
+        receiver.poll(|_| reports += 1);
+        assert_eq!(reports, 1);
+
+        clock.set(10);
+        corrupt_mac(&mut sender, &mut receiver);
+        assert_eq!(sent_frames(&mut receiver), 2);
+        assert_eq!(receiver.stats(0x100).unwrap().auth_fail_sent, 2);
+    }
+
+    static REKEY_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn rekey_request(id: u16) {
+        assert_eq!(id, 0x100);
+        REKEY_REQUESTS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Moves connection 0x100 of `context` to the last epoch.
+    fn last_epoch<S, F, T>(context: &mut LeiAContext<S, F, T>)
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        assert!(context.leia_auth_fail_receive(0x100, LEIA_EPOCH_MAX));
+    }
+
+    // Uses up the last epoch of connection 0x100 of `context`.
+    fn exhaust<S, F, T>(context: &mut LeiAContext<S, F, T>)
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        last_epoch(context);
+        context.leia_auth_fail_send_response(0x100);
+        assert!(context.connections().next().unwrap().epoch_exhausted());
+        assert!(context.pop_frame().is_none());
+    }
+
+    #[test]
+    fn exhausted_epochs_stop_traffic_and_request_rekey() {
+        let connection = LeiAConnection::new(0x100).with_max_epoch_jump(!0);
+        let mut sender = context(connection).with_rekey_request(rekey_request);
+        let mut receiver = context(connection).with_rekey_request(rekey_request);
+        let mut other = context(connection);
+
+        let requests = REKEY_REQUESTS.load(Ordering::SeqCst);
+        exhaust(&mut sender);
+        exhaust(&mut receiver);
+        last_epoch(&mut other);
+        assert_eq!(REKEY_REQUESTS.load(Ordering::SeqCst), requests + 2);
+
+        sender.auth_send(0x100, &[1]);
+        assert!(sender.pop_frame().is_none());
+
+        other.auth_send(0x100, &[1]);
+        let (eid, data) = other.pop_frame().unwrap();
+        match receiver.auth_recv(eid, &data) {
+            Ok(Event::Desync(failure)) => assert_eq!(failure.reason, FailReason::EpochExhausted),
+            _ => panic!("Frame accepted on an exhausted connection"),
+        }
+
+        // The rekey hook is only called once per exhaustion.
+        assert_eq!(REKEY_REQUESTS.load(Ordering::SeqCst), requests + 2);
+
+        sender.rekey(0x100, &[3; SANCUS_KEY_SIZE]);
+        receiver.rekey(0x100, &[3; SANCUS_KEY_SIZE]);
+        sender.auth_send(0x100, &[2]);
+        let (eid, data) = sender.pop_frame().unwrap();
+        let (eid_mac, mac) = sender.pop_frame().unwrap();
+        receiver.auth_recv(eid, &data).unwrap();
+        match receiver.auth_recv(eid_mac, &mac) {
+            Ok(Event::Authenticated(m)) => assert_eq!(m.epoch, 1),
+            _ => panic!("Message after rekey rejected"),
+        }
+    }
+
+    #[test]
+    fn exhausted_epochs_stop_fresh_traffic() {
+        let connection = LeiAConnection::new(0x100).with_max_epoch_jump(!0);
+        let fresh = || context(connection).with_freshness(CounterFreshness::new(ArrayStore::new()));
+        let mut sender = fresh();
+        let mut receiver = fresh();
+        let mut other = fresh();
+
+        exhaust(&mut sender);
+        exhaust(&mut receiver);
+        last_epoch(&mut other);
+
+        sender.auth_send(0x100, &[1]);
+        assert!(sender.pop_frame().is_none());
+
+        other.auth_send(0x100, &[1]);
+        let (eid, data) = other.pop_frame().unwrap();
+        match receiver.auth_recv(eid, &data) {
+            Ok(Event::Desync(failure)) => assert_eq!(failure.reason, FailReason::EpochExhausted),
+            _ => panic!("Fresh frame accepted on an exhausted connection"),
+        }
+    }
+}
@@ -4,8 +4,55 @@
 extern crate byteorder;
 pub extern crate spongent;
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "async")]
+extern crate futures_core;
+
+#[cfg(feature = "embedded")]
+extern crate embedded_can;
+#[cfg(feature = "embedded")]
+extern crate nb;
+
 mod vulcan;
 pub use vulcan::*;
 
 mod leia;
 pub use leia::*;
+
+mod isotp;
+pub use isotp::*;
+
+mod freshness;
+pub use freshness::*;
+
+mod secoc;
+pub use secoc::*;
+
+mod gateway;
+pub use gateway::*;
+
+mod j1939;
+pub use j1939::*;
+
+mod uds;
+pub use uds::*;
+
+mod store;
+pub use store::*;
+
+mod ids;
+pub use ids::*;
+
+#[cfg(feature = "async")]
+mod async_leia;
+#[cfg(feature = "async")]
+pub use async_leia::*;
+
+#[cfg(feature = "embedded")]
+mod embedded;
+#[cfg(feature = "embedded")]
+pub use embedded::*;
@@ -0,0 +1,438 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use freshness::*;
+use vulcan::*;
+
+use core::marker::PhantomData;
+
+// SecOC calls a freshness source a Freshness Value Manager.
+pub use freshness::FreshnessSource as FreshnessValueManager;
+
+const SECOC_FV_SIZE: usize = 8;
+const SECOC_AD_SIZE: usize = 2 + CAN_FD_PAYLOAD_SIZE + SECOC_FV_SIZE;
+
+/// Number of freshness values tried before verification of a PDU fails.
+const SECOC_VERIFICATION_ATTEMPTS: u8 = 2;
+
+/// Configuration of a secured PDU.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SecOCPdu {
+    id: u16,
+    data_id: u16,
+    key: SancusKey,
+    freshness_len: usize,
+    mac_len: usize,
+}
+
+impl SecOCPdu {
+    /// Creates a new secured PDU sent on `id`, using `id` as Data ID.
+    ///
+    /// By default, the PDU carries one byte of truncated freshness and a three
+    /// byte truncated MAC (SecOC profile 1).
+    pub fn new(id: u16) -> Self {
+        Self {
+            id: id,
+            data_id: id,
+            key: Default::default(),
+            freshness_len: 1,
+            mac_len: 3,
+        }
+    }
+
+    /// Sets the key used to authenticate this PDU.
+    pub fn with_key(mut self, key: &[u8]) -> Self {
+        self.key.copy_from_slice(key);
+        self
+    }
+
+    /// Sets the Data ID included in the authenticator.
+    pub fn with_data_id(mut self, data_id: u16) -> Self {
+        self.data_id = data_id;
+        self
+    }
+
+    /// Sets the number of freshness value bytes carried in the PDU.
+    pub fn with_freshness_len(mut self, freshness_len: usize) -> Self {
+        assert!(freshness_len <= SECOC_FV_SIZE);
+        self.freshness_len = freshness_len;
+        self
+    }
+
+    /// Sets the number of MAC bytes carried in the PDU.
+    pub fn with_mac_len(mut self, mac_len: usize) -> Self {
+        assert!(mac_len > 0 && mac_len <= SANCUS_KEY_SIZE);
+        self.mac_len = mac_len;
+        self
+    }
+
+    /// Gets the id of the PDU.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+}
+
+/// Outcome of the verification of a secured PDU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SecOCVerificationResult {
+    /// The MAC was verified with an acceptable freshness value.
+    Success,
+    /// No freshness value resulted in a correct MAC.
+    VerificationFailure,
+    /// The Freshness Value Manager did not provide an acceptable freshness value.
+    FreshnessFailure,
+}
+
+/// Freshness Value Manager using a monotonic counter per id, see
+/// [CounterFreshness](struct.CounterFreshness.html).
+pub type CounterFvm<S> = CounterFreshness<S>;
+
+/// Structure managing multiple AUTOSAR SecOC secured PDUs on a single node.
+///
+/// A secured PDU carries the authentic data followed by the truncated freshness
+/// value and the truncated MAC. The MAC is computed over the Data ID, the
+/// authentic data and the complete freshness value.
+///
+/// Secured PDUs are sent and received on standard 11 bit ids. Received frames are
+/// timestamped by a [Clock](trait.Clock.html), by default a plain function set
+/// with [with_time](#method.with_time).
+pub struct SecOCContext<F, M = SpongentMac, T = fn() -> u64>
+where
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    pdus: [SecOCPdu; 16],
+    fvm: F,
+    send: fn(u32, &[u8]),
+    clock: T,
+    mac: PhantomData<M>,
+}
+
+impl<F, M> SecOCContext<F, M>
+where
+    F: FreshnessSource,
+    M: VulCANMac,
+{
+    /// Creates a new SecOC context.
+    ///
+    /// # Parameters
+    ///
+    /// - `pdus` - A list of [SecOCPdu](struct.SecOCPdu.html) to be managed by the context.
+    /// - `fvm` - A structure implementing [FreshnessSource](trait.FreshnessSource.html),
+    ///   acting as Freshness Value Manager.
+    pub fn new(pdus: &[SecOCPdu], fvm: F) -> Self {
+        // @Cleanup @Hardcode: Same limit as LeiAContext.
+        let mut ps = [SecOCPdu::new(0); 16];
+        ps[..pdus.len()].copy_from_slice(pdus);
+        Self {
+            pdus: ps,
+            fvm: fvm,
+            send: |_, _| {},
+            clock: || 0,
+            mac: PhantomData,
+        }
+    }
+}
+
+impl<F, M, T> SecOCContext<F, M, T>
+where
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    /// Sets the function to be used by the context to send messages.
+    pub fn with_send(mut self, send: fn(u32, &[u8])) -> Self {
+        self.send = send;
+        self
+    }
+
+    /// Sets the function to be used by the context to timestamp received frames.
+    pub fn with_time(self, time: fn() -> u64) -> SecOCContext<F, M, fn() -> u64> {
+        self.with_clock(time)
+    }
+
+    /// Sets the clock to be used by the context to timestamp received frames.
+    pub fn with_clock<C>(self, clock: C) -> SecOCContext<F, M, C>
+    where
+        C: Clock,
+    {
+        SecOCContext {
+            pdus: self.pdus,
+            fvm: self.fvm,
+            send: self.send,
+            clock: clock,
+            mac: PhantomData,
+        }
+    }
+
+    /// Gets the Freshness Value Manager of the context.
+    pub fn fvm(&mut self) -> &mut F {
+        &mut self.fvm
+    }
+
+    /// Verifies a secured PDU received on `id`.
+    pub fn verify(&mut self, id: u16, msg: &[u8]) -> Option<SecOCVerificationResult> {
+        self.verify_pdu(id, msg).map(|(result, _, _)| result)
+    }
+
+    // Verifies a secured PDU, also returning the freshness value and length of
+    // the authentic data.
+    fn verify_pdu(&mut self, id: u16, msg: &[u8]) -> Option<(SecOCVerificationResult, u64, usize)> {
+        let pdu = *self.find_pdu(id)?;
+
+        let data_len = match msg.len().checked_sub(pdu.freshness_len + pdu.mac_len) {
+            Some(len) => len,
+            None => return Some((SecOCVerificationResult::VerificationFailure, 0, 0)),
+        };
+        let mac_pos = data_len + pdu.freshness_len;
+
+        let truncated = if pdu.freshness_len > 0 {
+            BigEndian::read_uint(&msg[data_len..mac_pos], pdu.freshness_len)
+        } else {
+            0
+        };
+        let bits = (pdu.freshness_len * 8) as u32;
+
+        for attempt in 0..SECOC_VERIFICATION_ATTEMPTS {
+            let freshness = match self.fvm.rx_freshness(id, truncated, bits, attempt) {
+                Some(freshness) => freshness,
+                None => {
+                    return Some((
+                        SecOCVerificationResult::FreshnessFailure,
+                        truncated,
+                        data_len,
+                    ))
+                }
+            };
+
+            let mac = secoc_mac::<M>(&pdu, &msg[..data_len], freshness);
+            if mac[..pdu.mac_len] == msg[mac_pos..] {
+                // A freshness value that can't be recorded could be replayed.
+                if self.fvm.rx_confirmation(id, freshness).is_err() {
+                    return Some((
+                        SecOCVerificationResult::FreshnessFailure,
+                        freshness,
+                        data_len,
+                    ));
+                }
+                return Some((SecOCVerificationResult::Success, freshness, data_len));
+            }
+        }
+
+        Some((
+            SecOCVerificationResult::VerificationFailure,
+            truncated,
+            data_len,
+        ))
+    }
+
+    // Writes the secured PDU of `msg` to `frame` and returns its length, unless
+    // its freshness value can't be recorded.
+    fn secure(&mut self, id: u16, msg: &[u8], frame: &mut [u8]) -> Option<usize> {
+        let pdu = *self.find_pdu(id).unwrap();
+
+        let len = msg.len() + pdu.freshness_len + pdu.mac_len;
+        assert!(
+            len <= CAN_FD_PAYLOAD_SIZE,
+            "Message does not fit in a frame together with freshness and MAC."
+        );
+
+        let freshness = self.fvm.tx_freshness(id);
+        let mac = secoc_mac::<M>(&pdu, msg, freshness);
+
+        frame[..msg.len()].copy_from_slice(msg);
+
+        let mac_pos = msg.len() + pdu.freshness_len;
+        if pdu.freshness_len > 0 {
+            BigEndian::write_uint(
+                &mut frame[msg.len()..mac_pos],
+                freshness & (::core::u64::MAX >> (64 - pdu.freshness_len * 8)),
+                pdu.freshness_len,
+            );
+        }
+        frame[mac_pos..len].copy_from_slice(&mac[..pdu.mac_len]);
+
+        self.fvm.tx_confirmation(id, freshness).ok().map(|_| len)
+    }
+
+    // Finds the PDU with the specified id
+    fn find_pdu(&self, id: u16) -> Option<&SecOCPdu> {
+        self.pdus.iter().find(|p| p.id == id && id != 0)
+    }
+}
+
+// Computes the authenticator over Data ID, authentic data and freshness value.
+fn secoc_mac<M: VulCANMac>(pdu: &SecOCPdu, data: &[u8], freshness: u64) -> [u8; SANCUS_KEY_SIZE] {
+    let mut ad = [0; SECOC_AD_SIZE];
+    BigEndian::write_u16(&mut ad[0..2], pdu.data_id);
+    ad[2..2 + data.len()].copy_from_slice(data);
+    let ad_len = 2 + data.len() + SECOC_FV_SIZE;
+    BigEndian::write_u64(&mut ad[2 + data.len()..ad_len], freshness);
+
+    M::mac(&pdu.key, &ad[..ad_len])
+}
+
+/// Implements AUTOSAR SecOC as a VulCAN context.
+impl<F, M, T> VulCANContext for SecOCContext<F, M, T>
+where
+    F: FreshnessSource,
+    M: VulCANMac,
+    T: Clock,
+{
+    type ProtocolInfo = SecOCPdu;
+
+    fn init(&mut self) {}
+
+    fn auth_send(&mut self, id: u16, msg: &[u8]) {
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+
+        if let Some(len) = self.secure(id, msg, &mut frame) {
+            (self.send)(id as u32, &frame[..len]);
+        }
+    }
+
+    fn send(&mut self, id: u16, msg: &[u8]) {
+        (self.send)(id as u32, msg);
+    }
+
+    fn auth_recv(&mut self, eid: u32, msg: &[u8]) -> Result<Event, ()> {
+        if eid & CAN_EFF_FLAG != 0 || eid > 0x7FF {
+            return Err(());
+        }
+        let id = eid as u16;
+
+        let timestamp = self.clock.now();
+        let failure = |counter, reason| Failure {
+            id: id,
+            counter: counter,
+            reason: reason,
+            timestamp: timestamp,
+        };
+
+        match self.verify_pdu(id, msg) {
+            Some((SecOCVerificationResult::Success, freshness, data_len)) => {
+                Ok(Event::Authenticated(Message {
+                    id: id,
+                    data: Payload::new(&msg[..data_len]),
+                    counter: freshness,
+                    epoch: 0,
+                    timestamp: timestamp,
+                }))
+            }
+            Some((SecOCVerificationResult::VerificationFailure, truncated, _)) => {
+                let pdu = self.find_pdu(id).unwrap();
+                let reason = if msg.len() < pdu.freshness_len + pdu.mac_len {
+                    FailReason::Truncated
+                } else {
+                    FailReason::MacMismatch
+                };
+                Ok(Event::IncorrectMAC(failure(truncated, reason)))
+            }
+            Some((SecOCVerificationResult::FreshnessFailure, truncated, _)) => Ok(Event::Desync(
+                failure(truncated, FailReason::FreshnessRejected),
+            )),
+            None => Ok(Event::UnknownId(failure(0, FailReason::NotConfigured))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    type TestContext = SecOCContext<CounterFreshness<ArrayStore<u16, u64>>>;
+
+    fn context() -> TestContext {
+        let pdus = [SecOCPdu::new(0x100).with_key(&[1; SANCUS_KEY_SIZE])];
+        SecOCContext::new(&pdus, CounterFreshness::new(ArrayStore::new()))
+    }
+
+    #[test]
+    fn secured_pdu_is_verified() {
+        let mut sender = context();
+        let mut receiver = context();
+
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+        let len = sender.secure(0x100, &[1, 2, 3, 4], &mut frame).unwrap();
+        assert_eq!(len, 4 + 1 + 3);
+
+        match receiver.auth_recv(0x100, &frame[..len]) {
+            Ok(Event::Authenticated(m)) => {
+                assert_eq!(&m.data[..], &[1, 2, 3, 4]);
+                assert_eq!(m.counter, 1);
+            }
+            _ => panic!("PDU not authenticated"),
+        }
+
+        frame[0] ^= 1;
+        assert_eq!(
+            receiver.verify(0x100, &frame[..len]),
+            Some(SecOCVerificationResult::VerificationFailure)
+        );
+    }
+
+    #[test]
+    fn replayed_pdu_is_rejected() {
+        let mut sender = context();
+        let mut receiver = context();
+
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+        let len = sender.secure(0x100, &[1], &mut frame).unwrap();
+
+        assert_eq!(
+            receiver.verify(0x100, &frame[..len]),
+            Some(SecOCVerificationResult::Success)
+        );
+        assert_ne!(
+            receiver.verify(0x100, &frame[..len]),
+            Some(SecOCVerificationResult::Success)
+        );
+    }
+
+    #[test]
+    fn freshness_rolls_over_truncation() {
+        let mut sender = context();
+        let mut receiver = context();
+
+        // One byte of freshness is carried, so the receiver has to reconstruct
+        // the upper bits every 256 PDUs.
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+        for i in 1..600 {
+            let len = sender.secure(0x100, &[i as u8], &mut frame).unwrap();
+            match receiver.auth_recv(0x100, &frame[..len]) {
+                Ok(Event::Authenticated(m)) => assert_eq!(m.counter, i),
+                _ => panic!("PDU {} not authenticated", i),
+            }
+        }
+    }
+
+    #[test]
+    fn short_pdu_is_truncated() {
+        let mut receiver = context();
+
+        for len in 0..4 {
+            match receiver.auth_recv(0x100, &[0; 4][..len]) {
+                Ok(Event::IncorrectMAC(failure)) => {
+                    assert_eq!(failure.reason, FailReason::Truncated)
+                }
+                _ => panic!("Short PDU accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn extended_ids_are_rejected() {
+        let mut sender = context();
+        let mut receiver = context();
+
+        let mut frame = [0; CAN_FD_PAYLOAD_SIZE];
+        let len = sender.secure(0x100, &[1], &mut frame).unwrap();
+
+        assert!(receiver.auth_recv(0x1234_0100, &frame[..len]).is_err());
+        assert!(receiver
+            .auth_recv(0x100 | CAN_EFF_FLAG, &frame[..len])
+            .is_err());
+        assert!(receiver.auth_recv(0x100, &frame[..len]).is_ok());
+    }
+}
@@ -0,0 +1,589 @@
+use vulcan::*;
+
+#[cfg(feature = "alloc")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "alloc")]
+use core::mem;
+
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::hash::Hash;
+
+/// Entry of a [VulCANStore](trait.VulCANStore.html), as returned by
+/// [entry](trait.VulCANStore.html#method.entry).
+pub enum Entry<'a, S>
+where
+    S: VulCANStore + 'a,
+    S::K: Clone,
+{
+    Occupied(OccupiedEntry<'a, S>),
+    Vacant(VacantEntry<'a, S>),
+}
+
+impl<'a, S> Entry<'a, S>
+where
+    S: VulCANStore + 'a,
+    S::K: Clone,
+{
+    /// Gets the key of the entry.
+    pub fn key(&self) -> &S::K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Calls `f` with the value of an occupied entry.
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut S::V),
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            entry => entry,
+        }
+    }
+
+    /// Gets the value of the entry, inserting `default` if it is vacant.
+    pub fn or_insert(self, default: S::V) -> Result<&'a mut S::V, CapacityError<S::V>> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+}
+
+/// Entry of a key present in a [VulCANStore](trait.VulCANStore.html).
+pub struct OccupiedEntry<'a, S>
+where
+    S: VulCANStore + 'a,
+{
+    store: &'a mut S,
+    key: S::K,
+}
+
+impl<'a, S> OccupiedEntry<'a, S>
+where
+    S: VulCANStore + 'a,
+    S::K: Clone,
+{
+    pub(crate) fn new(store: &'a mut S, key: S::K) -> Self {
+        Self {
+            store: store,
+            key: key,
+        }
+    }
+
+    /// Gets the key of the entry.
+    pub fn key(&self) -> &S::K {
+        &self.key
+    }
+
+    /// Gets the value of the entry.
+    pub fn get(&self) -> &S::V {
+        self.store.get(&self.key).unwrap()
+    }
+
+    /// Gets the value of the entry mutably.
+    pub fn get_mut(&mut self) -> &mut S::V {
+        self.store.get_mut(&self.key).unwrap()
+    }
+
+    /// Converts the entry into a reference to its value.
+    pub fn into_mut(self) -> &'a mut S::V {
+        self.store.get_mut(&self.key).unwrap()
+    }
+
+    /// Replaces the value of the entry, returning the old value.
+    pub fn insert(&mut self, v: S::V) -> S::V {
+        ::core::mem::replace(self.get_mut(), v)
+    }
+
+    /// Removes the entry from the store, returning its value.
+    pub fn remove(self) -> S::V {
+        self.store.remove(&self.key).unwrap()
+    }
+}
+
+/// Entry of a key absent from a [VulCANStore](trait.VulCANStore.html).
+pub struct VacantEntry<'a, S>
+where
+    S: VulCANStore + 'a,
+{
+    store: &'a mut S,
+    key: S::K,
+}
+
+impl<'a, S> VacantEntry<'a, S>
+where
+    S: VulCANStore + 'a,
+    S::K: Clone,
+{
+    pub(crate) fn new(store: &'a mut S, key: S::K) -> Self {
+        Self {
+            store: store,
+            key: key,
+        }
+    }
+
+    /// Gets the key of the entry.
+    pub fn key(&self) -> &S::K {
+        &self.key
+    }
+
+    /// Inserts `v` for the key of the entry, returning a reference to it, or an
+    /// error if the store is full.
+    pub fn insert(self, v: S::V) -> Result<&'a mut S::V, CapacityError<S::V>> {
+        self.store.insert(self.key.clone(), v)?;
+        Ok(self.store.get_mut(&self.key).unwrap())
+    }
+}
+
+/// Fixed-capacity [VulCANStore](trait.VulCANStore.html) for targets without an
+/// allocator, holding at most 16 entries.
+#[derive(Copy, Clone)]
+pub struct ArrayStore<K, V>
+where
+    K: Copy + Eq,
+    V: Copy,
+{
+    // @Cleanup @Hardcode: 16 will do for now, like the connections of a context.
+    // Could use const generics when they are stable.
+    entries: [Option<(K, V)>; 16],
+    len: usize,
+}
+
+impl<K, V> ArrayStore<K, V>
+where
+    K: Copy + Eq,
+    V: Copy,
+{
+    /// Creates a new, empty store.
+    pub fn new() -> Self {
+        Self {
+            entries: [None; 16],
+            len: 0,
+        }
+    }
+
+    // Finds the slot holding `k`.
+    fn position(&self, k: &K) -> Option<usize> {
+        self.entries.iter().position(|entry| match *entry {
+            Some((key, _)) => key == *k,
+            None => false,
+        })
+    }
+}
+
+impl<K, V> Default for ArrayStore<K, V>
+where
+    K: Copy + Eq,
+    V: Copy,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> VulCANStore for ArrayStore<K, V>
+where
+    K: Copy + Eq,
+    V: Copy,
+{
+    type K = K;
+    type V = V;
+
+    fn get(&self, k: &K) -> Option<&V> {
+        self.position(k)
+            .and_then(|i| self.entries[i].as_ref())
+            .map(|&(_, ref v)| v)
+    }
+
+    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.position(k)
+            .and_then(move |i| self.entries[i].as_mut())
+            .map(|&mut (_, ref mut v)| v)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Result<Option<V>, CapacityError<V>> {
+        if let Some(i) = self.position(&k) {
+            let old = self.entries[i].map(|(_, old)| old);
+            self.entries[i] = Some((k, v));
+            return Ok(old);
+        }
+
+        match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(i) => {
+                self.entries[i] = Some((k, v));
+                self.len += 1;
+                Ok(None)
+            }
+            None => Err(CapacityError(v)),
+        }
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let i = self.position(k)?;
+        self.len -= 1;
+        self.entries[i].take().map(|(_, v)| v)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.position(k).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for &(ref k, ref v) in self.entries.iter().filter_map(|entry| entry.as_ref()) {
+            f(k, v);
+        }
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        for entry in self.entries.iter_mut() {
+            let keep = match *entry {
+                Some((ref k, ref mut v)) => f(k, v),
+                None => true,
+            };
+
+            if !keep {
+                *entry = None;
+                self.len -= 1;
+            }
+        }
+    }
+}
+
+/// [VulCANStore](trait.VulCANStore.html) backed by a `BTreeMap`, available with
+/// the `alloc` feature, which needs at least Rust 1.36 for `alloc::collections`.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct BTreeStore<K, V>
+where
+    K: Ord,
+{
+    map: BTreeMap<K, V>,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "alloc")]
+impl<K, V> BTreeStore<K, V>
+where
+    K: Ord,
+{
+    /// Creates a new, empty store without a limit on the number of entries.
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            limit: None,
+        }
+    }
+
+    /// Limits the number of entries held by the store to `limit`.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K, V> VulCANStore for BTreeStore<K, V>
+where
+    K: Ord,
+{
+    type K = K;
+    type V = V;
+
+    fn get(&self, k: &K) -> Option<&V> {
+        self.map.get(k)
+    }
+
+    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.map.get_mut(k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Result<Option<V>, CapacityError<V>> {
+        let full = self.limit.map_or(false, |limit| self.map.len() >= limit);
+        if full && !self.map.contains_key(&k) {
+            return Err(CapacityError(v));
+        }
+
+        Ok(self.map.insert(k, v))
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.map.remove(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.map.contains_key(k)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for (k, v) in self.map.iter() {
+            f(k, v);
+        }
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        // BTreeMap::retain needs Rust 1.53, so rebuild the map instead.
+        let map = mem::replace(&mut self.map, BTreeMap::new());
+        for (k, mut v) in map {
+            if f(&k, &mut v) {
+                self.map.insert(k, v);
+            }
+        }
+    }
+}
+
+/// [VulCANStore](trait.VulCANStore.html) backed by a `HashMap`, available with
+/// the `std` feature.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct HashStore<K, V>
+where
+    K: Hash + Eq,
+{
+    map: HashMap<K, V>,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl<K, V> HashStore<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Creates a new, empty store without a limit on the number of entries.
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+            limit: None,
+        }
+    }
+
+    /// Limits the number of entries held by the store to `limit`.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V> VulCANStore for HashStore<K, V>
+where
+    K: Hash + Eq,
+{
+    type K = K;
+    type V = V;
+
+    fn get(&self, k: &K) -> Option<&V> {
+        self.map.get(k)
+    }
+
+    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.map.get_mut(k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Result<Option<V>, CapacityError<V>> {
+        let full = self.limit.map_or(false, |limit| self.map.len() >= limit);
+        if full && !self.map.contains_key(&k) {
+            return Err(CapacityError(v));
+        }
+
+        Ok(self.map.insert(k, v))
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.map.remove(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool {
+        self.map.contains_key(k)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for (k, v) in self.map.iter() {
+            f(k, v);
+        }
+    }
+
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.map.retain(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fills `store` up to `capacity` and checks it only takes new keys after a removal.
+    fn check_capacity<S>(mut store: S, capacity: u16)
+    where
+        S: VulCANStore<K = u16, V = u32>,
+    {
+        for k in 0..capacity {
+            assert_eq!(store.insert(k, k as u32), Ok(None));
+        }
+        assert_eq!(store.len(), capacity as usize);
+
+        assert_eq!(store.insert(capacity, 100), Err(CapacityError(100)));
+        assert!(!store.contains_key(&capacity));
+
+        // Existing keys are replaced in a full store.
+        assert_eq!(store.insert(0, 200), Ok(Some(0)));
+        assert_eq!(store.get(&0), Some(&200));
+        assert_eq!(store.len(), capacity as usize);
+
+        assert_eq!(store.remove(&1), Some(1));
+        assert_eq!(store.remove(&1), None);
+        assert_eq!(store.len(), capacity as usize - 1);
+
+        assert_eq!(store.insert(capacity, 100), Ok(None));
+        assert_eq!(store.get(&capacity), Some(&100));
+        assert_eq!(store.insert(capacity + 1, 101), Err(CapacityError(101)));
+    }
+
+    // Checks the entry API on a store holding at most `capacity` entries.
+    fn check_entry<S>(mut store: S, capacity: u16)
+    where
+        S: VulCANStore<K = u16, V = u32>,
+    {
+        match store.entry(1) {
+            Entry::Vacant(entry) => {
+                assert_eq!(entry.key(), &1);
+                assert_eq!(entry.insert(10).map(|v| *v), Ok(10));
+            }
+            Entry::Occupied(_) => panic!("empty store has an occupied entry"),
+        }
+
+        match store.entry(1) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.get(), &10);
+                *entry.get_mut() += 1;
+                assert_eq!(entry.insert(20), 11);
+                assert_eq!(entry.remove(), 20);
+            }
+            Entry::Vacant(_) => panic!("inserted key has a vacant entry"),
+        }
+        assert!(store.is_empty());
+
+        assert_eq!(store.entry(2).or_insert(5).map(|v| *v), Ok(5));
+        store.entry(2).and_modify(|v| *v += 1).or_insert(0).unwrap();
+        assert_eq!(store.get(&2), Some(&6));
+
+        for k in 3..capacity + 2 {
+            store.insert(k, 0).unwrap();
+        }
+        match store.entry(capacity + 2) {
+            Entry::Vacant(entry) => assert_eq!(entry.insert(1).err(), Some(CapacityError(1))),
+            Entry::Occupied(_) => panic!("missing key has an occupied entry"),
+        }
+        assert_eq!(store.entry(2).or_insert(0).map(|v| *v), Ok(6));
+    }
+
+    // Checks for_each and retain.
+    fn check_retain<S>(mut store: S)
+    where
+        S: VulCANStore<K = u16, V = u32>,
+    {
+        for k in 0..4 {
+            store.insert(k, k as u32 * 10).unwrap();
+        }
+
+        let mut sum = 0;
+        store.for_each(|&k, &v| {
+            assert_eq!(v, k as u32 * 10);
+            sum += v;
+        });
+        assert_eq!(sum, 60);
+
+        store.retain(|&k, v| {
+            *v += 1;
+            k % 2 == 0
+        });
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(&0), Some(&1));
+        assert_eq!(store.get(&2), Some(&21));
+        assert!(!store.contains_key(&1));
+        assert!(!store.contains_key(&3));
+
+        // Retained slots are freed for new keys.
+        store.insert(5, 50).unwrap();
+        let mut count = 0;
+        store.for_each(|_, _| count += 1);
+        assert_eq!(count, 3);
+
+        store.retain(|_, _| false);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn array_store_capacity() {
+        check_capacity(ArrayStore::new(), 16);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn btree_store_capacity() {
+        check_capacity(BTreeStore::new().with_limit(4), 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn hash_store_capacity() {
+        check_capacity(HashStore::new().with_limit(4), 4);
+    }
+
+    #[test]
+    fn array_store_entries() {
+        check_entry(ArrayStore::new(), 16);
+        check_retain(ArrayStore::new());
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn btree_store_entries() {
+        check_entry(BTreeStore::new().with_limit(4), 4);
+        check_retain(BTreeStore::new());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn hash_store_entries() {
+        check_entry(HashStore::new().with_limit(4), 4);
+        check_retain(HashStore::new());
+    }
+}
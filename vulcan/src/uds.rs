@@ -0,0 +1,729 @@
+use freshness::*;
+use isotp::*;
+use leia::*;
+use vulcan::*;
+
+/// Service id of ECUReset.
+pub const UDS_ECU_RESET: u8 = 0x11;
+/// Service id of WriteDataByIdentifier.
+pub const UDS_WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+/// Service id of RoutineControl.
+pub const UDS_ROUTINE_CONTROL: u8 = 0x31;
+
+/// Services that only accept authenticated requests.
+pub const UDS_CRITICAL_SERVICES: [u8; 3] = [
+    UDS_ECU_RESET,
+    UDS_WRITE_DATA_BY_IDENTIFIER,
+    UDS_ROUTINE_CONTROL,
+];
+
+/// Negative response code for services without a handler.
+pub const UDS_NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+/// Negative response code for responses that don't fit in a single frame.
+pub const UDS_NRC_RESPONSE_TOO_LONG: u8 = 0x14;
+/// Negative response code for authenticated requests received while the previous
+/// response is still being sent. The negative response follows that response.
+pub const UDS_NRC_BUSY_REPEAT_REQUEST: u8 = 0x21;
+/// Negative response code for unauthenticated requests of critical services, and
+/// plain requests that don't fit in a single frame.
+pub const UDS_NRC_SECURITY_ACCESS_DENIED: u8 = 0x33;
+
+const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+const UDS_POSITIVE_RESPONSE: u8 = 0x40;
+const UDS_NEGATIVE_RESPONSE_SIZE: usize = 3;
+
+// Longest message carried by an ISO-TP single frame.
+const UDS_SF_MAX_SIZE: usize = CAN_PAYLOAD_SIZE - 1;
+
+/// Handler of a UDS service.
+///
+/// Gets the request parameters following the service id, and writes the
+/// response parameters to `response`, returning their length or a negative
+/// response code.
+pub type UdsHandler = fn(request: &[u8], response: &mut [u8]) -> Result<usize, u8>;
+
+#[derive(Copy, Clone)]
+struct UdsService {
+    sid: u8,
+    handler: UdsHandler,
+    authenticated: bool,
+}
+
+/// Request handled by a [UdsServer](struct.UdsServer.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UdsOutcome {
+    /// The handler of `sid` was called and a positive response sent.
+    Served { sid: u8, authenticated: bool },
+    /// A negative response with code `nrc` was sent.
+    Rejected {
+        sid: u8,
+        nrc: u8,
+        authenticated: bool,
+    },
+}
+
+/// Response received by a [UdsClient](struct.UdsClient.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UdsResponse<'a> {
+    /// Positive response of `sid` with its parameters.
+    Positive {
+        sid: u8,
+        data: &'a [u8],
+        authenticated: bool,
+    },
+    /// Negative response of `sid` with code `nrc`.
+    Negative {
+        sid: u8,
+        nrc: u8,
+        authenticated: bool,
+    },
+}
+
+/// Diagnostic server dispatching UDS requests received on `request_id` to the
+/// handlers of their services.
+///
+/// Plain requests are ISO-TP single frames, as sent by any tester; longer ones are
+/// rejected with securityAccessDenied. Authenticated
+/// requests are sent over an [IsoTpChannel](struct.IsoTpChannel.html) on the same
+/// ids, which have to be LeiA connections of the context: `request_id` to receive
+/// and `response_id` to send. Responses are authenticated like their request.
+///
+/// Requests of critical services, ECUReset, WriteDataByIdentifier and
+/// RoutineControl by default, are rejected with securityAccessDenied unless they
+/// are authenticated.
+///
+/// Frames received on `request_id` must be passed to [recv](#method.recv) instead
+/// of `auth_recv`.
+pub struct UdsServer {
+    channel: IsoTpChannel,
+    // @Cleanup @Hardcode: 16 will do for now, like the connections of a context.
+    services: [Option<UdsService>; 16],
+    response: [u8; ISOTP_MAX_PAYLOAD_SIZE],
+    // Service id of the request being received, from its first frame.
+    rx_sid: u8,
+    // Service id of the request rejected with busyRepeatRequest, whose negative
+    // response waits for the response being sent.
+    queued: Option<u8>,
+}
+
+impl UdsServer {
+    /// Creates a new server receiving requests on `request_id` and responding on
+    /// `response_id`, without any services.
+    pub fn new(request_id: u16, response_id: u16) -> Self {
+        Self {
+            channel: IsoTpChannel::new(response_id, request_id),
+            services: [None; 16],
+            response: [0; ISOTP_MAX_PAYLOAD_SIZE],
+            rx_sid: 0,
+            queued: None,
+        }
+    }
+
+    /// Handles requests of service `sid` with `handler`. Only authenticated
+    /// requests are accepted if `sid` is one of `UDS_CRITICAL_SERVICES`.
+    pub fn with_service(self, sid: u8, handler: UdsHandler) -> Self {
+        let authenticated = UDS_CRITICAL_SERVICES.contains(&sid);
+        self.add_service(sid, handler, authenticated)
+    }
+
+    /// Handles authenticated requests of service `sid` with `handler`, rejecting
+    /// plain ones.
+    pub fn with_authenticated_service(self, sid: u8, handler: UdsHandler) -> Self {
+        self.add_service(sid, handler, true)
+    }
+
+    // Adds or replaces the handler of `sid`.
+    fn add_service(mut self, sid: u8, handler: UdsHandler, authenticated: bool) -> Self {
+        assert!(sid & UDS_POSITIVE_RESPONSE == 0, "Not a request service id");
+
+        let slot = self
+            .services
+            .iter()
+            .position(|s| s.map_or(false, |s| s.sid == sid))
+            .or_else(|| self.services.iter().position(|s| s.is_none()))
+            .expect("Too many UDS services");
+
+        self.services[slot] = Some(UdsService {
+            sid: sid,
+            handler: handler,
+            authenticated: authenticated,
+        });
+        self
+    }
+
+    /// Aborts a response that waits longer than `timeout` for a flow control
+    /// frame, see [IsoTpChannel](struct.IsoTpChannel.html#method.with_timeout).
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.channel = self.channel.with_timeout(timeout);
+        self
+    }
+
+    /// Gets the id requests are received on.
+    pub fn request_id(&self) -> u16 {
+        self.channel.rx_id()
+    }
+
+    /// Gets the id responses are sent on.
+    pub fn response_id(&self) -> u16 {
+        self.channel.tx_id()
+    }
+
+    /// Receives a frame sent on `request_id`.
+    ///
+    /// Once a complete request is received, it is dispatched and its response
+    /// sent. Authenticated requests are rejected with busyRepeatRequest while the
+    /// response to the previous one is still being sent, once it is sent. Only
+    /// the last of these rejections is kept.
+    pub fn recv<S, F, T>(
+        &mut self,
+        context: &mut LeiAContext<S, F, T>,
+        data: &[u8],
+    ) -> Result<Option<UdsOutcome>, IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if data.is_empty() {
+            return Err(IsoTpError::Protocol);
+        }
+
+        // Authenticated messages never fit in a single frame, so these are
+        // always plain requests.
+        if data[0] & 0xF0 == ISOTP_PCI_SF {
+            let len = (data[0] & 0x0F) as usize;
+            if len == 0 || len + 1 > data.len() {
+                return Err(IsoTpError::Protocol);
+            }
+
+            let (outcome, len) = dispatch(
+                &self.services,
+                &data[1..1 + len],
+                false,
+                UDS_SF_MAX_SIZE,
+                &mut self.response,
+            );
+            send_single_frame(context, self.channel.tx_id(), &self.response[..len]);
+
+            return Ok(Some(outcome));
+        }
+
+        if data[0] & 0xF0 == ISOTP_PCI_FF && data.len() > 2 {
+            self.rx_sid = data[2];
+        }
+
+        let busy = self.channel.is_sending();
+        let tx_id = self.channel.tx_id();
+
+        let dispatched = match self.channel.recv(context, data) {
+            Ok(Some(request)) => {
+                if request.is_empty() {
+                    return Err(IsoTpError::Protocol);
+                }
+                if busy {
+                    // The channel can't carry another response yet, so the
+                    // rejection follows the current one.
+                    self.queued = Some(request[0]);
+
+                    return Ok(Some(UdsOutcome::Rejected {
+                        sid: request[0],
+                        nrc: UDS_NRC_BUSY_REPEAT_REQUEST,
+                        authenticated: true,
+                    }));
+                }
+
+                Some(dispatch(
+                    &self.services,
+                    request,
+                    true,
+                    ISOTP_MAX_PAYLOAD_SIZE,
+                    &mut self.response,
+                ))
+            }
+            Ok(None) => None,
+            Err(IsoTpError::Auth(_)) => {
+                // Plain requests longer than a single frame fail authentication,
+                // they are answered like unauthenticated requests.
+                let (outcome, len) = reject(
+                    self.rx_sid,
+                    UDS_NRC_SECURITY_ACCESS_DENIED,
+                    false,
+                    &mut self.response,
+                );
+                send_single_frame(context, tx_id, &self.response[..len]);
+
+                return Ok(Some(outcome));
+            }
+            Err(error) => return Err(error),
+        };
+
+        let (outcome, len) = match dispatched {
+            Some(dispatched) => dispatched,
+            None => {
+                self.send_queued(context)?;
+                return Ok(None);
+            }
+        };
+
+        self.channel.send(context, &self.response[..len])?;
+
+        Ok(Some(outcome))
+    }
+
+    /// Continues or times out the authenticated response being sent, see
+    /// [IsoTpChannel](struct.IsoTpChannel.html#method.poll).
+    pub fn poll<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        self.channel.poll(context)?;
+        self.send_queued(context)
+    }
+
+    /// Aborts the authenticated request and response in progress.
+    pub fn abort(&mut self) {
+        self.queued = None;
+        self.channel.abort()
+    }
+
+    // Sends the busyRepeatRequest rejection waiting for the previous response,
+    // once that has been sent.
+    fn send_queued<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if self.channel.is_sending() {
+            return Ok(());
+        }
+
+        let sid = match self.queued.take() {
+            Some(sid) => sid,
+            None => return Ok(()),
+        };
+
+        let (_, len) = reject(sid, UDS_NRC_BUSY_REPEAT_REQUEST, true, &mut self.response);
+        self.channel.send(context, &self.response[..len])
+    }
+}
+
+// Calls the handler of the service of `request`, writing the positive or
+// negative response of at most `max_len` bytes to `response`.
+fn dispatch(
+    services: &[Option<UdsService>],
+    request: &[u8],
+    authenticated: bool,
+    max_len: usize,
+    response: &mut [u8],
+) -> (UdsOutcome, usize) {
+    let sid = request[0];
+
+    let service = services
+        .iter()
+        .filter_map(|s| s.as_ref())
+        .find(|s| s.sid == sid);
+
+    let result = match service {
+        Some(s) if s.authenticated && !authenticated => Err(UDS_NRC_SECURITY_ACCESS_DENIED),
+        Some(s) => match (s.handler)(&request[1..], &mut response[1..]) {
+            Ok(len) if len + 1 > max_len => Err(UDS_NRC_RESPONSE_TOO_LONG),
+            result => result,
+        },
+        None => Err(UDS_NRC_SERVICE_NOT_SUPPORTED),
+    };
+
+    match result {
+        Ok(len) => {
+            response[0] = sid + UDS_POSITIVE_RESPONSE;
+            let outcome = UdsOutcome::Served {
+                sid: sid,
+                authenticated: authenticated,
+            };
+            (outcome, len + 1)
+        }
+        Err(nrc) => reject(sid, nrc, authenticated, response),
+    }
+}
+
+// Writes the negative response of `sid` with code `nrc` to `response`.
+fn reject(sid: u8, nrc: u8, authenticated: bool, response: &mut [u8]) -> (UdsOutcome, usize) {
+    response[..UDS_NEGATIVE_RESPONSE_SIZE].copy_from_slice(&[UDS_NEGATIVE_RESPONSE, sid, nrc]);
+
+    let outcome = UdsOutcome::Rejected {
+        sid: sid,
+        nrc: nrc,
+        authenticated: authenticated,
+    };
+    (outcome, UDS_NEGATIVE_RESPONSE_SIZE)
+}
+
+// Sends `msg` as a plain ISO-TP single frame on `id`.
+fn send_single_frame<S, F, T>(context: &mut LeiAContext<S, F, T>, id: u16, msg: &[u8])
+where
+    S: LeiAStore,
+    F: FreshnessSource,
+    T: Clock,
+{
+    let mut frame = [0; CAN_PAYLOAD_SIZE];
+    frame[0] = ISOTP_PCI_SF | msg.len() as u8;
+    frame[1..1 + msg.len()].copy_from_slice(msg);
+    context.send(id, &frame[..1 + msg.len()]);
+}
+
+/// Diagnostic tester sending UDS requests to a [UdsServer](struct.UdsServer.html).
+///
+/// Authenticated requests are sent over an [IsoTpChannel](struct.IsoTpChannel.html)
+/// on `request_id` and `response_id`, which have to be LeiA connections of the
+/// context.
+///
+/// Frames received on `response_id` must be passed to [recv](#method.recv)
+/// instead of `auth_recv`.
+pub struct UdsClient {
+    channel: IsoTpChannel,
+}
+
+impl UdsClient {
+    /// Creates a new client sending requests on `request_id` and receiving
+    /// responses on `response_id`.
+    pub fn new(request_id: u16, response_id: u16) -> Self {
+        Self {
+            channel: IsoTpChannel::new(request_id, response_id),
+        }
+    }
+
+    /// Aborts a request that waits longer than `timeout` for a flow control
+    /// frame, see [IsoTpChannel](struct.IsoTpChannel.html#method.with_timeout).
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.channel = self.channel.with_timeout(timeout);
+        self
+    }
+
+    /// Continues or times out the authenticated request being sent, see
+    /// [IsoTpChannel](struct.IsoTpChannel.html#method.poll).
+    pub fn poll<S, F, T>(&mut self, context: &mut LeiAContext<S, F, T>) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        self.channel.poll(context)
+    }
+
+    /// Aborts the authenticated request and response in progress.
+    pub fn abort(&mut self) {
+        self.channel.abort()
+    }
+
+    /// Sends `request`, starting with its service id.
+    ///
+    /// Plain requests have to fit in a single frame.
+    pub fn request<S, F, T>(
+        &mut self,
+        context: &mut LeiAContext<S, F, T>,
+        request: &[u8],
+        authenticated: bool,
+    ) -> Result<(), IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if request.is_empty() {
+            return Err(IsoTpError::Protocol);
+        }
+        if authenticated {
+            return self.channel.send(context, request);
+        }
+        if request.len() > UDS_SF_MAX_SIZE {
+            return Err(IsoTpError::TooLong);
+        }
+
+        send_single_frame(context, self.channel.tx_id(), request);
+
+        Ok(())
+    }
+
+    /// Receives a frame sent on `response_id`.
+    ///
+    /// Returns the response once it has been completely received.
+    pub fn recv<'a, S, F, T>(
+        &'a mut self,
+        context: &mut LeiAContext<S, F, T>,
+        data: &'a [u8],
+    ) -> Result<Option<UdsResponse<'a>>, IsoTpError>
+    where
+        S: LeiAStore,
+        F: FreshnessSource,
+        T: Clock,
+    {
+        if data.is_empty() {
+            return Err(IsoTpError::Protocol);
+        }
+
+        if data[0] & 0xF0 == ISOTP_PCI_SF {
+            let len = (data[0] & 0x0F) as usize;
+            if len == 0 || len + 1 > data.len() {
+                return Err(IsoTpError::Protocol);
+            }
+
+            return parse_response(&data[1..1 + len], false).map(Some);
+        }
+
+        match self.channel.recv(context, data)? {
+            Some(response) => parse_response(response, true).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+// Splits a response into its service id and parameters or response code.
+fn parse_response<'a>(
+    response: &'a [u8],
+    authenticated: bool,
+) -> Result<UdsResponse<'a>, IsoTpError> {
+    if response.is_empty() {
+        return Err(IsoTpError::Protocol);
+    }
+
+    if response[0] == UDS_NEGATIVE_RESPONSE {
+        if response.len() != UDS_NEGATIVE_RESPONSE_SIZE {
+            return Err(IsoTpError::Protocol);
+        }
+
+        return Ok(UdsResponse::Negative {
+            sid: response[1],
+            nrc: response[2],
+            authenticated: authenticated,
+        });
+    }
+
+    if response[0] & UDS_POSITIVE_RESPONSE == 0 {
+        return Err(IsoTpError::Protocol);
+    }
+
+    Ok(UdsResponse::Positive {
+        sid: response[0] - UDS_POSITIVE_RESPONSE,
+        data: &response[1..],
+        authenticated: authenticated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::ArrayStore;
+
+    type TestContext = LeiAContext<ArrayStore<u16, LeiAPending>>;
+
+    fn context() -> TestContext {
+        let connections = [
+            LeiAConnection::new(0x7e0).with_k_i(&[1; SANCUS_KEY_SIZE]),
+            LeiAConnection::new(0x7e8).with_k_i(&[2; SANCUS_KEY_SIZE]),
+        ];
+        let aec = LeiAConnection::new(0x7ff).with_k_i(&[3; SANCUS_KEY_SIZE]);
+        let mut context = LeiAContext::new(&connections, aec, ArrayStore::new()).with_queue();
+        context.init();
+        context
+    }
+
+    fn reset(_: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        response[0] = 1;
+        Ok(1)
+    }
+
+    fn read(_: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        for (i, b) in response[..20].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(20)
+    }
+
+    // Passes frames between tester and server until both are quiet, or until the
+    // server handled a request if `stop` is set. Returns the last outcome and the
+    // last response as service id, response code and whether it was authenticated.
+    fn exchange(
+        server: &mut UdsServer,
+        client: &mut UdsClient,
+        tester: &mut TestContext,
+        ecu: &mut TestContext,
+        stop: bool,
+    ) -> (Option<UdsOutcome>, Option<(u8, u8, bool)>) {
+        let mut outcome = None;
+        let mut response = None;
+
+        loop {
+            let request = tester.pop_frame();
+            if let Some((_, ref data)) = request {
+                outcome = server.recv(ecu, data).ok().unwrap().or(outcome);
+                if stop && outcome.is_some() {
+                    return (outcome, response);
+                }
+            }
+
+            let reply = ecu.pop_frame();
+            if let Some((_, ref data)) = reply {
+                response = match client.recv(tester, data).ok().unwrap() {
+                    Some(UdsResponse::Positive {
+                        sid, authenticated, ..
+                    }) => Some((sid, 0, authenticated)),
+                    Some(UdsResponse::Negative {
+                        sid,
+                        nrc,
+                        authenticated,
+                    }) => Some((sid, nrc, authenticated)),
+                    None => response,
+                };
+            }
+
+            if request.is_none() && reply.is_none() {
+                return (outcome, response);
+            }
+        }
+    }
+
+    #[test]
+    fn critical_services_need_authentication() {
+        let mut tester = context();
+        let mut ecu = context();
+        let mut server = UdsServer::new(0x7e0, 0x7e8).with_service(UDS_ECU_RESET, reset);
+        let mut client = UdsClient::new(0x7e0, 0x7e8);
+
+        client
+            .request(&mut tester, &[UDS_ECU_RESET, 1], false)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            exchange(&mut server, &mut client, &mut tester, &mut ecu, false),
+            (
+                Some(UdsOutcome::Rejected {
+                    sid: UDS_ECU_RESET,
+                    nrc: UDS_NRC_SECURITY_ACCESS_DENIED,
+                    authenticated: false,
+                }),
+                Some((UDS_ECU_RESET, UDS_NRC_SECURITY_ACCESS_DENIED, false))
+            )
+        );
+
+        client
+            .request(&mut tester, &[UDS_ECU_RESET, 1], true)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            exchange(&mut server, &mut client, &mut tester, &mut ecu, false),
+            (
+                Some(UdsOutcome::Served {
+                    sid: UDS_ECU_RESET,
+                    authenticated: true,
+                }),
+                Some((UDS_ECU_RESET, 0, true))
+            )
+        );
+    }
+
+    #[test]
+    fn busy_server_asks_to_repeat() {
+        let mut tester = context();
+        let mut ecu = context();
+        let mut server = UdsServer::new(0x7e0, 0x7e8)
+            .with_service(UDS_ECU_RESET, reset)
+            .with_service(0x22, read);
+        let mut client = UdsClient::new(0x7e0, 0x7e8);
+
+        // Hold back the first frame of the long response, so it isn't completed.
+        client
+            .request(&mut tester, &[0x22, 0xF1, 0x90], true)
+            .ok()
+            .unwrap();
+        exchange(&mut server, &mut client, &mut tester, &mut ecu, true);
+        let (_, first) = ecu.pop_frame().unwrap();
+
+        client
+            .request(&mut tester, &[UDS_ECU_RESET, 1], true)
+            .ok()
+            .unwrap();
+        let (_, ff) = tester.pop_frame().unwrap();
+        assert_eq!(server.recv(&mut ecu, &ff).ok().unwrap(), None);
+        let (_, fc) = ecu.pop_frame().unwrap();
+        assert!(client.recv(&mut tester, &fc).ok().unwrap().is_none());
+
+        let mut outcome = None;
+        while let Some((_, cf)) = tester.pop_frame() {
+            outcome = server.recv(&mut ecu, &cf).ok().unwrap();
+        }
+        assert_eq!(
+            outcome,
+            Some(UdsOutcome::Rejected {
+                sid: UDS_ECU_RESET,
+                nrc: UDS_NRC_BUSY_REPEAT_REQUEST,
+                authenticated: true,
+            })
+        );
+        assert!(ecu.pop_frame().is_none());
+
+        // The rejection is authenticated and follows the long response.
+        assert!(client.recv(&mut tester, &first).ok().unwrap().is_none());
+        assert_eq!(
+            exchange(&mut server, &mut client, &mut tester, &mut ecu, false),
+            (
+                None,
+                Some((UDS_ECU_RESET, UDS_NRC_BUSY_REPEAT_REQUEST, true))
+            )
+        );
+    }
+
+    #[test]
+    fn plain_long_requests_are_denied() {
+        let mut tester = context();
+        let mut ecu = context();
+        let mut server = UdsServer::new(0x7e0, 0x7e8).with_service(0x22, read);
+        let mut client = UdsClient::new(0x7e0, 0x7e8);
+
+        // Plain ISO-TP transfers, shorter and longer than the counter and MAC.
+        for &len in [10, 30].iter() {
+            let mut request = [0x22; 30];
+            request[1] = len as u8;
+
+            let mut ff = [0; CAN_PAYLOAD_SIZE];
+            ff[0] = ISOTP_PCI_FF;
+            ff[1] = len as u8;
+            ff[2..].copy_from_slice(&request[..6]);
+            assert_eq!(server.recv(&mut ecu, &ff).ok().unwrap(), None);
+            assert!(ecu.pop_frame().is_some());
+
+            let mut outcome = None;
+            for (seq, chunk) in request[6..len].chunks(CAN_PAYLOAD_SIZE - 1).enumerate() {
+                let mut cf = [0; CAN_PAYLOAD_SIZE];
+                cf[0] = ISOTP_PCI_CF | (seq as u8 + 1);
+                cf[1..1 + chunk.len()].copy_from_slice(chunk);
+                outcome = server.recv(&mut ecu, &cf[..1 + chunk.len()]).ok().unwrap();
+            }
+            assert_eq!(
+                outcome,
+                Some(UdsOutcome::Rejected {
+                    sid: 0x22,
+                    nrc: UDS_NRC_SECURITY_ACCESS_DENIED,
+                    authenticated: false,
+                })
+            );
+
+            // Skip the AUTH_FAIL frames of the failed verification.
+            let response = loop {
+                let (id, data) = ecu.pop_frame().unwrap();
+                if id == 0x7e8 && data[0] & 0xF0 == ISOTP_PCI_SF {
+                    break data;
+                }
+            };
+            assert_eq!(
+                client.recv(&mut tester, &response).ok().unwrap(),
+                Some(UdsResponse::Negative {
+                    sid: 0x22,
+                    nrc: UDS_NRC_SECURITY_ACCESS_DENIED,
+                    authenticated: false,
+                })
+            );
+        }
+    }
+}
@@ -1,23 +1,211 @@
+use freshness::*;
 use leia::*;
+use secoc::*;
+use spongent::spongent_mac;
+use store::*;
+
+use core::fmt;
+use core::ops::Deref;
 
 pub const SANCUS_KEY_SIZE: usize = 16;
 pub const CAN_PAYLOAD_SIZE: usize = 8;
+pub const CAN_FD_PAYLOAD_SIZE: usize = 64;
 
 pub const CAN_EFF_MASK: u32 = 0x1FFFFFFF;
 pub const CAN_EFF_FLAG: u32 = 0x80000000;
 
 pub type SancusKey = [u8; SANCUS_KEY_SIZE];
 pub type CANPayload = [u8; CAN_PAYLOAD_SIZE];
+pub type CANFDPayload = [u8; CAN_FD_PAYLOAD_SIZE];
+
+/// Rounds `len` up to the next data length that can be represented by a CAN FD DLC.
+pub fn canfd_len(len: usize) -> usize {
+    if len <= 8 {
+        len
+    } else if len <= 12 {
+        12
+    } else if len <= 16 {
+        16
+    } else if len <= 20 {
+        20
+    } else if len <= 24 {
+        24
+    } else if len <= 32 {
+        32
+    } else if len <= 48 {
+        48
+    } else {
+        CAN_FD_PAYLOAD_SIZE
+    }
+}
+
+/// Data of a single CAN (FD) frame.
+#[derive(Copy, Clone)]
+pub struct Payload {
+    data: CANFDPayload,
+    len: usize,
+}
+
+impl Payload {
+    /// Creates a new payload holding a copy of `data`.
+    pub fn new(data: &[u8]) -> Self {
+        assert!(data.len() <= CAN_FD_PAYLOAD_SIZE);
+
+        let mut payload = Self::default();
+        payload.data[..data.len()].copy_from_slice(data);
+        payload.len = data.len();
+        payload
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self {
+            data: [0; CAN_FD_PAYLOAD_SIZE],
+            len: 0,
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data[..self.len]
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Payload {}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self[..].fmt(f)
+    }
+}
+
+// Fixed-size FIFO queue.
+// @Cleanup @Hardcode: 32 will do for now. Could use const generics when they are stable.
+pub(crate) struct Queue<T: Copy> {
+    items: [Option<T>; 32],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy> Queue<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            items: [None; 32],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // Appends `item`, handing it back if the queue is full.
+    pub(crate) fn push(&mut self, item: T) -> Result<(), T> {
+        if self.len == self.items.len() {
+            return Err(item);
+        }
+
+        let tail = (self.head + self.len) % self.items.len();
+        self.items[tail] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % self.items.len();
+        self.len -= 1;
+        item
+    }
+}
+
+/// Message received by a VulCAN context.
+#[derive(Copy, Clone, Debug)]
+pub struct Message {
+    /// Id the message was sent on.
+    pub id: u16,
+    /// Data of the message.
+    pub data: Payload,
+    /// Counter or freshness value of the message.
+    pub counter: u64,
+    /// Epoch of the session key, zero for protocols without epochs.
+    pub epoch: u64,
+    /// Time at which the message was received.
+    pub timestamp: u64,
+}
+
+/// Reason a received frame was rejected.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FailReason {
+    /// No connection is configured for the id.
+    NotConfigured,
+    /// The counter is lower than the counter of the connection.
+    CounterTooOld,
+    /// The freshness value was not accepted by the freshness source.
+    FreshnessRejected,
+    /// The frame is too short to contain a MAC.
+    Truncated,
+    /// The MAC does not match the data.
+    MacMismatch,
+    /// A new data frame was received before the MAC of the previous one.
+    MacNotReceived,
+    /// No data frame is waiting for this MAC.
+    NoPendingData,
+    /// The MAC did not arrive within the MAC timeout of the context.
+    Timeout,
+    /// The store had no room to keep the data frame until its MAC arrives, or
+    /// to record the freshness value of the message.
+    StoreFull,
+    /// No new epoch was received in response to AUTH_FAIL.
+    ResyncTimeout,
+    /// The connection used up all epochs of its long-term key.
+    EpochExhausted,
+    /// The new epoch is not higher than the current one, or too far ahead of it.
+    EpochRejected,
+}
+
+/// Frame rejected by a VulCAN context.
+#[derive(Copy, Clone, Debug)]
+pub struct Failure {
+    /// Id the frame was sent on.
+    pub id: u16,
+    /// Counter or freshness value of the frame.
+    pub counter: u64,
+    /// Reason the frame was rejected.
+    pub reason: FailReason,
+    /// Time at which the frame was received.
+    pub timestamp: u64,
+}
 
+#[derive(Copy, Clone, Debug)]
 pub enum Event {
-    Received(u16, Option<[u8; 8]>),
-    Authenticated(u16),
-    MissingMAC(u16),
-    UnexpectedMAC(u16),
-    IncorrectMAC(u16),
-    Desync(u16),
+    Received(Message),
+    Buffered(u16),
+    Authenticated(Message),
+    MissingMAC(Failure),
+    UnexpectedMAC(Failure),
+    IncorrectMAC(Failure),
+    Desync(Failure),
     Resynced(u16),
-    UnknownId(u16),
+    UnknownId(Failure),
+    /// Number of AUTH_FAIL frames suppressed on a connection since the last report.
+    AuthFailSuppressed(u16, u64),
     Debug(u64),
 }
 
@@ -50,13 +238,92 @@ where
     LeiAContext::new(connections, aec, store).with_send(send)
 }
 
+pub fn secoc<F>(pdus: &[SecOCPdu], fvm: F, send: fn(u32, &[u8])) -> SecOCContext<F>
+where
+    F: FreshnessSource,
+{
+    SecOCContext::new(pdus, fvm).with_send(send)
+}
+
+/// Trait representing a MAC algorithm used to authenticate frames.
+pub trait VulCANMac {
+    /// Computes the full-length MAC of `data` under `key`.
+    fn mac(key: &SancusKey, data: &[u8]) -> [u8; SANCUS_KEY_SIZE];
+}
+
+/// Spongent-based MAC, as used by LeiA.
+pub struct SpongentMac;
+
+impl VulCANMac for SpongentMac {
+    fn mac(key: &SancusKey, data: &[u8]) -> [u8; SANCUS_KEY_SIZE] {
+        spongent_mac(key, data).unwrap()
+    }
+}
+
+/// Trait representing a time source, e.g. a hardware timer or the system clock.
+///
+/// Contexts only compare differences between timestamps, so the unit and epoch
+/// are up to the application, as long as timeouts use the same unit.
+pub trait Clock {
+    /// Gets the current time.
+    fn now(&self) -> u64;
+}
+
+impl Clock for fn() -> u64 {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+/// Error returned when a store has no room for another key, holding the value
+/// that couldn't be inserted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CapacityError<V>(pub V);
+
+/// Trait representing a key-value store used by contexts to keep state per id,
+/// like the data frames awaiting their MAC.
 pub trait VulCANStore {
     type K;
     type V;
 
     fn get(&self, k: &Self::K) -> Option<&Self::V>;
-    fn insert(&mut self, k: &Self::K, Self::V) -> Option<Self::V>;
+    fn get_mut(&mut self, k: &Self::K) -> Option<&mut Self::V>;
+    /// Inserts `v` for `k`, returning the previous value, or an error if the
+    /// store is full.
+    fn insert(&mut self, k: Self::K, v: Self::V)
+        -> Result<Option<Self::V>, CapacityError<Self::V>>;
     fn remove(&mut self, k: &Self::K) -> Option<Self::V>;
-    fn contains_key(&self, k: &Self::K) -> bool;
     fn len(&self) -> usize;
+
+    /// Calls `f` for every entry in the store.
+    fn for_each<F>(&self, f: F)
+    where
+        F: FnMut(&Self::K, &Self::V);
+
+    /// Keeps only the entries for which `f` returns true, e.g. to sweep expired
+    /// entries.
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&Self::K, &mut Self::V) -> bool;
+
+    fn contains_key(&self, k: &Self::K) -> bool {
+        self.get(k).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the entry of `k` for in-place manipulation.
+    fn entry<'a>(&'a mut self, k: Self::K) -> Entry<'a, Self>
+    where
+        Self: Sized,
+        Self::K: Clone,
+    {
+        if self.contains_key(&k) {
+            Entry::Occupied(OccupiedEntry::new(self, k))
+        } else {
+            Entry::Vacant(VacantEntry::new(self, k))
+        }
+    }
 }